@@ -0,0 +1,7 @@
+#!/bin/sh
+# Regenerates the encoded fixtures from image.bin with the same tools the
+# Makefile uses for real images.
+set -e
+cd "$(dirname "$0")"
+heatshrink -e -w 10 -l 4 image.bin image.bin.hs
+python3 ../../tools/mkpatch.py image.bin image2.bin image2.patch
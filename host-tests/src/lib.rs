@@ -0,0 +1,33 @@
+//! Unit tests of the firmware's hardware independent modules, built for the
+//! host: `cargo test` in this directory, or `make test` at the top.
+//! The modules are compiled from `../src` as they are, tests included.
+
+#[path = "../../src/ota_signature.rs"]
+pub mod ota_signature;
+
+#[path = "../../src/heatshrink.rs"]
+pub mod heatshrink;
+
+#[path = "../../src/delta.rs"]
+pub mod delta;
+
+#[path = "../../src/dns.rs"]
+pub mod dns;
+
+#[path = "../../src/mdns.rs"]
+pub mod mdns;
+
+#[path = "../../src/sntp.rs"]
+pub mod sntp;
+
+#[path = "../../src/auth.rs"]
+pub mod auth;
+
+#[path = "../../src/tz.rs"]
+pub mod tz;
+
+#[path = "../../src/dhcp_leases.rs"]
+pub mod dhcp_leases;
+
+#[path = "../../src/ap_config.rs"]
+pub mod ap_config;
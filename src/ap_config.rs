@@ -0,0 +1,323 @@
+//! Settings of the provisioning access point: the stored record and the JSON
+//! the settings page exchanges with `/api/wifi/ap`. Storage is in `config.rs`.
+
+use core::fmt::{self, Write as _};
+use core::net::Ipv4Addr;
+use core::str::FromStr;
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::dhcp_leases::MAX_POOL;
+
+/// Most DHCP leases the access point hands out
+pub const MAX_DHCP_LEASES: u8 = MAX_POOL as u8;
+/// Channel, gateway, prefix length, DHCP pool size, SSID length, SSID and password
+pub const AP_RECORD_LEN: usize = 8 + 32 + 64;
+
+/// Provisioning access point, also used as the station's fallback
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApConfig {
+    /// Empty for the default `esp-wifi-<MAC suffix>`
+    pub ssid: String<32>,
+    /// WPA2 passphrase, empty for an open network
+    pub password: String<64>,
+    pub channel: u8,
+    /// Address of the device on the AP, also its DHCP and DNS server
+    pub gateway: Ipv4Addr,
+    pub prefix_len: u8,
+    pub dhcp_pool: u8,
+    /// Keep DHCP leases in storage so clients get their address back after a
+    /// reboot. Stored under its own key, not in the AP record.
+    pub persist_leases: bool,
+}
+
+impl ApConfig {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32u32.saturating_sub(self.prefix_len as u32))
+                .unwrap_or(0),
+        )
+    }
+
+    /// First and last address handed out by DHCP: the pool starts right after
+    /// the gateway and stops short of the broadcast address
+    pub fn dhcp_range(&self) -> (Ipv4Addr, Ipv4Addr) {
+        let mask = u32::from(self.netmask());
+        let gateway = u32::from(self.gateway);
+        let last_host = (gateway | !mask) - 1;
+        let start = gateway + 1;
+        let end = (gateway + self.dhcp_pool as u32).min(last_host);
+        (Ipv4Addr::from(start), Ipv4Addr::from(end))
+    }
+
+    pub fn encode(&self, buf: &mut [u8; AP_RECORD_LEN]) -> usize {
+        let ssid_end = 8 + self.ssid.len();
+        let end = ssid_end + self.password.len();
+        buf[0] = self.channel;
+        buf[1..5].copy_from_slice(&self.gateway.octets());
+        buf[5] = self.prefix_len;
+        buf[6] = self.dhcp_pool;
+        buf[7] = self.ssid.len() as u8;
+        buf[8..ssid_end].copy_from_slice(self.ssid.as_bytes());
+        buf[ssid_end..end].copy_from_slice(self.password.as_bytes());
+        end
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let header = data.get(..8)?;
+        let ssid_end = 8 + header[7] as usize;
+        let ssid = core::str::from_utf8(data.get(8..ssid_end)?).ok()?;
+        let password = core::str::from_utf8(&data[ssid_end..]).ok()?;
+        Some(Self {
+            ssid: String::try_from(ssid).ok()?,
+            password: String::try_from(password).ok()?,
+            channel: header[0],
+            gateway: Ipv4Addr::new(header[1], header[2], header[3], header[4]),
+            prefix_len: header[5],
+            dhcp_pool: header[6],
+            persist_leases: false,
+        })
+    }
+}
+
+pub(crate) fn is_wpa2_passphrase(password: &str) -> bool {
+    (8..=63).contains(&password.len()) && password.bytes().all(|b| (0x20..0x7f).contains(&b))
+}
+
+/// Access point settings as sent by the browser
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApSettings {
+    #[serde(default)]
+    pub(crate) ssid: String<32>,
+    #[serde(default, skip_serializing)]
+    pub(crate) password: String<64>,
+    pub(crate) channel: u8,
+    pub(crate) address: String<15>,
+    pub(crate) netmask: String<15>,
+    pub(crate) dhcp_pool: u8,
+    #[serde(default)]
+    pub(crate) persist_leases: bool,
+    /// Drops the password. The password is never sent back, so an empty one
+    /// keeps the stored password instead of opening the network.
+    #[serde(default)]
+    pub(crate) open: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ApConfigError {
+    Password,
+    /// `open` together with a password
+    OpenWithPassword,
+    Channel,
+    Address,
+    Netmask,
+    DhcpPool,
+}
+
+impl ApConfigError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApConfigError::Password => "Password must be 8-63 printable characters",
+            ApConfigError::OpenWithPassword => "An open AP takes no password",
+            ApConfigError::Channel => "Channel must be 1-13",
+            ApConfigError::Address => "Invalid AP address",
+            ApConfigError::Netmask => "Invalid netmask",
+            ApConfigError::DhcpPool => "DHCP pool does not fit the subnet",
+        }
+    }
+}
+
+impl fmt::Display for ApConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&ApConfig> for ApSettings {
+    fn from(config: &ApConfig) -> Self {
+        let mut address = String::new();
+        let mut netmask = String::new();
+        // Dotted quads are at most 15 characters
+        let _ = write!(address, "{}", config.gateway);
+        let _ = write!(netmask, "{}", config.netmask());
+        Self {
+            ssid: config.ssid.clone(),
+            password: String::new(),
+            channel: config.channel,
+            address,
+            netmask,
+            dhcp_pool: config.dhcp_pool,
+            persist_leases: config.persist_leases,
+            open: config.password.is_empty(),
+        }
+    }
+}
+
+impl ApSettings {
+    /// Checks the settings; the password of `stored` stays unless a new one is
+    /// given or `open` is set
+    pub fn parse(&self, stored: &ApConfig) -> Result<ApConfig, ApConfigError> {
+        let password = match (self.open, self.password.is_empty()) {
+            (true, true) => String::new(),
+            (true, false) => return Err(ApConfigError::OpenWithPassword),
+            (false, true) => stored.password.clone(),
+            (false, false) if is_wpa2_passphrase(&self.password) => self.password.clone(),
+            (false, false) => return Err(ApConfigError::Password),
+        };
+        if !(1..=13).contains(&self.channel) {
+            return Err(ApConfigError::Channel);
+        }
+        let gateway =
+            Ipv4Addr::from_str(self.address.trim()).map_err(|_| ApConfigError::Address)?;
+        let netmask =
+            Ipv4Addr::from_str(self.netmask.trim()).map_err(|_| ApConfigError::Netmask)?;
+        let mask = u32::from(netmask);
+        let prefix_len = mask.leading_ones();
+        if mask.count_ones() != prefix_len || !(8..=30).contains(&prefix_len) {
+            return Err(ApConfigError::Netmask);
+        }
+        let host = u32::from(gateway) & !mask;
+        if !is_unicast(gateway) || host == 0 || host == !mask {
+            return Err(ApConfigError::Address);
+        }
+        // Leases come after the gateway and before the broadcast address
+        let room = !mask - host - 1;
+        if self.dhcp_pool == 0 || self.dhcp_pool > MAX_DHCP_LEASES || self.dhcp_pool as u32 > room {
+            return Err(ApConfigError::DhcpPool);
+        }
+        Ok(ApConfig {
+            ssid: String::try_from(self.ssid.trim()).unwrap_or_default(),
+            password,
+            channel: self.channel,
+            gateway,
+            prefix_len: prefix_len as u8,
+            dhcp_pool: self.dhcp_pool,
+            persist_leases: self.persist_leases,
+        })
+    }
+}
+
+pub(crate) fn is_unicast(addr: Ipv4Addr) -> bool {
+    !(addr.is_unspecified() || addr.is_broadcast() || addr.is_multicast() || addr.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(password: &str) -> ApConfig {
+        ApConfig {
+            ssid: String::try_from("workshop").unwrap(),
+            password: String::try_from(password).unwrap(),
+            channel: 6,
+            gateway: Ipv4Addr::new(192, 168, 4, 1),
+            prefix_len: 24,
+            dhcp_pool: 16,
+            persist_leases: false,
+        }
+    }
+
+    fn settings(json: &str) -> ApSettings {
+        serde_json_core::from_str(json).unwrap().0
+    }
+
+    const FORM: &str = r#"{"ssid": "workshop", "channel": 11, "address": "192.168.4.1",
+        "netmask": "255.255.255.0", "dhcp_pool": 16, "persist_leases": true"#;
+
+    fn form(extra: &str) -> ApSettings {
+        settings(&format!("{FORM}{extra}}}"))
+    }
+
+    #[test]
+    fn keeps_the_stored_password_when_none_is_sent() {
+        let secured = stored("correct horse");
+        let config = form("").parse(&secured).unwrap();
+        assert_eq!(config.password, "correct horse");
+        assert_eq!(config.channel, 11);
+        assert!(config.persist_leases);
+        let config = form(r#", "password": """#).parse(&secured).unwrap();
+        assert_eq!(config.password, "correct horse");
+    }
+
+    #[test]
+    fn replaces_the_password() {
+        let config = form(r#", "password": "battery staple""#)
+            .parse(&stored("correct horse"))
+            .unwrap();
+        assert_eq!(config.password, "battery staple");
+    }
+
+    #[test]
+    fn opens_the_network_only_when_asked() {
+        let config = form(r#", "open": true"#)
+            .parse(&stored("correct horse"))
+            .unwrap();
+        assert!(config.password.is_empty());
+        assert_eq!(
+            form(r#", "password": "battery staple", "open": true"#).parse(&stored("")),
+            Err(ApConfigError::OpenWithPassword)
+        );
+    }
+
+    #[test]
+    fn rejects_short_and_non_ascii_passwords() {
+        for password in [r#""short""#, r#""grüezi mitenand""#] {
+            assert_eq!(
+                form(&format!(r#", "password": {password}"#)).parse(&stored("")),
+                Err(ApConfigError::Password)
+            );
+        }
+    }
+
+    #[test]
+    fn reports_open_but_never_the_password() {
+        let mut buf = [0u8; 256];
+        let n = serde_json_core::to_slice(&ApSettings::from(&stored("correct horse")), &mut buf)
+            .unwrap();
+        let json = core::str::from_utf8(&buf[..n]).unwrap();
+        assert!(!json.contains("password") && !json.contains("correct horse"));
+        assert!(json.contains(r#""open":false"#));
+        let n = serde_json_core::to_slice(&ApSettings::from(&stored("")), &mut buf).unwrap();
+        assert!(
+            core::str::from_utf8(&buf[..n])
+                .unwrap()
+                .contains(r#""open":true"#)
+        );
+    }
+
+    #[test]
+    fn checks_channel_address_netmask_and_pool() {
+        let open = stored("");
+        let cases = [
+            (
+                r#""channel": 14, "address": "192.168.4.1", "netmask": "255.255.255.0", "dhcp_pool": 16"#,
+                ApConfigError::Channel,
+            ),
+            (
+                r#""channel": 1, "address": "192.168.4.0", "netmask": "255.255.255.0", "dhcp_pool": 16"#,
+                ApConfigError::Address,
+            ),
+            (
+                r#""channel": 1, "address": "192.168.4.1", "netmask": "255.0.255.0", "dhcp_pool": 16"#,
+                ApConfigError::Netmask,
+            ),
+            (
+                r#""channel": 1, "address": "192.168.4.1", "netmask": "255.255.255.248", "dhcp_pool": 6"#,
+                ApConfigError::DhcpPool,
+            ),
+        ];
+        for (fields, error) in cases {
+            assert_eq!(settings(&format!("{{{fields}}}")).parse(&open), Err(error));
+        }
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let config = stored("correct horse");
+        let mut buf = [0u8; AP_RECORD_LEN];
+        let n = config.encode(&mut buf);
+        assert_eq!(ApConfig::decode(&buf[..n]), Some(config));
+        assert_eq!(ApConfig::decode(&buf[..4]), None);
+    }
+}
@@ -0,0 +1,461 @@
+//! Admin authentication for the web server: the salted password hash kept in
+//! EKV, the `Authorization` header and the bearer tokens `POST /api/login`
+//! hands out. `AuthLayer` in `web_server.rs` decides which requests need it.
+//! Until a password is set, during first provisioning, nothing is asked.
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use heapless::String;
+use sha2::{Digest, Sha256};
+
+use crate::ota_signature::parse_hex;
+
+/// User name expected with HTTP Basic
+pub const ADMIN_USER: &str = "admin";
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 64;
+pub const SALT_LEN: usize = 16;
+const DIGEST_LEN: usize = 32;
+/// Iteration count, salt and digest
+pub const PASSWORD_RECORD_LEN: usize = 4 + SALT_LEN + DIGEST_LEN;
+/// Slows down guessing without stalling the executor for long on every
+/// Basic request
+const HASH_ITERATIONS: u32 = 1024;
+
+pub const TOKEN_LEN: usize = 16;
+pub type Token = [u8; TOKEN_LEN];
+/// Tokens kept at once, the oldest goes first
+pub const MAX_SESSIONS: usize = 4;
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 3600);
+
+/// Base64 of `admin:` and the longest password
+const MAX_BASIC_LEN: usize = (ADMIN_USER.len() + 1 + MAX_PASSWORD_LEN).div_ceil(3) * 4;
+
+/// Iterated, salted SHA-256 of the admin password
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PasswordHash {
+    iterations: u32,
+    salt: [u8; SALT_LEN],
+    digest: [u8; DIGEST_LEN],
+}
+
+impl PasswordHash {
+    /// `None` unless the password is `MIN_PASSWORD_LEN` to `MAX_PASSWORD_LEN` bytes long
+    pub fn new(password: &str, salt: [u8; SALT_LEN]) -> Option<Self> {
+        if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password.len()) {
+            return None;
+        }
+        Some(Self {
+            iterations: HASH_ITERATIONS,
+            salt,
+            digest: stretch(password, &salt, HASH_ITERATIONS),
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let digest = stretch(password, &self.salt, self.iterations);
+        constant_time_eq(&digest, &self.digest)
+    }
+
+    pub fn encode(&self) -> [u8; PASSWORD_RECORD_LEN] {
+        let mut record = [0u8; PASSWORD_RECORD_LEN];
+        record[..4].copy_from_slice(&self.iterations.to_le_bytes());
+        record[4..4 + SALT_LEN].copy_from_slice(&self.salt);
+        record[4 + SALT_LEN..].copy_from_slice(&self.digest);
+        record
+    }
+
+    pub fn decode(record: &[u8]) -> Option<Self> {
+        let record: &[u8; PASSWORD_RECORD_LEN] = record.try_into().ok()?;
+        let iterations = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        if iterations == 0 {
+            return None;
+        }
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&record[4..4 + SALT_LEN]);
+        let mut digest = [0u8; DIGEST_LEN];
+        digest.copy_from_slice(&record[4 + SALT_LEN..]);
+        Some(Self {
+            iterations,
+            salt,
+            digest,
+        })
+    }
+}
+
+fn stretch(password: &str, salt: &[u8], iterations: u32) -> [u8; DIGEST_LEN] {
+    let mut digest: [u8; DIGEST_LEN] = Sha256::new()
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize()
+        .into();
+    for _ in 1..iterations {
+        digest = Sha256::new()
+            .chain_update(digest)
+            .chain_update(password)
+            .finalize()
+            .into();
+    }
+    digest
+}
+
+/// Compares without stopping at the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// What an `Authorization` header carries
+#[derive(Debug, PartialEq)]
+pub enum Credentials {
+    Basic { password: String<MAX_PASSWORD_LEN> },
+    Bearer(Token),
+}
+
+impl Credentials {
+    /// `None` for other schemes, malformed values and other users than `ADMIN_USER`
+    pub fn parse(header: &[u8]) -> Option<Self> {
+        let header = core::str::from_utf8(header).ok()?.trim();
+        let (scheme, value) = header.split_once(' ')?;
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            return parse_hex(value).map(Self::Bearer);
+        }
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        let mut buf = [0u8; MAX_BASIC_LEN];
+        let n = base64_decode(value.as_bytes(), &mut buf)?;
+        let (user, password) = core::str::from_utf8(&buf[..n]).ok()?.split_once(':')?;
+        if user != ADMIN_USER {
+            return None;
+        }
+        Some(Self::Basic {
+            password: String::try_from(password).ok()?,
+        })
+    }
+}
+
+/// Decodes padded standard base64 into `out`, `None` if it is invalid or does not fit
+fn base64_decode(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let chunks = input.chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let mut n = 0;
+    let quads = chunks.len();
+    for (i, quad) in chunks.enumerate() {
+        let padding = match (quad[2], quad[3]) {
+            (b'=', b'=') => 2,
+            (_, b'=') => 1,
+            _ => 0,
+        };
+        if padding > 0 && i + 1 != quads {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &c in &quad[..4 - padding] {
+            bits = bits << 6 | sextet(c)?;
+        }
+        bits <<= 6 * padding as u32;
+        let bytes = bits.to_be_bytes();
+        let len = 3 - padding;
+        out.get_mut(n..n + len)?.copy_from_slice(&bytes[1..1 + len]);
+        n += len;
+    }
+    Some(n)
+}
+
+pub fn format_token(token: &Token) -> String<{ TOKEN_LEN * 2 }> {
+    let mut text = String::new();
+    for byte in token {
+        let _ = write!(text, "{byte:02x}");
+    }
+    text
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Session {
+    token: Token,
+    expires: Instant,
+}
+
+/// Tokens handed out by `POST /api/login`, forgotten on reboot
+#[derive(Debug, Default)]
+pub struct Sessions {
+    sessions: heapless::Vec<Session, MAX_SESSIONS>,
+}
+
+impl Sessions {
+    pub const fn new() -> Self {
+        Self {
+            sessions: heapless::Vec::new(),
+        }
+    }
+
+    /// Starts a session for `token`, ending the oldest when all are in use
+    pub fn issue(&mut self, token: Token, now: Instant) {
+        self.expire(now);
+        if self.sessions.is_full() {
+            self.sessions.remove(0);
+        }
+        let _ = self.sessions.push(Session {
+            token,
+            expires: now + SESSION_LIFETIME,
+        });
+    }
+
+    pub fn is_valid(&mut self, token: &Token, now: Instant) -> bool {
+        self.expire(now);
+        // Every session is compared, so the time taken tells nothing
+        self.sessions.iter().fold(false, |found, session| {
+            found | constant_time_eq(&session.token, token)
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.sessions.retain(|session| session.expires > now);
+    }
+}
+
+/// `None` until a password is set
+static ADMIN_PASSWORD: Mutex<CriticalSectionRawMutex, Cell<Option<PasswordHash>>> =
+    Mutex::new(Cell::new(None));
+static SESSIONS: Mutex<CriticalSectionRawMutex, RefCell<Sessions>> =
+    Mutex::new(RefCell::new(Sessions::new()));
+
+/// Replaces the admin password; sessions of the old one end
+pub fn set_password(hash: PasswordHash) {
+    ADMIN_PASSWORD.lock(|password| password.set(Some(hash)));
+    SESSIONS.lock(|sessions| sessions.borrow_mut().clear());
+}
+
+/// Whether the web server asks for credentials at all
+pub fn password_set() -> bool {
+    ADMIN_PASSWORD.lock(Cell::get).is_some()
+}
+
+pub fn check_password(password: &str) -> bool {
+    // Hashed outside the critical section, it takes a while
+    ADMIN_PASSWORD
+        .lock(Cell::get)
+        .is_some_and(|hash| hash.verify(password))
+}
+
+pub fn is_authorized(credentials: &Credentials) -> bool {
+    match credentials {
+        Credentials::Basic { password } => check_password(password),
+        Credentials::Bearer(token) => {
+            SESSIONS.lock(|sessions| sessions.borrow_mut().is_valid(token, Instant::now()))
+        }
+    }
+}
+
+/// Checks `password` and starts a session for `token`
+pub fn login(password: &str, token: Token) -> bool {
+    if !check_password(password) {
+        return false;
+    }
+    SESSIONS.lock(|sessions| sessions.borrow_mut().issue(token, Instant::now()));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: [u8; SALT_LEN] = [7; SALT_LEN];
+
+    fn base64(input: &[u8]) -> std::string::String {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = std::string::String::new();
+        for chunk in input.chunks(3) {
+            let mut bytes = [0u8; 3];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    fn basic(user_password: &str) -> std::string::String {
+        format!("Basic {}", base64(user_password.as_bytes()))
+    }
+
+    #[test]
+    fn parses_basic_and_bearer() {
+        assert_eq!(
+            Credentials::parse(b"Basic YWRtaW46cGFzc3dvcmQx"),
+            Some(Credentials::Basic {
+                password: String::try_from("password1").unwrap()
+            })
+        );
+        assert_eq!(
+            Credentials::parse(b"  basic   YWRtaW46cGFzc3dvcmQx "),
+            Credentials::parse(b"Basic YWRtaW46cGFzc3dvcmQx")
+        );
+        let token: Token = core::array::from_fn(|i| i as u8 * 17);
+        let header = format!("Bearer {}", format_token(&token));
+        assert_eq!(
+            Credentials::parse(header.as_bytes()),
+            Some(Credentials::Bearer(token))
+        );
+        assert_eq!(Credentials::parse(b"Bearer 0011"), None);
+        assert_eq!(Credentials::parse(b"Digest YWRtaW46cGFzc3dvcmQx"), None);
+        assert_eq!(Credentials::parse(b"Basic"), None);
+    }
+
+    #[test]
+    fn rejects_bad_base64() {
+        for value in [
+            // Missing padding
+            "YWRtaW46cGFzc3dvcmQ",
+            // Padding before the last quad
+            "YQ==YWRtaW46",
+            // Padding only in the third position
+            "YWRtaW46cG=x",
+            // Characters outside the alphabet
+            "YWRt*W46cGFzc3dvcmQx",
+            "YWRtaW46cGFzc3dvcmQx-_",
+        ] {
+            assert_eq!(
+                Credentials::parse(format!("Basic {value}").as_bytes()),
+                None,
+                "{value}"
+            );
+        }
+        assert_eq!(base64_decode(b"YWRtaW46", &mut [0u8; 5]), None);
+        assert_eq!(base64_decode(b"YWRtaW46", &mut [0u8; 6]), Some(6));
+    }
+
+    #[test]
+    fn rejects_other_users_and_missing_colon() {
+        assert_eq!(Credentials::parse(basic("root:password1").as_bytes()), None);
+        assert_eq!(
+            Credentials::parse(basic("Admin:password1").as_bytes()),
+            None
+        );
+        assert_eq!(Credentials::parse(basic("adminpassword1").as_bytes()), None);
+    }
+
+    #[test]
+    fn rejects_passwords_over_the_maximum() {
+        let longest = "p".repeat(MAX_PASSWORD_LEN);
+        assert_eq!(
+            Credentials::parse(basic(&format!("admin:{longest}")).as_bytes()),
+            Some(Credentials::Basic {
+                password: String::try_from(longest.as_str()).unwrap()
+            })
+        );
+        let too_long = "p".repeat(MAX_PASSWORD_LEN + 1);
+        assert_eq!(
+            Credentials::parse(basic(&format!("admin:{too_long}")).as_bytes()),
+            None
+        );
+        assert_eq!(PasswordHash::new(&too_long, SALT), None);
+        assert_eq!(PasswordHash::new("1234567", SALT), None);
+    }
+
+    #[test]
+    fn hash_verifies_and_round_trips() {
+        let hash = PasswordHash::new("correct horse", SALT).unwrap();
+        assert!(hash.verify("correct horse"));
+        assert!(!hash.verify("correct horsE"));
+        assert!(!hash.verify(""));
+        assert_eq!(PasswordHash::decode(&hash.encode()), Some(hash));
+
+        let other_salt = PasswordHash::new("correct horse", [8; SALT_LEN]).unwrap();
+        assert_ne!(other_salt.encode(), hash.encode());
+        assert!(other_salt.verify("correct horse"));
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let record = PasswordHash::new("correct horse", SALT).unwrap().encode();
+        assert_eq!(PasswordHash::decode(&record[1..]), None);
+        assert_eq!(
+            PasswordHash::decode(&[record.as_slice(), &[0]].concat()),
+            None
+        );
+        let mut no_iterations = record;
+        no_iterations[..4].fill(0);
+        assert_eq!(PasswordHash::decode(&no_iterations), None);
+    }
+
+    #[test]
+    fn compares_in_full() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let start = Instant::from_secs(100);
+        let token = [1; TOKEN_LEN];
+        let mut sessions = Sessions::new();
+        sessions.issue(token, start);
+        assert!(sessions.is_valid(&token, start));
+        assert!(!sessions.is_valid(&[2; TOKEN_LEN], start));
+        let almost = start + SESSION_LIFETIME - Duration::from_secs(1);
+        assert!(sessions.is_valid(&token, almost));
+        assert!(!sessions.is_valid(&token, start + SESSION_LIFETIME));
+        // Gone for good, also for an earlier clock reading
+        assert!(!sessions.is_valid(&token, start));
+    }
+
+    #[test]
+    fn evicts_the_oldest_session() {
+        let now = Instant::from_secs(100);
+        let mut sessions = Sessions::new();
+        let tokens: [Token; MAX_SESSIONS + 1] = core::array::from_fn(|i| [i as u8; TOKEN_LEN]);
+        for (i, token) in tokens.iter().enumerate() {
+            sessions.issue(*token, now + Duration::from_secs(i as u64));
+        }
+        let later = now + Duration::from_secs(MAX_SESSIONS as u64);
+        assert!(!sessions.is_valid(&tokens[0], later));
+        for token in &tokens[1..] {
+            assert!(sessions.is_valid(token, later));
+        }
+        sessions.clear();
+        assert!(!sessions.is_valid(&tokens[MAX_SESSIONS], later));
+    }
+
+    #[test]
+    fn expired_sessions_make_room_first() {
+        let start = Instant::from_secs(100);
+        let mut sessions = Sessions::new();
+        for i in 0..MAX_SESSIONS {
+            sessions.issue([i as u8; TOKEN_LEN], start);
+        }
+        let later = start + SESSION_LIFETIME;
+        sessions.issue([9; TOKEN_LEN], later);
+        assert!(sessions.is_valid(&[9; TOKEN_LEN], later));
+        assert_eq!(sessions.sessions.len(), 1);
+    }
+}
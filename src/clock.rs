@@ -0,0 +1,54 @@
+//! Wall-clock time: a UTC offset kept on top of `embassy_time::Instant`,
+//! set by the SNTP task, and the time zone from the settings for local time.
+//! Until the first sync there is no time of day.
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::Ordering;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+
+use crate::TIME_SYNCED;
+use crate::tz::{LocalTime, TimeZone};
+
+/// Microseconds to add to `Instant::now()` for Unix time
+static UTC_OFFSET_US: Mutex<CriticalSectionRawMutex, Cell<i64>> = Mutex::new(Cell::new(0));
+/// `None` is UTC
+static TIME_ZONE: Mutex<CriticalSectionRawMutex, RefCell<Option<TimeZone>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Sets the clock so `Instant` plus `offset_us` is Unix time, and returns how
+/// far the previous offset was off, `None` on the first sync
+pub fn set_utc_offset(offset_us: i64) -> Option<i64> {
+    let previous = UTC_OFFSET_US.lock(|offset| offset.replace(offset_us));
+    let was_synced = TIME_SYNCED.swap(true, Ordering::AcqRel);
+    was_synced.then_some(offset_us - previous)
+}
+
+/// Microseconds since the Unix epoch, `None` until SNTP set the clock
+pub fn now_utc_us() -> Option<u64> {
+    if !TIME_SYNCED.load(Ordering::Acquire) {
+        return None;
+    }
+    let offset = UTC_OFFSET_US.lock(Cell::get);
+    u64::try_from(Instant::now().as_micros() as i64 + offset).ok()
+}
+
+/// Seconds since the Unix epoch, `None` until SNTP set the clock
+pub fn now_utc() -> Option<u64> {
+    now_utc_us().map(|us| us / 1_000_000)
+}
+
+pub fn set_time_zone(zone: TimeZone) {
+    TIME_ZONE.lock(|current| current.replace(Some(zone)));
+}
+
+pub fn time_zone() -> TimeZone {
+    TIME_ZONE.lock(|current| current.borrow().clone().unwrap_or_else(TimeZone::utc))
+}
+
+/// Local time in the configured zone, `None` until SNTP set the clock
+pub fn now_local() -> Option<LocalTime> {
+    let utc_s = now_utc()?;
+    Some(time_zone().to_local(utc_s as i64))
+}
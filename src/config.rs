@@ -1,5 +1,14 @@
-use crate::{DbMutex, KvDatabase, PASSWORD, SSID};
-use core::fmt;
+use crate::ap_config::{AP_RECORD_LEN, is_unicast, is_wpa2_passphrase};
+pub use crate::ap_config::{ApConfig, ApSettings};
+use crate::auth::{PASSWORD_RECORD_LEN, PasswordHash};
+use crate::dhcp_leases::{
+    MAX_RESERVATIONS, Mac, RESERVATION_RECORD_LEN, Reservation, Reservations, format_mac, parse_mac,
+};
+use crate::tz::{TimeZone, TzError};
+use crate::{AP_PASSWORD, DbMutex, KvDatabase, PASSWORD, SSID};
+use core::fmt::{self, Write as _};
+use core::net::Ipv4Addr;
+use core::str::FromStr;
 use ekv::{CommitError, ReadError, WriteError};
 use esp_storage::FlashStorageError;
 use heapless::String;
@@ -13,24 +22,222 @@ pub struct WifiCredentials {
     pub hostname: String<32>,
 }
 
-pub async fn get_wifi_credentials(
-    db_mutex: &'static DbMutex,
-) -> Result<WifiCredentials, WifiSettingsError> {
-    let (_, ssid) = read_wifi_ssid(db_mutex).await?;
-    let (_, password) = read_wifi_password(db_mutex).await?;
-    let (_, hostname) = read_hostname(db_mutex).await?;
+/// Most Wi-Fi networks that can be saved
+pub const MAX_NETWORKS: usize = 8;
+const NETWORK_KEYS: [&[u8]; MAX_NETWORKS] = [
+    b"wifi.net.0",
+    b"wifi.net.1",
+    b"wifi.net.2",
+    b"wifi.net.3",
+    b"wifi.net.4",
+    b"wifi.net.5",
+    b"wifi.net.6",
+    b"wifi.net.7",
+];
+/// Priority, SSID length, SSID and password
+const NETWORK_RECORD_LEN: usize = 2 + 32 + 64;
+
+/// A known network; among visible ones the highest priority is tried first
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SavedNetwork {
+    pub(crate) ssid: String<32>,
+    #[serde(skip_serializing)]
+    pub(crate) psw: String<64>,
+    #[serde(default)]
+    pub(crate) priority: u8,
+}
+
+pub type SavedNetworks = heapless::Vec<SavedNetwork, MAX_NETWORKS>;
+
+impl SavedNetwork {
+    fn encode(&self, buf: &mut [u8; NETWORK_RECORD_LEN]) -> usize {
+        let ssid_end = 2 + self.ssid.len();
+        let end = ssid_end + self.psw.len();
+        buf[0] = self.priority;
+        buf[1] = self.ssid.len() as u8;
+        buf[2..ssid_end].copy_from_slice(self.ssid.as_bytes());
+        buf[ssid_end..end].copy_from_slice(self.psw.as_bytes());
+        end
+    }
 
-    if !ssid.is_empty() && !password.is_empty() {
-        Ok(WifiCredentials {
-            ssid,
-            password,
-            hostname,
+    fn decode(data: &[u8]) -> Option<Self> {
+        let ssid_end = 2 + *data.get(1)? as usize;
+        let ssid = core::str::from_utf8(data.get(2..ssid_end)?).ok()?;
+        let psw = core::str::from_utf8(&data[ssid_end..]).ok()?;
+        Some(Self {
+            ssid: String::try_from(ssid).ok()?,
+            psw: String::try_from(psw).ok()?,
+            priority: data[0],
         })
-    } else {
-        Err(WifiSettingsError::InvalidData)
     }
 }
 
+/// Saved networks, highest priority first. A pair stored the old way under
+/// `wifi.ssid`/`wifi.password` shows up as the only network until the list is saved.
+pub async fn read_networks(db_mutex: &'static DbMutex) -> Result<SavedNetworks, DbError> {
+    let mut networks = SavedNetworks::new();
+    {
+        let mut db = db_mutex.lock().await;
+        let mut buf = [0u8; NETWORK_RECORD_LEN];
+        for key in NETWORK_KEYS {
+            let n = match read_db(&mut db, key, &mut buf).await {
+                Ok(n) => n,
+                Err(DbError::Read(ReadError::KeyNotFound)) => continue,
+                Err(e) => return Err(e),
+            };
+            match SavedNetwork::decode(&buf[..n]) {
+                Some(network) => {
+                    let _ = networks.push(network);
+                }
+                None if n > 0 => error!("Ignoring corrupt saved network {:?}", key),
+                None => {}
+            }
+        }
+    }
+
+    if networks.is_empty() {
+        let legacy = async {
+            let (_, ssid) = read_wifi_ssid(db_mutex).await?;
+            let (_, psw) = read_wifi_password(db_mutex).await?;
+            Ok((ssid, psw))
+        };
+        let (ssid, psw) = match legacy.await {
+            Ok(pair) => pair,
+            Err(DbError::Read(ReadError::KeyNotFound)) => (String::new(), String::new()),
+            Err(e) => return Err(e),
+        };
+        if !ssid.is_empty() && !psw.is_empty() {
+            let _ = networks.push(SavedNetwork {
+                ssid,
+                psw,
+                priority: 0,
+            });
+        }
+    }
+    networks.sort_unstable_by(|a, b| b.priority.cmp(&a.priority));
+    Ok(networks)
+}
+
+/// Replaces the saved networks with `networks` in one transaction
+async fn write_networks(db_mutex: &'static DbMutex, networks: &SavedNetworks) -> DbResult<()> {
+    let db = db_mutex.lock().await;
+    let mut tx = db.write_transaction().await;
+    // ekv wants the keys of a transaction in ascending order
+    let mut buf = [0u8; NETWORK_RECORD_LEN];
+    for (i, key) in NETWORK_KEYS.iter().enumerate() {
+        let n = networks
+            .get(i)
+            .map_or(0, |network| network.encode(&mut buf));
+        tx.write(key, &buf[..n]).await?;
+    }
+    // The list supersedes the single network of older firmware
+    tx.write(b"wifi.password", &[]).await?;
+    tx.write(b"wifi.ssid", &[]).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum NetworksError {
+    Storage(DbError),
+    /// Network without an SSID
+    EmptySsid,
+    /// All `MAX_NETWORKS` slots are taken
+    Full,
+    /// No saved network has that SSID
+    UnknownNetwork,
+}
+
+impl From<DbError> for NetworksError {
+    fn from(e: DbError) -> Self {
+        NetworksError::Storage(e)
+    }
+}
+
+/// Saves `network`, replacing one with the same SSID. Without a priority it
+/// goes to the top of the list.
+pub async fn add_network(
+    network: SavedNetwork,
+    db_mutex: &'static DbMutex,
+) -> Result<SavedNetworks, NetworksError> {
+    if network.ssid.is_empty() {
+        return Err(NetworksError::EmptySsid);
+    }
+    let mut networks = read_networks(db_mutex).await?;
+    networks.retain(|saved| saved.ssid != network.ssid);
+    let mut network = network;
+    if network.priority == 0 {
+        network.priority = networks
+            .iter()
+            .map(|saved| saved.priority)
+            .max()
+            .map_or(1, |top| top.saturating_add(1));
+    }
+    info!(
+        "Saving network {} with priority {}",
+        network.ssid, network.priority
+    );
+    networks.push(network).map_err(|_| NetworksError::Full)?;
+    networks.sort_unstable_by(|a, b| b.priority.cmp(&a.priority));
+    write_networks(db_mutex, &networks).await?;
+    Ok(networks)
+}
+
+pub async fn remove_network(
+    ssid: &str,
+    db_mutex: &'static DbMutex,
+) -> Result<SavedNetworks, NetworksError> {
+    let mut networks = read_networks(db_mutex).await?;
+    let count = networks.len();
+    networks.retain(|saved| saved.ssid != ssid);
+    if networks.len() == count {
+        return Err(NetworksError::UnknownNetwork);
+    }
+    info!("Removing network {}", ssid);
+    write_networks(db_mutex, &networks).await?;
+    Ok(networks)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NetworkSsid {
+    pub(crate) ssid: String<32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NetworkOrder {
+    pub(crate) ssids: heapless::Vec<String<32>, MAX_NETWORKS>,
+}
+
+/// Reassigns priorities so the networks are tried in the order of `order`.
+/// Networks missing from it keep their place behind the listed ones.
+pub async fn reorder_networks(
+    order: &NetworkOrder,
+    db_mutex: &'static DbMutex,
+) -> Result<SavedNetworks, NetworksError> {
+    let mut networks = read_networks(db_mutex).await?;
+    if order
+        .ssids
+        .iter()
+        .any(|ssid| !networks.iter().any(|saved| saved.ssid == *ssid))
+    {
+        return Err(NetworksError::UnknownNetwork);
+    }
+    let rank = |network: &SavedNetwork| {
+        order
+            .ssids
+            .iter()
+            .position(|ssid| *ssid == network.ssid)
+            .unwrap_or(MAX_NETWORKS)
+    };
+    networks.sort_by_key(rank);
+    let count = networks.len() as u8;
+    for (i, network) in networks.iter_mut().enumerate() {
+        network.priority = count - i as u8;
+    }
+    write_networks(db_mutex, &networks).await?;
+    Ok(networks)
+}
+
 #[derive(Debug)]
 pub enum CredTooLongError {
     Ssid,
@@ -51,12 +258,528 @@ pub struct WifiSettings {
     pub(crate) hostname: String<32>,
     pub(crate) ssid: String<32>,
     pub(crate) psw: String<64>,
+    /// Static station address, DHCP when left empty
+    #[serde(default)]
+    pub(crate) ip: String<15>,
+    #[serde(default)]
+    pub(crate) netmask: String<15>,
+    #[serde(default)]
+    pub(crate) gateway: String<15>,
+    #[serde(default)]
+    pub(crate) dns: String<15>,
+    /// New admin password of the web server, left alone when empty
+    #[serde(default, skip_serializing)]
+    pub(crate) admin_password: String<64>,
+}
+
+impl WifiSettings {
+    /// The static address settings, `None` when the station should use DHCP
+    pub fn static_ip(&self) -> Result<Option<StaticIpConfig>, StaticIpError> {
+        StaticIpConfig::parse(&self.ip, &self.netmask, &self.gateway, &self.dns)
+    }
+}
+
+/// Station address used instead of DHCP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StaticIpConfig {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+}
+
+/// Address, prefix length, gateway and DNS server, unset ones as 0.0.0.0
+const STATIC_IP_RECORD_LEN: usize = 13;
+
+#[derive(Debug, PartialEq)]
+pub enum StaticIpError {
+    /// Netmask, gateway or DNS server without an address, or an address without netmask
+    Incomplete,
+    Address,
+    Netmask,
+    Gateway,
+    Dns,
+}
+
+impl StaticIpError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StaticIpError::Incomplete => "Static IP needs an address and a netmask",
+            StaticIpError::Address => "Invalid static IP address",
+            StaticIpError::Netmask => "Invalid netmask",
+            StaticIpError::Gateway => "Gateway must be an address in the subnet",
+            StaticIpError::Dns => "Invalid DNS server address",
+        }
+    }
+}
+
+impl fmt::Display for StaticIpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl StaticIpConfig {
+    /// Validates settings as entered in the form. All fields empty means DHCP;
+    /// gateway and DNS server are optional.
+    pub fn parse(
+        address: &str,
+        netmask: &str,
+        gateway: &str,
+        dns: &str,
+    ) -> Result<Option<Self>, StaticIpError> {
+        let (address, netmask, gateway, dns) =
+            (address.trim(), netmask.trim(), gateway.trim(), dns.trim());
+        if address.is_empty() {
+            return if netmask.is_empty() && gateway.is_empty() && dns.is_empty() {
+                Ok(None)
+            } else {
+                Err(StaticIpError::Incomplete)
+            };
+        }
+        if netmask.is_empty() {
+            return Err(StaticIpError::Incomplete);
+        }
+
+        let address = Ipv4Addr::from_str(address).map_err(|_| StaticIpError::Address)?;
+        let netmask = Ipv4Addr::from_str(netmask).map_err(|_| StaticIpError::Netmask)?;
+        let mask = u32::from(netmask);
+        let prefix_len = mask.leading_ones();
+        // Contiguous, and leaving room for at least two hosts
+        if mask.count_ones() != prefix_len || !(1..=30).contains(&prefix_len) {
+            return Err(StaticIpError::Netmask);
+        }
+        let in_subnet = |addr: Ipv4Addr| {
+            let host = u32::from(addr) & !mask;
+            (u32::from(addr) & mask) == (u32::from(address) & mask) && host != 0 && host != !mask
+        };
+        if !is_unicast(address) || !in_subnet(address) {
+            return Err(StaticIpError::Address);
+        }
+
+        let gateway = match gateway {
+            "" => None,
+            gateway => {
+                let gateway = Ipv4Addr::from_str(gateway).map_err(|_| StaticIpError::Gateway)?;
+                if gateway == address || !in_subnet(gateway) {
+                    return Err(StaticIpError::Gateway);
+                }
+                Some(gateway)
+            }
+        };
+        let dns = match dns {
+            "" => None,
+            dns => match Ipv4Addr::from_str(dns) {
+                Ok(dns) if is_unicast(dns) => Some(dns),
+                _ => return Err(StaticIpError::Dns),
+            },
+        };
+
+        Ok(Some(Self {
+            address,
+            prefix_len: prefix_len as u8,
+            gateway,
+            dns,
+        }))
+    }
+
+    fn encode(&self) -> [u8; STATIC_IP_RECORD_LEN] {
+        let unset = Ipv4Addr::UNSPECIFIED;
+        let mut record = [0u8; STATIC_IP_RECORD_LEN];
+        record[0..4].copy_from_slice(&self.address.octets());
+        record[4] = self.prefix_len;
+        record[5..9].copy_from_slice(&self.gateway.unwrap_or(unset).octets());
+        record[9..13].copy_from_slice(&self.dns.unwrap_or(unset).octets());
+        record
+    }
+
+    fn decode(record: &[u8; STATIC_IP_RECORD_LEN]) -> Self {
+        let addr = |i: usize| Ipv4Addr::new(record[i], record[i + 1], record[i + 2], record[i + 3]);
+        let set = |addr: Ipv4Addr| (!addr.is_unspecified()).then_some(addr);
+        Self {
+            address: addr(0),
+            prefix_len: record[4],
+            gateway: set(addr(5)),
+            dns: set(addr(9)),
+        }
+    }
+}
+
+/// Static station address, `None` when the station uses DHCP
+pub async fn read_static_ip(db_mutex: &'static DbMutex) -> Result<Option<StaticIpConfig>, DbError> {
+    let mut record = [0u8; STATIC_IP_RECORD_LEN];
+    match read_bytes_setting(db_mutex, b"wifi.static_ip", &mut record).await? {
+        Some(STATIC_IP_RECORD_LEN) => Ok(Some(StaticIpConfig::decode(&record))),
+        _ => Ok(None),
+    }
+}
+
+/// Stores the static station address, or switches back to DHCP with `None`
+async fn write_static_ip(
+    db_mutex: &'static DbMutex,
+    config: Option<&StaticIpConfig>,
+) -> Result<(), DbError> {
+    match config {
+        Some(config) => write_bytes_setting(db_mutex, b"wifi.static_ip", &config.encode()).await,
+        None => write_bytes_setting(db_mutex, b"wifi.static_ip", &[]).await,
+    }
+}
+
+impl Default for ApConfig {
+    fn default() -> Self {
+        Self {
+            ssid: String::new(),
+            // Checked like a stored one, an invalid build-time value is ignored
+            password: String::try_from(AP_PASSWORD)
+                .ok()
+                .filter(|password| is_wpa2_passphrase(password))
+                .unwrap_or_default(),
+            channel: 1,
+            gateway: Ipv4Addr::new(192, 168, 1, 1),
+            prefix_len: 24,
+            dhcp_pool: 16,
+            persist_leases: false,
+        }
+    }
+}
+
+/// Access point settings, the defaults until some are stored
+pub async fn read_ap_config(db_mutex: &'static DbMutex) -> Result<ApConfig, DbError> {
+    let mut buf = [0u8; AP_RECORD_LEN];
+    let mut config = match read_bytes_setting(db_mutex, b"wifi.ap", &mut buf).await? {
+        Some(n) if n > 0 => ApConfig::decode(&buf[..n]).unwrap_or_else(|| {
+            error!("Ignoring corrupt AP settings");
+            ApConfig::default()
+        }),
+        _ => ApConfig::default(),
+    };
+    config.persist_leases = read_u32_setting(db_mutex, b"dhcp.persist").await? == Some(1);
+    Ok(config)
+}
+
+pub async fn write_ap_config(db_mutex: &'static DbMutex, config: &ApConfig) -> Result<(), DbError> {
+    info!(
+        "AP settings: SSID {:?} channel {} at {}/{} with {} leases{}",
+        config.ssid,
+        config.channel,
+        config.gateway,
+        config.prefix_len,
+        config.dhcp_pool,
+        if config.persist_leases {
+            ", kept across reboots"
+        } else {
+            ""
+        }
+    );
+    let mut buf = [0u8; AP_RECORD_LEN];
+    let n = config.encode(&mut buf);
+    let mut db = db_mutex.lock().await;
+    let mut tx = db.write_transaction().await;
+    // ekv wants keys in ascending order within a transaction
+    if !config.persist_leases {
+        tx.write(DHCP_LEASES_KEY, &[]).await?;
+    }
+    tx.write(
+        b"dhcp.persist",
+        &(config.persist_leases as u32).to_le_bytes(),
+    )
+    .await?;
+    tx.write(b"wifi.ap", &buf[..n]).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+const DHCP_LEASES_KEY: &[u8] = b"dhcp.leases";
+
+/// Leases saved by the DHCP server, encoded by `LeaseTable::encode_leases`
+pub async fn read_dhcp_leases(
+    db_mutex: &'static DbMutex,
+    buf: &mut [u8],
+) -> Result<usize, DbError> {
+    Ok(read_bytes_setting(db_mutex, DHCP_LEASES_KEY, buf)
+        .await?
+        .unwrap_or(0))
+}
+
+pub async fn write_dhcp_leases(db_mutex: &'static DbMutex, data: &[u8]) -> Result<(), DbError> {
+    write_bytes_setting(db_mutex, DHCP_LEASES_KEY, data).await
+}
+
+pub async fn read_dhcp_reservations(db_mutex: &'static DbMutex) -> Result<Reservations, DbError> {
+    let mut buf = [0u8; MAX_RESERVATIONS * RESERVATION_RECORD_LEN];
+    let n = read_bytes_setting(db_mutex, b"dhcp.reserved", &mut buf)
+        .await?
+        .unwrap_or(0);
+    Ok(buf[..n]
+        .chunks_exact(RESERVATION_RECORD_LEN)
+        .filter_map(Reservation::decode)
+        .collect())
+}
+
+async fn write_dhcp_reservations(
+    db_mutex: &'static DbMutex,
+    reservations: &Reservations,
+) -> Result<(), DbError> {
+    let mut buf = [0u8; MAX_RESERVATIONS * RESERVATION_RECORD_LEN];
+    for (record, reservation) in buf
+        .chunks_exact_mut(RESERVATION_RECORD_LEN)
+        .zip(reservations)
+    {
+        record.copy_from_slice(&reservation.encode());
+    }
+    let len = reservations.len() * RESERVATION_RECORD_LEN;
+    write_bytes_setting(db_mutex, b"dhcp.reserved", &buf[..len]).await
+}
+
+/// DHCP reservation as sent and shown by the browser
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReservationSettings {
+    pub(crate) mac: String<17>,
+    #[serde(default)]
+    pub(crate) ip: String<15>,
+}
+
+#[derive(Debug)]
+pub enum ReservationError {
+    Storage(DbError),
+    Mac,
+    /// Outside the AP subnet, the gateway, or reserved for another client
+    Address,
+    /// All `MAX_RESERVATIONS` slots are taken
+    Full,
+    /// No reservation for that MAC
+    Unknown,
+}
+
+impl From<DbError> for ReservationError {
+    fn from(e: DbError) -> Self {
+        ReservationError::Storage(e)
+    }
+}
+
+impl From<&Reservation> for ReservationSettings {
+    fn from(reservation: &Reservation) -> Self {
+        let mut ip = String::new();
+        let _ = write!(ip, "{}", reservation.ip);
+        Self {
+            mac: format_mac(&reservation.mac),
+            ip,
+        }
+    }
+}
+
+impl ReservationSettings {
+    pub fn mac(&self) -> Result<Mac, ReservationError> {
+        parse_mac(&self.mac).ok_or(ReservationError::Mac)
+    }
+
+    /// Checks the address is a host of the AP subnet other than the gateway
+    pub fn parse(&self, ap: &ApConfig) -> Result<Reservation, ReservationError> {
+        let mac = self.mac()?;
+        let ip = Ipv4Addr::from_str(self.ip.trim()).map_err(|_| ReservationError::Address)?;
+        let mask = u32::from(ap.netmask());
+        let host = u32::from(ip) & !mask;
+        if u32::from(ip) & mask != u32::from(ap.gateway) & mask
+            || host == 0
+            || host == !mask
+            || ip == ap.gateway
+        {
+            return Err(ReservationError::Address);
+        }
+        Ok(Reservation { mac, ip })
+    }
+}
+
+/// Saves `reservation`, replacing the one for the same MAC
+pub async fn add_dhcp_reservation(
+    reservation: Reservation,
+    db_mutex: &'static DbMutex,
+) -> Result<Reservations, ReservationError> {
+    let mut reservations = read_dhcp_reservations(db_mutex).await?;
+    reservations.retain(|saved| saved.mac != reservation.mac);
+    if reservations.iter().any(|saved| saved.ip == reservation.ip) {
+        return Err(ReservationError::Address);
+    }
+    info!(
+        "Reserving {} for {}",
+        reservation.ip,
+        format_mac(&reservation.mac)
+    );
+    reservations
+        .push(reservation)
+        .map_err(|_| ReservationError::Full)?;
+    write_dhcp_reservations(db_mutex, &reservations).await?;
+    Ok(reservations)
+}
+
+pub async fn remove_dhcp_reservation(
+    mac: &Mac,
+    db_mutex: &'static DbMutex,
+) -> Result<Reservations, ReservationError> {
+    let mut reservations = read_dhcp_reservations(db_mutex).await?;
+    let count = reservations.len();
+    reservations.retain(|saved| saved.mac != *mac);
+    if reservations.len() == count {
+        return Err(ReservationError::Unknown);
+    }
+    info!("Removing reservation of {}", format_mac(mac));
+    write_dhcp_reservations(db_mutex, &reservations).await?;
+    Ok(reservations)
+}
+
+pub const MAX_NTP_SERVERS: usize = 4;
+/// Tried in order until one answers
+const DEFAULT_NTP_SERVERS: &str = "pool.ntp.org,time.cloudflare.com,time.google.com";
+
+pub type NtpServers = heapless::Vec<String<64>, MAX_NTP_SERVERS>;
+
+/// NTP servers as sent by the browser: names or addresses, comma separated
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NtpSettings {
+    pub(crate) servers: String<128>,
+}
+
+impl Default for NtpSettings {
+    fn default() -> Self {
+        Self {
+            servers: String::try_from(DEFAULT_NTP_SERVERS).unwrap_or_default(),
+        }
+    }
+}
+
+impl NtpSettings {
+    /// `None` if the list is empty, too long, or a server has blanks in it
+    pub fn parse(&self) -> Option<NtpServers> {
+        let mut servers = NtpServers::new();
+        for server in self.servers.split(',').map(str::trim) {
+            if server.is_empty() || server.contains(char::is_whitespace) {
+                return None;
+            }
+            servers.push(String::try_from(server).ok()?).ok()?;
+        }
+        Some(servers)
+    }
+}
+
+/// NTP servers, the defaults until some are stored
+pub async fn read_ntp_servers(db_mutex: &'static DbMutex) -> Result<NtpSettings, DbError> {
+    let mut buf = [0u8; 128];
+    let n = read_bytes_setting(db_mutex, b"time.ntp_servers", &mut buf)
+        .await?
+        .unwrap_or(0);
+    match core::str::from_utf8(&buf[..n]) {
+        Ok(servers) if !servers.is_empty() => Ok(NtpSettings {
+            servers: String::try_from(servers).unwrap_or_default(),
+        }),
+        _ => Ok(NtpSettings::default()),
+    }
+}
+
+/// Stores `servers` the way [`NtpSettings::parse`] read them
+pub async fn write_ntp_servers(
+    db_mutex: &'static DbMutex,
+    servers: &NtpServers,
+) -> Result<(), DbError> {
+    let mut list: String<128> = String::new();
+    for server in servers {
+        let separator = if list.is_empty() { "" } else { "," };
+        // Trimmed, so never longer than the settings it was parsed from
+        let _ = write!(list, "{separator}{server}");
+    }
+    info!("NTP servers: {list}");
+    write_bytes_setting(db_mutex, b"time.ntp_servers", list.as_bytes()).await
+}
+
+const DEFAULT_TIME_ZONE: &str = "UTC0";
+
+/// Time zone as sent by the browser, a POSIX TZ string
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TimeZoneSettings {
+    pub(crate) timezone: String<64>,
+}
+
+impl Default for TimeZoneSettings {
+    fn default() -> Self {
+        Self {
+            timezone: String::try_from(DEFAULT_TIME_ZONE).unwrap_or_default(),
+        }
+    }
+}
+
+impl TimeZoneSettings {
+    pub fn parse(&self) -> Result<TimeZone, TzError> {
+        TimeZone::parse(&self.timezone)
+    }
+}
+
+/// Time zone, UTC until one is stored
+pub async fn read_time_zone(db_mutex: &'static DbMutex) -> Result<TimeZoneSettings, DbError> {
+    let mut buf = [0u8; 64];
+    let n = read_bytes_setting(db_mutex, b"time.tz", &mut buf)
+        .await?
+        .unwrap_or(0);
+    match core::str::from_utf8(&buf[..n]) {
+        Ok(timezone) if !timezone.is_empty() => Ok(TimeZoneSettings {
+            timezone: String::try_from(timezone).unwrap_or_default(),
+        }),
+        _ => Ok(TimeZoneSettings::default()),
+    }
+}
+
+pub async fn write_time_zone(
+    db_mutex: &'static DbMutex,
+    settings: &TimeZoneSettings,
+) -> Result<(), DbError> {
+    let timezone = settings.timezone.trim();
+    info!("Time zone: {timezone}");
+    write_bytes_setting(db_mutex, b"time.tz", timezone.as_bytes()).await
+}
+
+/// Admin password of the web server, `None` until one is set
+pub async fn read_admin_password(
+    db_mutex: &'static DbMutex,
+) -> Result<Option<PasswordHash>, DbError> {
+    let mut record = [0u8; PASSWORD_RECORD_LEN];
+    match read_bytes_setting(db_mutex, b"auth.password", &mut record).await? {
+        None | Some(0) => Ok(None),
+        Some(n) => {
+            let hash = PasswordHash::decode(&record[..n]);
+            if hash.is_none() {
+                error!("Stored admin password is corrupt");
+            }
+            Ok(hash)
+        }
+    }
+}
+
+pub async fn write_admin_password(
+    db_mutex: &'static DbMutex,
+    hash: &PasswordHash,
+) -> Result<(), DbError> {
+    info!("Admin password changed");
+    write_bytes_setting(db_mutex, b"auth.password", &hash.encode()).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestSettings {
+    pub(crate) url: String<128>,
+}
+
+pub async fn update_manifest_url(
+    settings: &ManifestSettings,
+    db_mutex: &'static DbMutex,
+) -> Result<(), DbError> {
+    info!("OTA manifest URL: {}", settings.url);
+    let mut db = db_mutex.lock().await;
+    write_db(&mut db, b"ota.manifest_url", settings.url.as_bytes()).await
 }
 
 #[derive(Debug)]
 pub enum WifiSettingsError {
     Storage(DbError),
     InvalidData,
+    TooManyNetworks,
+    StaticIp(StaticIpError),
 }
 
 impl fmt::Display for WifiSettingsError {
@@ -64,6 +787,8 @@ impl fmt::Display for WifiSettingsError {
         match self {
             WifiSettingsError::Storage(e) => write!(f, "Storage error: {:?}", e),
             WifiSettingsError::InvalidData => write!(f, "Invalid data format"),
+            WifiSettingsError::TooManyNetworks => write!(f, "No room for another network"),
+            WifiSettingsError::StaticIp(e) => write!(f, "{e}"),
         }
     }
 }
@@ -74,6 +799,26 @@ impl From<DbError> for WifiSettingsError {
     }
 }
 
+impl From<StaticIpError> for WifiSettingsError {
+    fn from(e: StaticIpError) -> Self {
+        WifiSettingsError::StaticIp(e)
+    }
+}
+
+impl From<NetworksError> for WifiSettingsError {
+    fn from(e: NetworksError) -> Self {
+        match e {
+            NetworksError::Storage(e) => WifiSettingsError::Storage(e),
+            NetworksError::Full => WifiSettingsError::TooManyNetworks,
+            NetworksError::EmptySsid | NetworksError::UnknownNetwork => {
+                WifiSettingsError::InvalidData
+            }
+        }
+    }
+}
+
+/// Saves hostname, network and static IP. `/settings` only calls it once the
+/// station joined the network, so rejected credentials never get stored.
 pub async fn update_wifi_settings(
     settings: &WifiSettings,
     db_mutex: &'static DbMutex,
@@ -83,19 +828,32 @@ pub async fn update_wifi_settings(
     info!("  • SSID:     {}", settings.ssid);
     info!("  • Password: {}", settings.psw);
 
+    // Checked before anything is written, so a typo leaves the old settings alone
+    let static_ip = settings.static_ip()?;
+    match &static_ip {
+        Some(config) => info!(
+            "  • Static IP: {}/{} gateway {:?} DNS {:?}",
+            config.address, config.prefix_len, config.gateway, config.dns
+        ),
+        None => info!("  • DHCP"),
+    }
+
     {
         let mut db = db_mutex.lock().await;
         write_db(&mut db, b"wifi.hostname", settings.hostname.as_bytes()).await?;
-        write_db(&mut db, b"wifi.ssid", settings.ssid.as_bytes()).await?;
-        write_db(&mut db, b"wifi.password", settings.psw.as_bytes()).await?;
     }
+    let network = SavedNetwork {
+        ssid: settings.ssid.clone(),
+        psw: settings.psw.clone(),
+        priority: 0,
+    };
+    add_network(network, db_mutex).await?;
+    write_static_ip(db_mutex, static_ip.as_ref()).await?;
 
-    let (len, ssid) = read_wifi_ssid(db_mutex).await?;
-    if len == 0 {
-        return Err(WifiSettingsError::InvalidData);
-    }
-
-    let verified = len == settings.ssid.len() && ssid.as_bytes() == settings.ssid.as_bytes();
+    let networks = read_networks(db_mutex).await?;
+    let verified = networks
+        .iter()
+        .any(|saved| saved.ssid == settings.ssid && saved.psw == settings.psw);
 
     if verified {
         info!("✅  Wi-Fi settings saved and SSID verified.");
@@ -124,6 +882,52 @@ pub async fn read_setting<const N: usize>(
     Ok((n, setting))
 }
 
+/// Reads a little-endian `u32`, `None` if the key was never written
+pub async fn read_u32_setting(
+    db_mutex: &'static DbMutex,
+    key: &[u8],
+) -> Result<Option<u32>, DbError> {
+    let mut buf = [0u8; 4];
+    let mut db = db_mutex.lock().await;
+    match read_db(&mut db, key, &mut buf).await {
+        Ok(4) => Ok(Some(u32::from_le_bytes(buf))),
+        Ok(_) | Err(DbError::Read(ReadError::KeyNotFound)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn write_u32_setting(
+    db_mutex: &'static DbMutex,
+    key: &[u8],
+    value: u32,
+) -> Result<(), DbError> {
+    let mut db = db_mutex.lock().await;
+    write_db(&mut db, key, &value.to_le_bytes()).await
+}
+
+/// Reads a raw value into `buf`, `None` if the key was never written
+pub async fn read_bytes_setting(
+    db_mutex: &'static DbMutex,
+    key: &[u8],
+    buf: &mut [u8],
+) -> Result<Option<usize>, DbError> {
+    let mut db = db_mutex.lock().await;
+    match read_db(&mut db, key, buf).await {
+        Ok(n) => Ok(Some(n)),
+        Err(DbError::Read(ReadError::KeyNotFound)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn write_bytes_setting(
+    db_mutex: &'static DbMutex,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), DbError> {
+    let mut db = db_mutex.lock().await;
+    write_db(&mut db, key, value).await
+}
+
 #[derive(Debug)]
 pub enum DbError {
     Write(WriteError<FlashStorageError>),
@@ -186,3 +990,14 @@ pub async fn read_wifi_password(
 pub async fn read_hostname(db_mutex: &'static DbMutex) -> Result<(usize, String<32>), DbError> {
     read_setting(db_mutex, b"wifi.hostname").await
 }
+
+/// Failed STA connects before the fallback AP starts, `None` if not configured
+pub async fn read_sta_failure_limit(db_mutex: &'static DbMutex) -> Result<Option<u32>, DbError> {
+    read_u32_setting(db_mutex, b"wifi.sta_fail_limit").await
+}
+
+pub async fn read_manifest_url(
+    db_mutex: &'static DbMutex,
+) -> Result<(usize, String<128>), DbError> {
+    read_setting(db_mutex, b"ota.manifest_url").await
+}
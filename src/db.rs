@@ -3,7 +3,7 @@ use ekv::flash::{self, PageID};
 use embedded_storage_async::nor_flash::{NorFlash as AsyncNorFlash, ReadNorFlash};
 
 #[repr(C, align(4))]
-struct AlignedBuf<const N: usize>([u8; N]);
+pub(crate) struct AlignedBuf<const N: usize>(pub(crate) [u8; N]);
 
 pub struct DbFlash<T: AsyncNorFlash + ReadNorFlash> {
     pub(crate) start: usize,
@@ -0,0 +1,523 @@
+//! Delta OTA patches: a bsdiff-style patch rebuilds the new image from the
+//! image in the running slot, so only the differences go over the air.
+//!
+//! Layout, all integers little endian:
+//!
+//! | field                        | size |
+//! |------------------------------|------|
+//! | magic `EDP1`                 | 4    |
+//! | source image length          | 4    |
+//! | SHA-256 of the source image  | 32   |
+//! | target image length          | 4    |
+//!
+//! followed by records until the target image is complete:
+//!
+//! | field            | size        |
+//! |------------------|-------------|
+//! | diff length      | 4           |
+//! | extra length     | 4           |
+//! | seek             | 4 (signed)  |
+//! | diff bytes       | diff length |
+//! | extra bytes      | extra length|
+//!
+//! Diff bytes are added (wrapping) to the source bytes at the current source
+//! position, extra bytes are copied verbatim, then the source position moves
+//! by `seek`. `tools/mkpatch.py` produces this from two images.
+
+use crate::ota_signature::{DIGEST_LEN, ImageDigest};
+use embedded_storage::ReadStorage;
+
+pub const PATCH_MAGIC: [u8; 4] = *b"EDP1";
+
+const HEADER_LEN: usize = 4 + 4 + DIGEST_LEN + 4;
+const RECORD_LEN: usize = 12;
+const SOURCE_CHUNK: usize = 256;
+/// Source bytes hashed per [`DeltaDecoder::hash_source`] call
+const HASH_STEP: u32 = 4096;
+
+#[derive(Debug)]
+pub enum DeltaError {
+    /// Malformed patch or one that reaches outside source or target
+    InvalidPatch,
+
+    /// The running image is not the one the patch was made against
+    SourceMismatch,
+}
+
+enum State {
+    Header,
+    /// Source image hashed up to here
+    Source(u32),
+    Record,
+    Diff(u32),
+    Extra(u32),
+}
+
+/// Applies a patch streamed in arbitrary chunks, reading the source image from `source`
+pub struct DeltaDecoder<S: ReadStorage> {
+    source: S,
+    source_offset: u32,
+    source_capacity: u32,
+    source_len: u32,
+    source_pos: u32,
+    target_len: u32,
+    produced: u32,
+    state: State,
+    extra_len: u32,
+    seek: i32,
+    field: [u8; HEADER_LEN],
+    field_len: usize,
+    chunk: [u8; SOURCE_CHUNK],
+    digest: ImageDigest,
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+impl<S: ReadStorage> DeltaDecoder<S> {
+    /// `source_offset` and `source_capacity` describe the partition holding the source image
+    pub fn new(source: S, source_offset: u32, source_capacity: u32) -> Self {
+        Self {
+            source,
+            source_offset,
+            source_capacity,
+            source_len: 0,
+            source_pos: 0,
+            target_len: 0,
+            produced: 0,
+            state: State::Header,
+            extra_len: 0,
+            seek: 0,
+            field: [0; HEADER_LEN],
+            field_len: 0,
+            chunk: [0; SOURCE_CHUNK],
+            digest: ImageDigest::new(),
+        }
+    }
+
+    /// Decodes the next piece of the patch and hands the rebuilt image to `sink`.
+    /// Returns how much of `input` it took: it stops after the header until
+    /// [`Self::hash_source`] checked the source image.
+    pub fn decode<E, F>(&mut self, input: &[u8], sink: &mut F) -> Result<usize, E>
+    where
+        E: From<DeltaError> + From<S::Error>,
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        let len = input.len();
+        let mut input = input;
+        while !input.is_empty() {
+            match self.state {
+                State::Header => {
+                    input = self.collect(input, HEADER_LEN);
+                    if self.field_len == HEADER_LEN {
+                        self.start()?;
+                    }
+                }
+                State::Source(_) => break,
+                State::Record => {
+                    input = self.collect(input, RECORD_LEN);
+                    if self.field_len == RECORD_LEN {
+                        let diff_len = le_u32(&self.field[0..4]);
+                        self.extra_len = le_u32(&self.field[4..8]);
+                        self.seek = le_u32(&self.field[8..12]) as i32;
+                        self.field_len = 0;
+                        self.state = State::Diff(diff_len);
+                    }
+                }
+                State::Diff(remaining) => {
+                    let n = (remaining as usize).min(input.len()).min(SOURCE_CHUNK);
+                    if self.source_pos as usize + n > self.source_len as usize {
+                        return Err(DeltaError::InvalidPatch.into());
+                    }
+                    self.source
+                        .read(self.source_offset + self.source_pos, &mut self.chunk[..n])?;
+                    for (byte, diff) in self.chunk[..n].iter_mut().zip(&input[..n]) {
+                        *byte = byte.wrapping_add(*diff);
+                    }
+                    Self::emit(&mut self.produced, self.target_len, &self.chunk[..n], sink)?;
+                    self.source_pos += n as u32;
+                    input = &input[n..];
+                    self.state = State::Diff(remaining - n as u32);
+                }
+                State::Extra(remaining) => {
+                    let n = (remaining as usize).min(input.len());
+                    Self::emit(&mut self.produced, self.target_len, &input[..n], sink)?;
+                    input = &input[n..];
+                    self.state = State::Extra(remaining - n as u32);
+                }
+            }
+            self.advance()?;
+        }
+        Ok(len - input.len())
+    }
+
+    /// Hashes the next `HASH_STEP` bytes of the source image, `true` while
+    /// there is more. The caller yields in between, so hashing megabytes of
+    /// flash doesn't stall the executor.
+    pub fn hash_source<E>(&mut self) -> Result<bool, E>
+    where
+        E: From<DeltaError> + From<S::Error>,
+    {
+        let State::Source(mut pos) = self.state else {
+            return Ok(false);
+        };
+        let end = self.source_len.min(pos + HASH_STEP);
+        while pos < end {
+            let n = ((end - pos) as usize).min(SOURCE_CHUNK);
+            self.source
+                .read(self.source_offset + pos, &mut self.chunk[..n])?;
+            self.digest.update(&self.chunk[..n]);
+            pos += n as u32;
+        }
+        if pos < self.source_len {
+            self.state = State::Source(pos);
+            return Ok(true);
+        }
+
+        if core::mem::take(&mut self.digest).finalize()[..] != self.field[8..8 + DIGEST_LEN] {
+            return Err(DeltaError::SourceMismatch.into());
+        }
+        self.field_len = 0;
+        self.state = State::Record;
+        Ok(false)
+    }
+
+    /// Checks that the patch ended on a record boundary with the whole target rebuilt
+    pub fn finish(&self) -> Result<(), DeltaError> {
+        if matches!(self.state, State::Record)
+            && self.field_len == 0
+            && self.produced == self.target_len
+        {
+            Ok(())
+        } else {
+            Err(DeltaError::InvalidPatch)
+        }
+    }
+
+    fn collect<'a>(&mut self, input: &'a [u8], len: usize) -> &'a [u8] {
+        let n = (len - self.field_len).min(input.len());
+        self.field[self.field_len..self.field_len + n].copy_from_slice(&input[..n]);
+        self.field_len += n;
+        &input[n..]
+    }
+
+    /// Parses the header; the source image is hashed before anything is written
+    fn start(&mut self) -> Result<(), DeltaError> {
+        if self.field[0..4] != PATCH_MAGIC {
+            return Err(DeltaError::InvalidPatch);
+        }
+        self.source_len = le_u32(&self.field[4..8]);
+        self.target_len = le_u32(&self.field[8 + DIGEST_LEN..HEADER_LEN]);
+        if self.source_len > self.source_capacity {
+            return Err(DeltaError::InvalidPatch);
+        }
+        self.digest = ImageDigest::new();
+        self.state = State::Source(0);
+        Ok(())
+    }
+
+    /// Moves past finished diff and extra blocks
+    fn advance(&mut self) -> Result<(), DeltaError> {
+        loop {
+            match self.state {
+                State::Diff(0) => self.state = State::Extra(self.extra_len),
+                State::Extra(0) => {
+                    let pos = self.source_pos as i64 + self.seek as i64;
+                    if pos < 0 || pos > self.source_len as i64 {
+                        return Err(DeltaError::InvalidPatch);
+                    }
+                    self.source_pos = pos as u32;
+                    self.state = State::Record;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn emit<E, F>(produced: &mut u32, target_len: u32, data: &[u8], sink: &mut F) -> Result<(), E>
+    where
+        E: From<DeltaError>,
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        if *produced as usize + data.len() > target_len as usize {
+            return Err(DeltaError::InvalidPatch.into());
+        }
+        sink(data)?;
+        *produced += data.len() as u32;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{
+        ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash, check_erase, check_read, check_write,
+    };
+
+    /// `tools/mkpatch.py image.bin image2.bin image2.patch`, see `host-tests/fixtures/generate.sh`
+    const SOURCE: &[u8] = include_bytes!("../host-tests/fixtures/image.bin");
+    const TARGET: &[u8] = include_bytes!("../host-tests/fixtures/image2.bin");
+    const PATCH: &[u8] = include_bytes!("../host-tests/fixtures/image2.patch");
+
+    const SLOT_SIZE: u32 = 0x4000;
+    /// Source slot behind a partition before it, as `ota_1` is
+    const SOURCE_OFFSET: u32 = 0x1000;
+
+    /// NOR flash in memory: erasing sets all bits, writing only clears them
+    struct MemFlash {
+        data: Vec<u8>,
+    }
+
+    impl MemFlash {
+        fn new(size: u32) -> Self {
+            Self {
+                data: vec![0xff; size as usize],
+            }
+        }
+
+        fn with_source(source: &[u8]) -> Self {
+            let mut flash = Self::new(SOURCE_OFFSET + SLOT_SIZE);
+            flash.write(SOURCE_OFFSET, source).unwrap();
+            flash
+        }
+    }
+
+    impl ErrorType for MemFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MemFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len())?;
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MemFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = 4096;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            check_erase(self, from, to)?;
+            self.data[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            check_write(self, offset, bytes.len())?;
+            for (cell, byte) in self.data[offset as usize..].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    impl ReadStorage for MemFlash {
+        type Error = NorFlashErrorKind;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            ReadNorFlash::read(self, offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            ReadNorFlash::capacity(self)
+        }
+    }
+
+    #[derive(Debug)]
+    enum TestError {
+        Delta(DeltaError),
+        Flash(NorFlashErrorKind),
+    }
+
+    impl From<DeltaError> for TestError {
+        fn from(error: DeltaError) -> Self {
+            Self::Delta(error)
+        }
+    }
+
+    impl From<NorFlashErrorKind> for TestError {
+        fn from(error: NorFlashErrorKind) -> Self {
+            Self::Flash(error)
+        }
+    }
+
+    /// Streams `patch` in `chunk` byte pieces against the source in `flash`
+    /// into an erased target slot, the way the OTA writer does
+    fn apply(flash: MemFlash, patch: &[u8], chunk: usize) -> Result<Vec<u8>, TestError> {
+        let mut target = MemFlash::new(SLOT_SIZE);
+        let mut written = 0;
+        let mut decoder = DeltaDecoder::new(flash, SOURCE_OFFSET, SLOT_SIZE);
+        for mut piece in patch.chunks(chunk) {
+            while !piece.is_empty() {
+                let n = decoder.decode(piece, &mut |out: &[u8]| {
+                    target.write(written, out)?;
+                    written += out.len() as u32;
+                    Ok::<_, TestError>(())
+                })?;
+                piece = &piece[n..];
+                while decoder.hash_source::<TestError>()? {}
+            }
+        }
+        decoder.finish()?;
+        let mut image = vec![0; written as usize];
+        ReadNorFlash::read(&mut target, 0, &mut image)?;
+        Ok(image)
+    }
+
+    fn header(source: &[u8], target_len: u32) -> Vec<u8> {
+        let mut digest = ImageDigest::new();
+        digest.update(source);
+        let mut header = PATCH_MAGIC.to_vec();
+        header.extend_from_slice(&(source.len() as u32).to_le_bytes());
+        header.extend_from_slice(&digest.finalize());
+        header.extend_from_slice(&target_len.to_le_bytes());
+        header
+    }
+
+    fn record(diff_len: u32, extra_len: u32, seek: i32) -> Vec<u8> {
+        [
+            diff_len.to_le_bytes(),
+            extra_len.to_le_bytes(),
+            seek.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn applies_fixture() {
+        assert_eq!(PATCH[..4], PATCH_MAGIC);
+        let image = apply(MemFlash::with_source(SOURCE), PATCH, 512).unwrap();
+        assert_eq!(image, TARGET);
+    }
+
+    #[test]
+    fn applies_in_any_chunking() {
+        for chunk in [
+            1,
+            3,
+            HEADER_LEN,
+            HEADER_LEN + 1,
+            RECORD_LEN,
+            1000,
+            PATCH.len(),
+        ] {
+            let image = apply(MemFlash::with_source(SOURCE), PATCH, chunk).unwrap();
+            assert_eq!(image, TARGET, "chunks of {chunk}");
+        }
+    }
+
+    #[test]
+    fn hashes_source_in_steps() {
+        let mut decoder =
+            DeltaDecoder::new(MemFlash::with_source(SOURCE), SOURCE_OFFSET, SLOT_SIZE);
+        let mut sink = |_: &[u8]| Ok::<_, TestError>(());
+        assert_eq!(decoder.decode(PATCH, &mut sink).unwrap(), HEADER_LEN);
+        // Nothing more is taken until the source is checked
+        assert_eq!(decoder.decode(&PATCH[HEADER_LEN..], &mut sink).unwrap(), 0);
+
+        let steps = SOURCE.len().div_ceil(HASH_STEP as usize);
+        for _ in 1..steps {
+            assert!(decoder.hash_source::<TestError>().unwrap());
+        }
+        assert!(!decoder.hash_source::<TestError>().unwrap());
+        assert!(!decoder.hash_source::<TestError>().unwrap());
+        assert_eq!(
+            decoder.decode(&PATCH[HEADER_LEN..], &mut sink).unwrap(),
+            PATCH.len() - HEADER_LEN
+        );
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_other_source() {
+        let mut source = SOURCE.to_vec();
+        source[4000] ^= 1;
+        let result = apply(MemFlash::with_source(&source), PATCH, 512);
+        assert!(matches!(
+            result,
+            Err(TestError::Delta(DeltaError::SourceMismatch))
+        ));
+
+        let mut patch = PATCH.to_vec();
+        patch[8] ^= 1;
+        let result = apply(MemFlash::with_source(SOURCE), &patch, 512);
+        assert!(matches!(
+            result,
+            Err(TestError::Delta(DeltaError::SourceMismatch))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_patch() {
+        for len in 0..PATCH.len() {
+            let result = apply(MemFlash::with_source(SOURCE), &PATCH[..len], 512);
+            assert!(
+                matches!(result, Err(TestError::Delta(DeltaError::InvalidPatch))),
+                "cut at {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn passes_flash_errors_on() {
+        // The source slot claims more than the flash has
+        let patch = header(&[0xff; SLOT_SIZE as usize], 4);
+        let result = apply(MemFlash::new(SLOT_SIZE), &patch, 512);
+        assert!(matches!(
+            result,
+            Err(TestError::Flash(NorFlashErrorKind::OutOfBounds))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_patch() {
+        let flash = || MemFlash::with_source(SOURCE);
+        let invalid = |result| matches!(result, Err(TestError::Delta(DeltaError::InvalidPatch)));
+
+        let mut patch = PATCH.to_vec();
+        patch[3] = b'2';
+        assert!(invalid(apply(flash(), &patch, 512)));
+
+        // Source longer than its slot
+        let mut patch = header(SOURCE, 4);
+        patch[4..8].copy_from_slice(&(SLOT_SIZE + 1).to_le_bytes());
+        assert!(invalid(apply(flash(), &patch, 512)));
+
+        // Diff reaching past the source
+        let mut patch = header(SOURCE, SOURCE.len() as u32 + 1);
+        patch.extend(record(SOURCE.len() as u32 + 1, 0, 0));
+        patch.extend(vec![0; SOURCE.len() + 1]);
+        assert!(invalid(apply(flash(), &patch, 512)));
+
+        // Seek before the start of the source
+        let mut patch = header(SOURCE, 8);
+        patch.extend(record(4, 0, -5));
+        patch.extend([0; 4]);
+        assert!(invalid(apply(flash(), &patch, 512)));
+
+        // More output than the target length
+        let mut patch = header(SOURCE, 4);
+        patch.extend(record(0, 5, 0));
+        patch.extend(*b"extra");
+        assert!(invalid(apply(flash(), &patch, 512)));
+
+        // A complete target, then another record
+        let mut patch = header(SOURCE, 4);
+        patch.extend(record(4, 0, 0));
+        patch.extend([0; 4]);
+        assert_eq!(apply(flash(), &patch, 512).unwrap(), SOURCE[..4]);
+        patch.extend(&record(0, 0, 0)[..6]);
+        assert!(invalid(apply(flash(), &patch, 512)));
+    }
+}
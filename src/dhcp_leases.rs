@@ -0,0 +1,264 @@
+//! Lease table of the access point's DHCP server: dynamic leases from a pool,
+//! static reservations by MAC, and a compact encoding to keep leases across
+//! reboots. No I/O here; `run_dhcp` in `wifi.rs` feeds it the requests.
+
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use heapless::String;
+
+/// Largest dynamic pool
+pub const MAX_POOL: usize = 64;
+pub const MAX_RESERVATIONS: usize = 16;
+/// Every pool address and every reservation can be leased at once
+pub const MAX_LEASES: usize = MAX_POOL + MAX_RESERVATIONS;
+/// MAC, address and remaining seconds
+pub const LEASE_RECORD_LEN: usize = 6 + 4 + 4;
+/// MAC and address
+pub const RESERVATION_RECORD_LEN: usize = 6 + 4;
+
+pub type Mac = [u8; 6];
+
+/// `aa:bb:cc:dd:ee:ff`
+pub fn format_mac(mac: &[u8]) -> String<17> {
+    let mut text = String::new();
+    for (i, byte) in mac.iter().enumerate() {
+        let separator = if i == 0 { "" } else { ":" };
+        // 6 bytes of "xx:" fit the 17 characters exactly
+        let _ = write!(text, "{separator}{byte:02x}");
+    }
+    text
+}
+
+/// Reads a MAC written with `:` or `-` between the bytes, in either case
+pub fn parse_mac(text: &str) -> Option<Mac> {
+    let mut mac = [0u8; 6];
+    let mut parts = text.trim().split([':', '-']);
+    for byte in mac.iter_mut() {
+        let part = parts.next().filter(|part| part.len() == 2)?;
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lease {
+    pub mac: Mac,
+    pub ip: Ipv4Addr,
+    /// Seconds since boot
+    pub expires_s: u64,
+}
+
+/// Address always handed to one client, whether or not it is in the pool
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reservation {
+    pub mac: Mac,
+    pub ip: Ipv4Addr,
+}
+
+pub type Reservations = heapless::Vec<Reservation, MAX_RESERVATIONS>;
+
+impl Reservation {
+    pub fn encode(&self) -> [u8; RESERVATION_RECORD_LEN] {
+        let mut record = [0u8; RESERVATION_RECORD_LEN];
+        record[..6].copy_from_slice(&self.mac);
+        record[6..].copy_from_slice(&self.ip.octets());
+        record
+    }
+
+    pub fn decode(record: &[u8]) -> Option<Self> {
+        let record: &[u8; RESERVATION_RECORD_LEN] = record.try_into().ok()?;
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&record[..6]);
+        Some(Self {
+            mac,
+            ip: Ipv4Addr::new(record[6], record[7], record[8], record[9]),
+        })
+    }
+}
+
+/// Outcome of a DHCPREQUEST
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeaseUpdate {
+    /// The client got a new address, the table changed
+    Added,
+    /// Same client, same address, later expiry
+    Renewed,
+    /// The address belongs to someone else or is outside the pool
+    Rejected,
+}
+
+pub struct LeaseTable {
+    range_start: Ipv4Addr,
+    range_end: Ipv4Addr,
+    leases: heapless::Vec<Lease, MAX_LEASES>,
+    reservations: Reservations,
+}
+
+impl Default for LeaseTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LeaseTable {
+    pub const fn new() -> Self {
+        Self {
+            range_start: Ipv4Addr::UNSPECIFIED,
+            range_end: Ipv4Addr::UNSPECIFIED,
+            leases: heapless::Vec::new(),
+            reservations: heapless::Vec::new(),
+        }
+    }
+
+    /// Sets the pool dynamic leases come from
+    pub fn set_range(&mut self, start: Ipv4Addr, end: Ipv4Addr) {
+        self.range_start = start;
+        self.range_end = end;
+    }
+
+    pub fn leases(&self) -> &[Lease] {
+        &self.leases
+    }
+
+    pub fn reservations(&self) -> &[Reservation] {
+        &self.reservations
+    }
+
+    /// Replaces the reservations. Dynamic leases on a now reserved address
+    /// are dropped, their clients get another one at the next renewal.
+    pub fn set_reservations(&mut self, reservations: &[Reservation]) {
+        self.reservations.clear();
+        for reservation in reservations.iter().take(MAX_RESERVATIONS) {
+            let _ = self.reservations.push(*reservation);
+        }
+        let taken = &self.reservations;
+        self.leases
+            .retain(|lease| taken.iter().all(|r| r.ip != lease.ip || r.mac == lease.mac));
+    }
+
+    pub fn reserved_for(&self, mac: &Mac) -> Option<Ipv4Addr> {
+        self.reservations
+            .iter()
+            .find(|r| r.mac == *mac)
+            .map(|r| r.ip)
+    }
+
+    fn in_range(&self, ip: Ipv4Addr) -> bool {
+        (u32::from(self.range_start)..=u32::from(self.range_end)).contains(&u32::from(ip))
+    }
+
+    /// Whether `mac` may have `ip` at `now`
+    fn is_available(&self, mac: &Mac, ip: Ipv4Addr, now: u64) -> bool {
+        if let Some(reserved) = self.reserved_for(mac) {
+            return reserved == ip;
+        }
+        self.in_range(ip)
+            && !self.reservations.iter().any(|r| r.ip == ip)
+            && !self
+                .leases
+                .iter()
+                .any(|lease| lease.ip == ip && lease.mac != *mac && lease.expires_s > now)
+    }
+
+    /// Address to offer on a DHCPDISCOVER: the reservation, else the requested
+    /// address if free, else the client's current lease, else the first free one
+    pub fn offer(&self, mac: &Mac, requested: Option<Ipv4Addr>, now: u64) -> Option<Ipv4Addr> {
+        if let Some(reserved) = self.reserved_for(mac) {
+            return Some(reserved);
+        }
+        requested
+            .filter(|ip| self.is_available(mac, *ip, now))
+            .or_else(|| {
+                self.leases
+                    .iter()
+                    .find(|lease| lease.mac == *mac && self.is_available(mac, lease.ip, now))
+                    .map(|lease| lease.ip)
+            })
+            .or_else(|| {
+                (u32::from(self.range_start)..=u32::from(self.range_end))
+                    .map(Ipv4Addr::from)
+                    .find(|ip| self.is_available(mac, *ip, now))
+            })
+    }
+
+    /// Records `ip` as leased to `mac` for `duration_s` on a DHCPREQUEST
+    pub fn confirm(&mut self, mac: &Mac, ip: Ipv4Addr, now: u64, duration_s: u32) -> LeaseUpdate {
+        if !self.is_available(mac, ip, now) {
+            return LeaseUpdate::Rejected;
+        }
+        let expires_s = now + duration_s as u64;
+        if let Some(lease) = self.leases.iter_mut().find(|lease| lease.mac == *mac) {
+            let moved = lease.ip != ip;
+            lease.ip = ip;
+            lease.expires_s = expires_s;
+            if !moved {
+                return LeaseUpdate::Renewed;
+            }
+            // An expired lease of another client may still hold the address
+            let mac = *mac;
+            self.leases
+                .retain(|lease| lease.ip != ip || lease.mac == mac);
+            return LeaseUpdate::Added;
+        }
+
+        self.leases.retain(|lease| lease.ip != ip);
+        if self.leases.is_full() {
+            // Make room by dropping the lease that expired first, if any did
+            let oldest = self
+                .leases
+                .iter()
+                .enumerate()
+                .filter(|(_, lease)| lease.expires_s <= now)
+                .min_by_key(|(_, lease)| lease.expires_s)
+                .map(|(i, _)| i);
+            match oldest {
+                Some(i) => {
+                    self.leases.swap_remove(i);
+                }
+                None => return LeaseUpdate::Rejected,
+            }
+        }
+        let _ = self.leases.push(Lease {
+            mac: *mac,
+            ip,
+            expires_s,
+        });
+        LeaseUpdate::Added
+    }
+
+    /// Drops the lease of `mac` on DHCPRELEASE or DHCPDECLINE, `true` if it had one
+    pub fn release(&mut self, mac: &Mac) -> bool {
+        let count = self.leases.len();
+        self.leases.retain(|lease| lease.mac != *mac);
+        self.leases.len() != count
+    }
+
+    /// Writes the unexpired leases to `out`, with their remaining time.
+    /// `out` holds all of them with `MAX_LEASES * LEASE_RECORD_LEN` bytes.
+    pub fn encode_leases(&self, now: u64, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        for lease in self.leases.iter().filter(|lease| lease.expires_s > now) {
+            let Some(record) = out.get_mut(len..len + LEASE_RECORD_LEN) else {
+                break;
+            };
+            let remaining = u32::try_from(lease.expires_s - now).unwrap_or(u32::MAX);
+            record[..6].copy_from_slice(&lease.mac);
+            record[6..10].copy_from_slice(&lease.ip.octets());
+            record[10..].copy_from_slice(&remaining.to_le_bytes());
+            len += LEASE_RECORD_LEN;
+        }
+        len
+    }
+
+    /// Adds leases saved by [`Self::encode_leases`], counting their remaining
+    /// time from `now`. Ones that clash with the pool or a reservation are skipped.
+    pub fn restore_leases(&mut self, data: &[u8], now: u64) {
+        for record in data.chunks_exact(LEASE_RECORD_LEN) {
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(&record[..6]);
+            let ip = Ipv4Addr::new(record[6], record[7], record[8], record[9]);
+            let remaining = u32::from_le_bytes([record[10], record[11], record[12], record[13]]);
+            self.confirm(&mac, ip, now, remaining);
+        }
+    }
+}
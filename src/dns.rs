@@ -0,0 +1,545 @@
+//! Just enough of the DNS wire format (RFC 1035) for the captive portal and
+//! mDNS responders: parsing queries and writing answers. No I/O here, so it can be
+//! exercised on the host with plain byte buffers.
+
+use core::net::Ipv4Addr;
+
+pub const HEADER_LEN: usize = 12;
+
+pub const FLAG_QR: u16 = 0x8000;
+pub const FLAG_AA: u16 = 0x0400;
+pub const FLAG_RD: u16 = 0x0100;
+pub const FLAG_RA: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+/// Longest name on the wire
+const MAX_NAME_LEN: usize = 255;
+/// Compression pointers followed before a name is considered a loop
+const MAX_POINTERS: usize = 16;
+/// Captive portal answers are not worth caching for long
+const CAPTIVE_TTL_S: u32 = 60;
+
+#[derive(Debug, PartialEq)]
+pub enum DnsError {
+    /// Packet ends inside a header, name or record
+    Truncated,
+
+    /// Malformed or looping name
+    BadName,
+
+    /// Response or an opcode other than a standard query
+    NotAQuery,
+
+    /// Answer does not fit the output buffer
+    BufferTooSmall,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: u16,
+    pub answers: u16,
+    pub authorities: u16,
+    pub additionals: u16,
+}
+
+impl Header {
+    pub fn parse(packet: &[u8]) -> Result<Self, DnsError> {
+        if packet.len() < HEADER_LEN {
+            return Err(DnsError::Truncated);
+        }
+        let word = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        Ok(Self {
+            id: word(0),
+            flags: word(2),
+            questions: word(4),
+            answers: word(6),
+            authorities: word(8),
+            additionals: word(10),
+        })
+    }
+
+    /// Standard query, the only kind the responders answer
+    pub fn is_query(&self) -> bool {
+        self.flags & (FLAG_QR | OPCODE_MASK) == 0
+    }
+}
+
+/// A question, with its name left in place in the packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Question {
+    /// Offset of the name in the packet
+    pub name: usize,
+    /// Offset right after the question
+    pub end: usize,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+impl Question {
+    pub fn parse(packet: &[u8], offset: usize) -> Result<Self, DnsError> {
+        let name_end = skip_name(packet, offset)?;
+        let fixed = packet
+            .get(name_end..name_end + 4)
+            .ok_or(DnsError::Truncated)?;
+        Ok(Self {
+            name: offset,
+            end: name_end + 4,
+            qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        })
+    }
+}
+
+/// Iterates over the questions of `packet`
+pub fn questions(packet: &[u8]) -> impl Iterator<Item = Result<Question, DnsError>> + '_ {
+    let count = Header::parse(packet).map_or(0, |header| header.questions);
+    let mut offset = HEADER_LEN;
+    let mut failed = false;
+    (0..count).map_while(move |_| {
+        if failed {
+            return None;
+        }
+        let question = Question::parse(packet, offset);
+        match &question {
+            Ok(q) => offset = q.end,
+            Err(_) => failed = true,
+        }
+        Some(question)
+    })
+}
+
+/// Offset right after the name at `offset`, which may end in a compression pointer
+pub fn skip_name(packet: &[u8], mut offset: usize) -> Result<usize, DnsError> {
+    loop {
+        let len = *packet.get(offset).ok_or(DnsError::Truncated)? as usize;
+        match len {
+            0 => return Ok(offset + 1),
+            l if l & 0xC0 == 0xC0 => {
+                packet.get(offset + 1).ok_or(DnsError::Truncated)?;
+                return Ok(offset + 2);
+            }
+            l if l & 0xC0 != 0 => return Err(DnsError::BadName),
+            l => offset += 1 + l,
+        }
+    }
+}
+
+/// Calls `f` with every label of the name at `offset`, following compression pointers
+pub fn for_each_label(
+    packet: &[u8],
+    mut offset: usize,
+    mut f: impl FnMut(&[u8]),
+) -> Result<(), DnsError> {
+    let mut pointers = 0;
+    let mut total = 0;
+    loop {
+        let len = *packet.get(offset).ok_or(DnsError::Truncated)? as usize;
+        match len {
+            0 => return Ok(()),
+            l if l & 0xC0 == 0xC0 => {
+                let low = *packet.get(offset + 1).ok_or(DnsError::Truncated)? as usize;
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(DnsError::BadName);
+                }
+                offset = (l & 0x3F) << 8 | low;
+            }
+            l if l & 0xC0 != 0 => return Err(DnsError::BadName),
+            l => {
+                let label = packet
+                    .get(offset + 1..offset + 1 + l)
+                    .ok_or(DnsError::Truncated)?;
+                total += 1 + l;
+                if total > MAX_NAME_LEN {
+                    return Err(DnsError::BadName);
+                }
+                f(label);
+                offset += 1 + l;
+            }
+        }
+    }
+}
+
+/// Whether the name at `offset` is `labels`, compared ASCII case-insensitively
+pub fn name_matches(packet: &[u8], offset: usize, labels: &[&str]) -> bool {
+    let mut expected = labels.iter();
+    let mut equal = true;
+    let parsed = for_each_label(packet, offset, |label| {
+        equal &= expected
+            .next()
+            .is_some_and(|want| want.as_bytes().eq_ignore_ascii_case(label));
+    });
+    parsed.is_ok() && equal && expected.next().is_none()
+}
+
+/// Length of `labels` written as an uncompressed name
+pub fn name_len(labels: &[&str]) -> usize {
+    labels.iter().map(|label| 1 + label.len()).sum::<usize>() + 1
+}
+
+/// Builds a DNS message in a caller provided buffer
+pub struct MessageWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> MessageWriter<'a> {
+    /// Starts a message with `header`. The counts are the caller's to keep right.
+    pub fn new(buf: &'a mut [u8], header: &Header) -> Self {
+        let mut writer = Self {
+            buf,
+            len: 0,
+            overflow: false,
+        };
+        for word in [
+            header.id,
+            header.flags,
+            header.questions,
+            header.answers,
+            header.authorities,
+            header.additionals,
+        ] {
+            writer.u16(word);
+        }
+        writer
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        match self.buf.get_mut(self.len..self.len + data.len()) {
+            Some(out) => {
+                out.copy_from_slice(data);
+                self.len += data.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    /// Writes `labels` as an uncompressed name
+    pub fn name(&mut self, labels: &[&str]) {
+        for label in labels {
+            let Ok(len) = u8::try_from(label.len()) else {
+                self.overflow = true;
+                return;
+            };
+            self.bytes(&[len]);
+            self.bytes(label.as_bytes());
+        }
+        self.bytes(&[0]);
+    }
+
+    /// Writes a compression pointer to a name earlier in the message
+    pub fn name_pointer(&mut self, offset: usize) {
+        self.u16(0xC000 | offset as u16);
+    }
+
+    /// Writes type, class, TTL and data of a record whose name was just written
+    pub fn record(&mut self, rtype: u16, class: u16, ttl: u32, data: &[u8]) {
+        self.record_header(rtype, class, ttl, data.len());
+        self.bytes(data);
+    }
+
+    /// Like [`Self::record`], for data the caller writes next
+    pub fn record_header(&mut self, rtype: u16, class: u16, ttl: u32, data_len: usize) {
+        self.u16(rtype);
+        self.u16(class);
+        self.u32(ttl);
+        self.u16(data_len as u16);
+    }
+
+    /// Length of the message, or an error if it did not fit
+    pub fn finish(self) -> Result<usize, DnsError> {
+        if self.overflow {
+            Err(DnsError::BufferTooSmall)
+        } else {
+            Ok(self.len)
+        }
+    }
+}
+
+/// Answers the first question of `query` with `address`, whatever the name.
+/// Questions other than A get an empty answer so clients fall back to IPv4.
+pub fn captive_portal_answer(
+    query: &[u8],
+    address: Ipv4Addr,
+    out: &mut [u8],
+) -> Result<usize, DnsError> {
+    let header = Header::parse(query)?;
+    if !header.is_query() || header.questions == 0 {
+        return Err(DnsError::NotAQuery);
+    }
+    let question = Question::parse(query, HEADER_LEN)?;
+    // The top bit of the class is the mDNS unicast-response bit
+    let answer =
+        question.qclass & 0x7FFF == CLASS_IN && matches!(question.qtype, TYPE_A | TYPE_ANY);
+
+    let mut writer = MessageWriter::new(
+        out,
+        &Header {
+            id: header.id,
+            flags: FLAG_QR | FLAG_AA | FLAG_RA | (header.flags & FLAG_RD),
+            questions: 1,
+            answers: answer as u16,
+            authorities: 0,
+            additionals: 0,
+        },
+    );
+    writer.bytes(&query[HEADER_LEN..question.end]);
+    if answer {
+        writer.name_pointer(HEADER_LEN);
+        writer.record(TYPE_A, CLASS_IN, CAPTIVE_TTL_S, &address.octets());
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `dig example.com` as it went over the wire
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, //
+        0x00, 0x01, 0x00, 0x01,
+    ];
+
+    fn query(names: &[&[&str]], qtype: u16) -> Vec<u8> {
+        let mut buf = [0; 512];
+        let header = Header {
+            id: 7,
+            flags: 0,
+            questions: names.len() as u16,
+            answers: 0,
+            authorities: 0,
+            additionals: 0,
+        };
+        let mut writer = MessageWriter::new(&mut buf, &header);
+        for name in names {
+            writer.name(name);
+            writer.u16(qtype);
+            writer.u16(CLASS_IN);
+        }
+        let len = writer.finish().unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn labels(packet: &[u8], offset: usize) -> Result<Vec<String>, DnsError> {
+        let mut labels = Vec::new();
+        for_each_label(packet, offset, |label| {
+            labels.push(String::from_utf8_lossy(label).into_owned())
+        })?;
+        Ok(labels)
+    }
+
+    #[test]
+    fn parses_header() {
+        let header = Header::parse(&QUERY).unwrap();
+        assert_eq!(
+            header,
+            Header {
+                id: 0x1234,
+                flags: 0x0120,
+                questions: 1,
+                answers: 0,
+                authorities: 0,
+                additionals: 0,
+            }
+        );
+        assert!(header.is_query());
+        assert_eq!(Header::parse(&QUERY[..11]), Err(DnsError::Truncated));
+    }
+
+    #[test]
+    fn responses_and_other_opcodes_are_no_queries() {
+        let header = |flags| Header {
+            flags,
+            ..Header::parse(&QUERY).unwrap()
+        };
+        assert!(!header(FLAG_QR).is_query());
+        // Inverse query and status
+        assert!(!header(1 << 11).is_query());
+        assert!(!header(2 << 11).is_query());
+        assert!(header(FLAG_RD).is_query());
+    }
+
+    #[test]
+    fn parses_question() {
+        let question = Question::parse(&QUERY, HEADER_LEN).unwrap();
+        assert_eq!(
+            question,
+            Question {
+                name: HEADER_LEN,
+                end: QUERY.len(),
+                qtype: TYPE_A,
+                qclass: CLASS_IN,
+            }
+        );
+        assert!(name_matches(&QUERY, question.name, &["example", "com"]));
+        assert!(name_matches(&QUERY, question.name, &["EXAMPLE", "Com"]));
+        assert!(!name_matches(&QUERY, question.name, &["example"]));
+        assert!(!name_matches(
+            &QUERY,
+            question.name,
+            &["example", "com", "x"]
+        ));
+        assert!(!name_matches(&QUERY, question.name, &["example", "org"]));
+        assert_eq!(name_len(&["example", "com"]), 13);
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        // Second question is `www` followed by a pointer to the first name
+        let mut packet = query(&[&["example", "com"]], TYPE_A);
+        packet[5] = 2;
+        packet.extend_from_slice(&[3, b'w', b'w', b'w', 0xC0, HEADER_LEN as u8]);
+        packet.extend_from_slice(&[0, TYPE_AAAA as u8, 0, 1]);
+
+        let parsed: Vec<_> = questions(&packet).collect::<Result<_, _>>().unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].qtype, TYPE_AAAA);
+        assert_eq!(parsed[1].end, packet.len());
+        assert_eq!(skip_name(&packet, parsed[1].name), Ok(packet.len() - 4));
+        assert_eq!(
+            labels(&packet, parsed[1].name).unwrap(),
+            ["www", "example", "com"]
+        );
+        assert!(name_matches(
+            &packet,
+            parsed[1].name,
+            &["www", "example", "com"]
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        for len in HEADER_LEN..QUERY.len() {
+            assert_eq!(
+                Question::parse(&QUERY[..len], HEADER_LEN),
+                Err(DnsError::Truncated),
+                "cut at {len}"
+            );
+        }
+        // A pointer cut in half
+        let packet = [&QUERY[..HEADER_LEN], &[0xC0]].concat();
+        assert_eq!(skip_name(&packet, HEADER_LEN), Err(DnsError::Truncated));
+        assert_eq!(labels(&packet, HEADER_LEN), Err(DnsError::Truncated));
+        // A pointer past the end
+        let packet = [&QUERY[..HEADER_LEN], &[0xC0, 0xFF]].concat();
+        assert_eq!(labels(&packet, HEADER_LEN), Err(DnsError::Truncated));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        // Label types 0x40 and 0x80 are reserved
+        for prefix in [0x40, 0x80] {
+            let packet = [&QUERY[..HEADER_LEN], &[prefix, b'a', 0, 0, 1, 0, 1]].concat();
+            assert_eq!(Question::parse(&packet, HEADER_LEN), Err(DnsError::BadName));
+            assert_eq!(labels(&packet, HEADER_LEN), Err(DnsError::BadName));
+        }
+
+        // A pointer to itself
+        let packet = [&QUERY[..HEADER_LEN], &[0xC0, HEADER_LEN as u8]].concat();
+        assert_eq!(labels(&packet, HEADER_LEN), Err(DnsError::BadName));
+        assert!(!name_matches(&packet, HEADER_LEN, &[]));
+
+        // Longer than 255 bytes
+        let mut packet = QUERY[..HEADER_LEN].to_vec();
+        for _ in 0..5 {
+            packet.push(63);
+            packet.extend_from_slice(&[b'a'; 63]);
+        }
+        packet.push(0);
+        assert_eq!(labels(&packet, HEADER_LEN), Err(DnsError::BadName));
+    }
+
+    #[test]
+    fn questions_stop_at_the_first_error() {
+        let mut packet = QUERY.to_vec();
+        packet[5] = 3;
+        let parsed: Vec<_> = questions(&packet).collect();
+        assert_eq!(parsed.len(), 2);
+        assert!(parsed[0].is_ok());
+        assert_eq!(parsed[1], Err(DnsError::Truncated));
+        assert_eq!(questions(&QUERY[..4]).count(), 0);
+    }
+
+    #[test]
+    fn captive_portal_answers_a() {
+        let mut out = [0; 512];
+        let len = captive_portal_answer(&QUERY, Ipv4Addr::new(192, 168, 4, 1), &mut out).unwrap();
+        let answer = &out[..len];
+        let header = Header::parse(answer).unwrap();
+        assert_eq!(header.id, 0x1234);
+        assert_eq!(header.flags, FLAG_QR | FLAG_AA | FLAG_RA | FLAG_RD);
+        assert_eq!((header.questions, header.answers), (1, 1));
+        assert_eq!(answer[HEADER_LEN..QUERY.len()], QUERY[HEADER_LEN..]);
+        assert_eq!(
+            answer[QUERY.len()..],
+            [0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 4, 1]
+        );
+    }
+
+    #[test]
+    fn captive_portal_leaves_aaaa_unanswered() {
+        let mut out = [0; 512];
+        let query = query(&[&["example", "com"]], TYPE_AAAA);
+        let len = captive_portal_answer(&query, Ipv4Addr::new(192, 168, 4, 1), &mut out).unwrap();
+        let header = Header::parse(&out[..len]).unwrap();
+        assert_eq!((header.questions, header.answers), (1, 0));
+        assert_eq!(len, query.len());
+    }
+
+    #[test]
+    fn captive_portal_refuses_non_queries() {
+        let address = Ipv4Addr::new(192, 168, 4, 1);
+        let mut out = [0; 512];
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        assert_eq!(
+            captive_portal_answer(&response, address, &mut out),
+            Err(DnsError::NotAQuery)
+        );
+        let empty = query(&[], TYPE_A);
+        assert_eq!(
+            captive_portal_answer(&empty, address, &mut out),
+            Err(DnsError::NotAQuery)
+        );
+        assert_eq!(
+            captive_portal_answer(&QUERY, address, &mut out[..QUERY.len()]),
+            Err(DnsError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn writer_reports_overflow() {
+        let header = Header::parse(&QUERY).unwrap();
+        let mut buf = [0; HEADER_LEN + 4];
+        let mut writer = MessageWriter::new(&mut buf, &header);
+        writer.name(&["abc"]);
+        assert_eq!(writer.finish(), Err(DnsError::BufferTooSmall));
+
+        let long = "a".repeat(256);
+        let mut buf = [0; 512];
+        let mut writer = MessageWriter::new(&mut buf, &header);
+        writer.name(&[&long]);
+        assert_eq!(writer.finish(), Err(DnsError::BufferTooSmall));
+    }
+}
@@ -0,0 +1,275 @@
+//! Streaming heatshrink (LZSS) decoder for compressed OTA images, as
+//! produced by `heatshrink -e -w 10 -l 4`.
+//!
+//! Needs a fixed 1 KiB window plus a small output buffer, independent of the
+//! image size.
+
+/// log2 of the window size, `-w` of the encoder
+pub const WINDOW_BITS: u32 = 10;
+/// log2 of the longest back-reference, `-l` of the encoder
+pub const LOOKAHEAD_BITS: u32 = 4;
+
+const WINDOW_SIZE: usize = 1 << WINDOW_BITS;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const OUT_BUF_SIZE: usize = 256;
+const LITERAL_BITS: u32 = 1 + 8;
+const BACKREF_BITS: u32 = 1 + WINDOW_BITS + LOOKAHEAD_BITS;
+
+#[derive(Debug, PartialEq)]
+pub enum HeatshrinkError {
+    /// The stream ends inside a token, or with more than zero padding
+    Truncated,
+}
+
+pub struct HeatshrinkDecoder {
+    window: [u8; WINDOW_SIZE],
+    head: usize,
+    bits: u32,
+    bit_count: u32,
+    out: [u8; OUT_BUF_SIZE],
+    out_len: usize,
+}
+
+impl Default for HeatshrinkDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeatshrinkDecoder {
+    pub const fn new() -> Self {
+        Self {
+            window: [0; WINDOW_SIZE],
+            head: 0,
+            bits: 0,
+            bit_count: 0,
+            out: [0; OUT_BUF_SIZE],
+            out_len: 0,
+        }
+    }
+
+    /// Decodes `input` and hands the output to `sink` in chunks of at most
+    /// 256 bytes. A token split across calls is completed by the next call;
+    /// the zero padding after the last token is left for [`Self::finish`].
+    pub fn decode<E, F>(&mut self, input: &[u8], sink: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        for &byte in input {
+            self.bits = (self.bits << 8) | byte as u32;
+            self.bit_count += 8;
+            self.decode_tokens(sink)?;
+        }
+        self.flush(sink)
+    }
+
+    /// Checks that the stream ended on a token, with less than a byte of zero
+    /// padding left. Back-references can't be checked: the encoder matches
+    /// against the zeroed window before the start too.
+    pub fn finish(&self) -> Result<(), HeatshrinkError> {
+        let padding = self.bits & ((1 << self.bit_count) - 1);
+        if self.bit_count >= 8 || padding != 0 {
+            return Err(HeatshrinkError::Truncated);
+        }
+        Ok(())
+    }
+
+    fn take_bits(&mut self, count: u32) -> u32 {
+        self.bit_count -= count;
+        (self.bits >> self.bit_count) & ((1 << count) - 1)
+    }
+
+    fn decode_tokens<E, F>(&mut self, sink: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        while self.bit_count > 0 {
+            let is_literal = (self.bits >> (self.bit_count - 1)) & 1 == 1;
+            if is_literal {
+                if self.bit_count < LITERAL_BITS {
+                    break;
+                }
+                self.take_bits(1);
+                let byte = self.take_bits(8) as u8;
+                self.push(byte, sink)?;
+            } else {
+                if self.bit_count < BACKREF_BITS {
+                    break;
+                }
+                self.take_bits(1);
+                let offset = self.take_bits(WINDOW_BITS) as usize + 1;
+                let count = self.take_bits(LOOKAHEAD_BITS) as usize + 1;
+                for _ in 0..count {
+                    let byte = self.window[self.head.wrapping_sub(offset) & WINDOW_MASK];
+                    self.push(byte, sink)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn push<E, F>(&mut self, byte: u8, sink: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        self.window[self.head & WINDOW_MASK] = byte;
+        self.head = self.head.wrapping_add(1);
+        self.out[self.out_len] = byte;
+        self.out_len += 1;
+        if self.out_len == OUT_BUF_SIZE {
+            self.flush(sink)?;
+        }
+        Ok(())
+    }
+
+    fn flush<E, F>(&mut self, sink: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        if self.out_len > 0 {
+            sink(&self.out[..self.out_len])?;
+            self.out_len = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `heatshrink -e -w 10 -l 4 image.bin image.bin.hs`, see `host-tests/fixtures/generate.sh`
+    const IMAGE: &[u8] = include_bytes!("../host-tests/fixtures/image.bin");
+    const COMPRESSED: &[u8] = include_bytes!("../host-tests/fixtures/image.bin.hs");
+
+    /// Decodes `chunks` one after the other, as the OTA writer gets them
+    fn decode_chunks<'a>(
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<u8>, HeatshrinkError> {
+        let mut decoder = HeatshrinkDecoder::new();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            decoder.decode(chunk, &mut |bytes: &[u8]| {
+                assert!(bytes.len() <= OUT_BUF_SIZE);
+                out.extend_from_slice(bytes);
+                Ok::<_, HeatshrinkError>(())
+            })?;
+        }
+        decoder.finish()?;
+        Ok(out)
+    }
+
+    fn decode(input: &[u8]) -> Result<Vec<u8>, HeatshrinkError> {
+        decode_chunks([input])
+    }
+
+    #[test]
+    fn decodes_fixture() {
+        assert_eq!(COMPRESSED[0], 0x80 | (IMAGE[0] >> 1));
+        assert_eq!(decode(COMPRESSED).unwrap(), IMAGE);
+    }
+
+    #[test]
+    fn decodes_split_at_every_byte() {
+        for split in 0..=COMPRESSED.len() {
+            let (head, tail) = COMPRESSED.split_at(split);
+            assert_eq!(
+                decode_chunks([head, tail]).unwrap(),
+                IMAGE,
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        assert_eq!(decode_chunks(COMPRESSED.chunks(1)).unwrap(), IMAGE);
+        assert_eq!(decode_chunks(COMPRESSED.chunks(7)).unwrap(), IMAGE);
+    }
+
+    #[test]
+    fn decodes_empty_stream() {
+        assert_eq!(decode(&[]).unwrap(), []);
+    }
+
+    #[test]
+    fn back_reference_before_start_reads_zeros() {
+        // Back-reference 3 bytes back, 4 long, into the zeroed window
+        let stream = [0b0000_0000, 0b0100_0110];
+        assert_eq!(decode(&stream).unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn literal_then_repeat() {
+        // Literal 'a', then 5 bytes from 1 back
+        let bits = (1u32 << 23) | (u32::from(b'a') << 15) | 4;
+        let stream = &bits.to_be_bytes()[1..];
+        assert_eq!(decode(stream).unwrap(), b"aaaaaa");
+    }
+
+    #[test]
+    fn truncated_stream_is_detected_or_short() {
+        for len in 0..COMPRESSED.len() {
+            match decode(&COMPRESSED[..len]) {
+                Err(HeatshrinkError::Truncated) => {}
+                // Cut right after a token: the OTA writer's length check catches it
+                Ok(out) => {
+                    assert!(out.len() < IMAGE.len(), "cut at {len}");
+                    assert_eq!(out, IMAGE[..out.len()], "cut at {len}");
+                }
+            }
+        }
+        assert_eq!(
+            decode(&COMPRESSED[..COMPRESSED.len() - 1]),
+            Err(HeatshrinkError::Truncated)
+        );
+    }
+
+    #[test]
+    fn trailing_garbage_is_rejected() {
+        let mut stream = COMPRESSED.to_vec();
+        stream.push(0x01);
+        assert_eq!(decode(&stream), Err(HeatshrinkError::Truncated));
+        assert_eq!(decode(&[0x80]), Err(HeatshrinkError::Truncated));
+    }
+
+    #[test]
+    fn corrupt_stream_does_not_panic() {
+        // Heatshrink has no checksum, a flipped bit mostly yields other bytes
+        // (or the same ones from another back-reference). The OTA digest check
+        // rejects those; here the decoder just has to get through them.
+        let mut differ = 0;
+        for bit in (0..COMPRESSED.len() * 8).step_by(3) {
+            let mut stream = COMPRESSED.to_vec();
+            stream[bit / 8] ^= 0x80 >> (bit % 8);
+            if decode(&stream).is_ok_and(|out| out != IMAGE) {
+                differ += 1;
+            }
+        }
+        assert!(differ > COMPRESSED.len() * 8 / 3 * 9 / 10, "{differ}");
+
+        // The literal carrying the image magic
+        let mut stream = COMPRESSED.to_vec();
+        stream[0] ^= 0x01;
+        assert_ne!(decode(&stream).unwrap()[0], IMAGE[0]);
+    }
+
+    #[test]
+    fn garbage_does_not_panic() {
+        let mut state = 0x2545_f491_u32;
+        let garbage: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        for chunk in garbage.chunks(97) {
+            let _ = decode(chunk);
+        }
+        let _ = decode(&garbage);
+        let _ = decode(&[0xff; 64]);
+        let _ = decode(&[0x00; 64]);
+    }
+}
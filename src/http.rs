@@ -1,15 +1,33 @@
 use alloc::boxed::Box;
+use core::fmt::Write;
 use embassy_net::dns::Error as DnsError;
 use embassy_net::tcp::ConnectError as TcpConnectError;
 use embassy_net::tcp::Error as TcpError;
 use embassy_net::{Stack, dns::DnsSocket, tcp::client::TcpClient};
 use embassy_time::{Duration, TimeoutError, with_timeout};
-use heapless::Vec;
+use embedded_io_async::Read;
+use heapless::{String, Vec};
 use log::{info, warn};
 use reqwless::Error as ReqlessError;
 use reqwless::client::HttpClient;
+use reqwless::request::RequestBuilder;
+use reqwless::response::Status;
 
 const RESPONSE_SIZE: usize = 1024;
+const CHUNK_SIZE: usize = 1024;
+
+/// Consumer of a streamed HTTP response body
+pub trait BodySink {
+    type Error: From<Error>;
+
+    /// Called once the response headers are received. `offset` is where the
+    /// body starts within the resource: the requested one if the server
+    /// honoured the range, 0 if it sent the whole resource.
+    fn begin(&mut self, content_length: Option<usize>, offset: usize) -> Result<(), Self::Error>;
+
+    /// Called for every chunk of the body, in order
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), Self::Error>;
+}
 
 pub struct EmbassyHttpClient<
     'a,
@@ -63,6 +81,77 @@ impl<'a, 'b, const N: usize, const TX_SZ: usize, const RX_SZ: usize>
 
         Ok(output)
     }
+
+    /// Send a GET request and stream the response body into `sink` chunk by chunk,
+    /// asking for the bytes from `offset` on with a `Range` header when it is not 0.
+    /// Returns the number of body bytes received.
+    pub async fn get_to_sink<S: BodySink>(
+        &mut self,
+        url: &str,
+        timeout: u64,
+        offset: usize,
+        sink: &mut S,
+    ) -> Result<usize, S::Error> {
+        let mut header_buffer = [0; RESPONSE_SIZE];
+        let mut chunk = [0; CHUNK_SIZE];
+        let mut range = String::<32>::new();
+        // "bytes=" and any usize fit, this cannot fail
+        let _ = write!(range, "bytes={offset}-");
+        let range_header = [("Range", range.as_str())];
+
+        let request_future = self
+            .http_client
+            .request(reqwless::request::Method::GET, url);
+        let request = match with_timeout(Duration::from_secs(timeout), request_future).await {
+            Ok(Ok(req)) => req,
+            Ok(Err(e)) => {
+                info!("Error creating request: {:?}", e);
+                return Err(Error::from(e).into());
+            }
+            Err(_) => {
+                warn!("Timeout out creating HTTP request!");
+                return Err(Error::from(TimeoutError).into());
+            }
+        };
+        let mut request = if offset > 0 {
+            request.headers(&range_header)
+        } else {
+            request
+        };
+
+        let response = request
+            .send(&mut header_buffer)
+            .await
+            .map_err(Error::from)?;
+        info!("HTTP status: {:?}", response.status);
+        if !response.status.is_successful() {
+            return Err(Error::Status(response.status).into());
+        }
+
+        let body_offset = if response.status == Status::PartialContent {
+            offset
+        } else {
+            0
+        };
+        sink.begin(response.content_length, body_offset)?;
+
+        let mut reader = response.body().reader();
+        let mut total = 0;
+        loop {
+            let n = with_timeout(Duration::from_secs(timeout), reader.read(&mut chunk))
+                .await
+                .map_err(Error::from)?
+                .map_err(Error::from)?;
+            if n == 0 {
+                break;
+            }
+            sink.write(&chunk[..n]).await?;
+            total += n;
+        }
+        info!("Streamed {} bytes", total);
+
+        Ok(total)
+    }
 }
 
 /// An error within an HTTP request
@@ -83,6 +172,9 @@ pub enum Error {
     /// Error in HTTP client
     Reqless(ReqlessError),
 
+    /// Server answered with a non-success status
+    Status(Status),
+
     Timeout(TimeoutError),
 }
 
@@ -1,11 +1,24 @@
-let input = document.getElementsByTagName("input")[0];
+// Token from POST /api/login, sent with every request once the device has an admin password
+let authToken = sessionStorage.getItem("authToken");
+
+function authFetch(url, options = {}) {
+    if (authToken) {
+        options.headers = {...options.headers, 'Authorization': 'Bearer ' + authToken};
+    }
+    return fetch(url, options);
+}
+
+let input = document.getElementById("echoInput");
 let output = document.getElementById("output");
-let button = document.getElementsByTagName("button")[0];
+let button = document.getElementById("echoBtn");
 
 let ssid = document.getElementById('ssidInput')
 let psw = document.getElementById('password')
 let hostname = document.getElementById('hostName')
+let adminPassword = document.getElementById('adminPassword')
 let uploadButton = document.getElementById("uploadDataBtn")
+let settingsStatus = document.getElementById("settingsStatus")
+let staticIpInputs = ["staticIp", "netmask", "gateway", "dns"].map(id => document.getElementById(id))
 
 // Regex for a “valid” SSID - 1–32 printable characters:
 // We allow letters, numbers, spaces, dashes, underscores, and dots.
@@ -14,13 +27,21 @@ const ssidPattern = /^[\w .\-]{1,32}$/;
 // WPA2-PSK regex: exactly 8–63 printable ASCII chars.
 const pswPattern = /^[\x20-\x7E]{8,63}$/;
 
+// Admin password: 8–64 characters, or nothing to keep the current one.
+const adminPasswordPattern = /^.{8,64}$|^$/;
+
 // Hostname regex (1–15 alphanumeric or dash).
 const hostnamePattern = /^[a-zA-Z0-9\-]{1,15}$/;
 
+// Dotted IPv4 address, or nothing for DHCP. The device checks the rest.
+const ipv4Pattern = /^((25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(25[0-5]|2[0-4]\d|1?\d?\d)$|^$/;
+
 
 ssid.addEventListener("input", validateInputs);
 psw.addEventListener("input", validateInputs);
 hostname.addEventListener("input", validateInputs);
+adminPassword.addEventListener("input", validateInputs);
+staticIpInputs.forEach(input => input.addEventListener("input", validateInputs));
 validateInputs();
 
 function checkPattern(inputElement, pattern) {
@@ -32,8 +53,11 @@ function validateInputs() {
     const ssidValid = checkPattern(ssid, ssidPattern);
     const pswValid = checkPattern(psw, pswPattern);
     const hostnameValid = checkPattern(hostname, hostnamePattern);
+    const adminPasswordValid = adminPasswordPattern.test(adminPassword.value);
 
-    const valid = (ssidValid && pswValid && hostnameValid);
+    const staticIpValid = staticIpInputs.every(input => checkPattern(input, ipv4Pattern));
+
+    const valid = (ssidValid && pswValid && hostnameValid && staticIpValid && adminPasswordValid);
     uploadButton.disabled = !valid
     console.log("button status disabled=", uploadButton.disabled)
     return valid
@@ -44,6 +68,11 @@ uploadButton.addEventListener("click", function () {
         "ssid": document.getElementById('ssidInput').value,
         "psw": document.getElementById('password').value,
         "hostname": document.getElementById('hostName').value,
+        "ip": staticIpInputs[0].value.trim(),
+        "netmask": staticIpInputs[1].value.trim(),
+        "gateway": staticIpInputs[2].value.trim(),
+        "dns": staticIpInputs[3].value.trim(),
+        "admin_password": adminPassword.value,
     };
     if (!validateInputs()) {
         console.log("Settings invalid", data)
@@ -52,7 +81,7 @@ uploadButton.addEventListener("click", function () {
     console.log("upload data", data)
 
     const url = window.location.href + "settings";
-    fetch(url, {
+    authFetch(url, {
         method: 'POST',
         headers: {
             'Content-Type': 'application/json',
@@ -61,16 +90,333 @@ uploadButton.addEventListener("click", function () {
     })
         .then(response => {
             console.log("Response:", response)
+            return response.ok ? response.text() : response.text().then(text => Promise.reject(text));
         })
         .then(data => {
             console.log('Upload success:', data);
+            settingsStatus.innerText = "Saved, connecting...";
+            loadSavedNetworks();
         })
         .catch((error) => {
             console.error('Error:', error);
+            settingsStatus.innerText = error;
         });
 });
 
 
+let savedNetworks = document.getElementById("savedNetworks")
+
+function showSavedNetworks(networks) {
+    savedNetworks.replaceChildren();
+    networks.forEach((network, index) => {
+        const item = document.createElement("li");
+        item.append(network.ssid + " ");
+        const up = document.createElement("button");
+        up.type = "button";
+        up.innerText = "Up";
+        up.disabled = index === 0;
+        up.addEventListener("click", () => moveSavedNetwork(networks, index, -1));
+        const down = document.createElement("button");
+        down.type = "button";
+        down.innerText = "Down";
+        down.disabled = index === networks.length - 1;
+        down.addEventListener("click", () => moveSavedNetwork(networks, index, 1));
+        const connect = document.createElement("button");
+        connect.type = "button";
+        connect.innerText = "Connect";
+        connect.addEventListener("click", () => sendWifiCommand("api/wifi/connect", {"ssid": network.ssid}));
+        const remove = document.createElement("button");
+        remove.type = "button";
+        remove.innerText = "Remove";
+        remove.addEventListener("click", () => updateSavedNetworks("api/wifi/networks/remove", {"ssid": network.ssid}));
+        item.append(connect, up, down, remove);
+        savedNetworks.appendChild(item);
+    });
+}
+
+function moveSavedNetwork(networks, index, step) {
+    const ssids = networks.map(network => network.ssid);
+    const [moved] = ssids.splice(index, 1);
+    ssids.splice(index + step, 0, moved);
+    updateSavedNetworks("api/wifi/networks/order", {"ssids": ssids});
+}
+
+function updateSavedNetworks(url, data) {
+    authFetch(url, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify(data),
+    })
+        .then(response => response.ok ? response.json() : Promise.reject(response.statusText))
+        .then(showSavedNetworks)
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+}
+
+// The outcome comes in over SSE
+function sendWifiCommand(url, data) {
+    authFetch(url, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify(data),
+    })
+        .then(response => response.text())
+        .then(text => {
+            settingsStatus.innerText = text;
+        })
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+}
+
+document.getElementById("staModeBtn").addEventListener("click", () => sendWifiCommand("api/wifi/mode", {"mode": "sta"}));
+document.getElementById("apModeBtn").addEventListener("click", () => sendWifiCommand("api/wifi/mode", {"mode": "ap"}));
+document.getElementById("disconnectBtn").addEventListener("click", () => sendWifiCommand("api/wifi/disconnect", {}));
+
+function loadSavedNetworks() {
+    authFetch("api/wifi/networks")
+        .then(response => response.json())
+        .then(showSavedNetworks)
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+}
+
+loadSavedNetworks();
+
+
+let apInputs = {
+    "ssid": document.getElementById("apSsid"),
+    "password": document.getElementById("apPassword"),
+    "channel": document.getElementById("apChannel"),
+    "address": document.getElementById("apAddress"),
+    "netmask": document.getElementById("apNetmask"),
+    "dhcp_pool": document.getElementById("apDhcpPool"),
+    "persist_leases": document.getElementById("apPersistLeases"),
+    "open": document.getElementById("apOpen"),
+}
+let apSaveButton = document.getElementById("apSaveBtn")
+let apStatus = document.getElementById("apStatus")
+
+authFetch("api/wifi/ap")
+    .then(response => response.json())
+    .then(settings => {
+        for (const [key, value] of Object.entries(settings)) {
+            if (apInputs[key].type === "checkbox") {
+                apInputs[key].checked = value;
+            } else {
+                apInputs[key].value = value;
+            }
+        }
+    })
+    .catch((error) => {
+        console.error('Error:', error);
+    });
+
+apSaveButton.addEventListener("click", function () {
+    const data = {
+        "ssid": apInputs.ssid.value.trim(),
+        "password": apInputs.password.value,
+        "channel": Number(apInputs.channel.value),
+        "address": apInputs.address.value.trim(),
+        "netmask": apInputs.netmask.value.trim(),
+        "dhcp_pool": Number(apInputs.dhcp_pool.value),
+        "persist_leases": apInputs.persist_leases.checked,
+        "open": apInputs.open.checked,
+    };
+    authFetch("api/wifi/ap", {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify(data),
+    })
+        .then(response => response.text())
+        .then(text => {
+            apStatus.innerText = text;
+        })
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+});
+
+
+let dhcpLeases = document.getElementById("dhcpLeases")
+let dhcpReservations = document.getElementById("dhcpReservations")
+let reservationStatus = document.getElementById("reservationStatus")
+
+function loadDhcpLeases() {
+    authFetch("api/dhcp/leases")
+        .then(response => response.json())
+        .then(leases => {
+            dhcpLeases.replaceChildren();
+            leases.forEach(lease => {
+                const item = document.createElement("li");
+                const minutes = Math.round(lease.expires_in_s / 60);
+                item.innerText = lease.ip + " " + lease.mac + (lease.reserved ? " (reserved)" : "")
+                    + ", " + minutes + " min left";
+                dhcpLeases.appendChild(item);
+            });
+        })
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+}
+
+function showDhcpReservations(reservations) {
+    dhcpReservations.replaceChildren();
+    reservations.forEach(reservation => {
+        const item = document.createElement("li");
+        item.innerText = reservation.mac + " \u2192 " + reservation.ip + " ";
+        const remove = document.createElement("button");
+        remove.type = "button";
+        remove.innerText = "Remove";
+        remove.addEventListener("click", () => updateDhcpReservations("api/dhcp/reservations/remove", {"mac": reservation.mac}));
+        item.append(remove);
+        dhcpReservations.appendChild(item);
+    });
+}
+
+function updateDhcpReservations(url, data) {
+    authFetch(url, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify(data),
+    })
+        .then(response => response.ok ? response.json() : response.text().then(text => Promise.reject(text)))
+        .then(reservations => {
+            reservationStatus.innerText = "";
+            showDhcpReservations(reservations);
+            loadDhcpLeases();
+        })
+        .catch((error) => {
+            reservationStatus.innerText = error;
+        });
+}
+
+document.getElementById("dhcpRefreshBtn").addEventListener("click", loadDhcpLeases);
+document.getElementById("reservationAddBtn").addEventListener("click", function () {
+    const mac = document.getElementById("reservationMac");
+    if (!mac.checkValidity()) {
+        reservationStatus.innerText = "Invalid MAC address";
+        return;
+    }
+    updateDhcpReservations("api/dhcp/reservations", {
+        "mac": mac.value.trim(),
+        "ip": document.getElementById("reservationIp").value.trim(),
+    });
+});
+
+authFetch("api/dhcp/reservations")
+    .then(response => response.json())
+    .then(showDhcpReservations)
+    .catch((error) => {
+        console.error('Error:', error);
+    });
+loadDhcpLeases();
+
+
+let timeNow = document.getElementById("timeNow")
+let timeZoneInput = document.getElementById("timeZone")
+let ntpServersInput = document.getElementById("ntpServers")
+let timeStatus = document.getElementById("timeStatus")
+
+function loadTime() {
+    authFetch("api/time")
+        .then(response => response.json())
+        .then(time => {
+            timeNow.innerText = time.synced ? time.local + " " + time.zone : "Not synced yet";
+            if (document.activeElement !== timeZoneInput) {
+                timeZoneInput.value = time.timezone;
+            }
+        })
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+}
+
+function saveTimeSetting(url, data) {
+    authFetch(url, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify(data),
+    })
+        .then(response => response.text())
+        .then(text => {
+            timeStatus.innerText = text;
+            loadTime();
+        })
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+}
+
+document.getElementById("timeZoneSaveBtn").addEventListener("click", () => saveTimeSetting("api/time/zone", {"timezone": timeZoneInput.value.trim()}));
+document.getElementById("ntpSaveBtn").addEventListener("click", () => saveTimeSetting("api/time/ntp", {"servers": ntpServersInput.value.trim()}));
+
+authFetch("api/time/ntp")
+    .then(response => response.json())
+    .then(settings => {
+        ntpServersInput.value = settings.servers;
+    })
+    .catch((error) => {
+        console.error('Error:', error);
+    });
+loadTime();
+setInterval(loadTime, 30000);
+
+
+let scanButton = document.getElementById("scanBtn")
+let networkList = document.getElementById("networkList")
+
+scanButton.addEventListener("click", function () {
+    scanButton.disabled = true;
+    scanButton.innerText = "Scanning...";
+    authFetch("api/wifi/scan")
+        .then(response => response.json())
+        .then(networks => {
+            networkList.replaceChildren();
+            const placeholder = document.createElement("option");
+            placeholder.text = networks.length + " networks found";
+            placeholder.value = "";
+            networkList.add(placeholder);
+            networks
+                .filter(network => network.ssid.length > 0)
+                .sort((a, b) => b.rssi - a.rssi)
+                .forEach(network => {
+                    const option = document.createElement("option");
+                    option.value = network.ssid;
+                    option.text = network.ssid + " (" + network.rssi + " dBm, ch " + network.channel
+                        + ", " + network.auth + ")";
+                    option.title = network.bssid;
+                    networkList.add(option);
+                });
+            networkList.hidden = false;
+        })
+        .catch((error) => {
+            console.error('Scan error:', error);
+        })
+        .finally(() => {
+            scanButton.disabled = false;
+            scanButton.innerText = "Scan";
+        });
+});
+
+networkList.addEventListener("change", function () {
+    if (networkList.value) {
+        ssid.value = networkList.value;
+        validateInputs();
+    }
+});
 input.addEventListener("input", function () {
     button.disabled = !input.value;
 });
@@ -113,8 +459,161 @@ events.addEventListener("error", function () {
     console.log("Events Closed");
 });
 
+let wifiStatus = document.getElementById("wifiStatus")
+
+events.addEventListener("wifi", function (ev) {
+    const status = JSON.parse(ev.data);
+    const parts = [];
+    if (status.link_up) {
+        parts.push("Connected to " + status.ssid);
+        const addresses = (status.ip ? [status.ip] : []).concat(status.ipv6 || []);
+        parts.push(addresses.length > 0 ? addresses.join(" ") : "waiting for an address");
+        if (status.rssi !== null) {
+            parts.push(status.rssi + " dBm");
+        }
+    } else {
+        parts.push("Station offline");
+    }
+    if (status.ap_up) {
+        parts.push("AP up");
+    }
+    if (status.channel !== null) {
+        parts.push("channel " + status.channel);
+    }
+    parts.push(status.reconnects + " reconnects");
+    wifiStatus.innerText = parts.join(", ");
+})
+
 events.addEventListener("message_changed", function (ev) {
     console.log("Got SSE data", ev.data);
+    if (ev.data.startsWith("Wi-Fi: ")) {
+        settingsStatus.innerText = ev.data.slice("Wi-Fi: ".length);
+    }
 })
 
 
+
+let otaFile = document.getElementById('otaFile')
+let otaReboot = document.getElementById('otaReboot')
+let otaUploadButton = document.getElementById('otaUploadBtn')
+let otaProgress = document.getElementById('otaProgress')
+let otaStatus = document.getElementById('otaStatus')
+
+otaFile.addEventListener("change", function () {
+    otaUploadButton.disabled = otaFile.files.length === 0;
+});
+
+otaUploadButton.addEventListener("click", function () {
+    const file = otaFile.files[0];
+    if (!file) {
+        return
+    }
+    otaUploadButton.disabled = true;
+    otaStatus.innerText = "Uploading " + file.name;
+
+    // Raw body, the device streams it straight into flash
+    const xhr = new XMLHttpRequest();
+    xhr.open("POST", "ota");
+    xhr.setRequestHeader("Content-Type", "application/octet-stream");
+    if (authToken) {
+        xhr.setRequestHeader("Authorization", "Bearer " + authToken);
+    }
+    xhr.upload.addEventListener("progress", function (ev) {
+        if (ev.lengthComputable) {
+            otaProgress.value = Math.round(ev.loaded * 100 / ev.total);
+        }
+    });
+    xhr.addEventListener("load", function () {
+        otaStatus.innerText = xhr.responseText;
+        otaUploadButton.disabled = false;
+        if (xhr.status === 200 && otaReboot.checked) {
+            authFetch("reboot", {method: 'POST'})
+                .then(() => {
+                    otaStatus.innerText += " Rebooting...";
+                })
+                .catch((error) => {
+                    console.error('Error:', error);
+                });
+        }
+    });
+    xhr.addEventListener("error", function () {
+        otaStatus.innerText = "Upload failed";
+        otaUploadButton.disabled = false;
+    });
+    xhr.send(file);
+});
+
+
+let loginPassword = document.getElementById("loginPassword")
+let loginStatus = document.getElementById("loginStatus")
+
+document.getElementById("loginBtn").addEventListener("click", function () {
+    fetch("api/login", {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify({"password": loginPassword.value}),
+    })
+        .then(response => response.ok ? response.json() : response.text().then(text => Promise.reject(text)))
+        .then(login => {
+            authToken = login.token;
+            sessionStorage.setItem("authToken", authToken);
+            loginPassword.value = "";
+            loginStatus.innerText = "Logged in";
+        })
+        .catch((error) => {
+            console.error('Error:', error);
+            loginStatus.innerText = error;
+        });
+});
+
+
+let deviceStatus = document.getElementById("deviceStatus")
+
+function formatUptime(seconds) {
+    const days = Math.floor(seconds / 86400);
+    const time = [Math.floor(seconds / 3600) % 24, Math.floor(seconds / 60) % 60, seconds % 60]
+        .map(part => String(part).padStart(2, "0"))
+        .join(":");
+    return days > 0 ? days + "d " + time : time;
+}
+
+function formatKiB(bytes) {
+    return (bytes / 1024).toFixed(1) + " KiB";
+}
+
+function loadStatus() {
+    authFetch("api/status")
+        .then(response => response.json())
+        .then(status => {
+            const rows = [
+                ["Firmware", status.version],
+                ["Uptime", formatUptime(status.uptime_s)],
+                ["Reset reason", status.reset_reason],
+                ["CPU", status.cpu_mhz + " MHz"],
+                ["Heap", formatKiB(status.heap_used) + " used, " + formatKiB(status.heap_free) + " free"],
+                ["Flash", formatKiB(status.flash_size)],
+                ["Wi-Fi", status.wifi_mode ?? "starting"],
+                ["IP", status.ip ?? "-"],
+                ["RSSI", status.rssi !== null ? status.rssi + " dBm" : "-"],
+                ["Time synced", status.time_synced ? "yes" : "no"],
+                ["Update in progress", status.firmware_upgrade_in_progress ? "yes" : "no"],
+            ];
+            deviceStatus.replaceChildren(...rows.map(([name, value]) => {
+                const row = document.createElement("tr");
+                const label = document.createElement("th");
+                label.innerText = name;
+                const cell = document.createElement("td");
+                cell.innerText = value;
+                row.append(label, cell);
+                return row;
+            }));
+        })
+        .catch((error) => {
+            console.error('Error:', error);
+        });
+}
+
+loadStatus();
+setInterval(loadStatus, 10000);
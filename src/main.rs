@@ -5,10 +5,11 @@
 #![feature(impl_trait_in_assoc_type)]
 extern crate alloc;
 
-use crate::ota::{run_with_ota, validate_current_ota_slot};
+use crate::ota::{begin_pending_verify, ota_health_task, ota_task, run_with_ota};
+use crate::ota_manifest::update_check_task;
 use core::ptr::addr_of_mut;
 use core::sync::atomic::{AtomicBool, Ordering};
-use embassy_executor::{Spawner, task};
+use embassy_executor::Spawner;
 use embassy_net::tcp::client::{TcpClient, TcpClientState};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
 use embassy_time::{Duration, Timer};
@@ -22,32 +23,43 @@ use esp_hal::{
 };
 use esp_hal::{rmt::Rmt, time::Rate};
 use esp_hal_embassy::Executor;
-use log::{error, info};
+use log::{error, info, warn};
 use static_cell::StaticCell;
 
+mod ap_config;
+mod auth;
+mod clock;
+mod delta;
+mod dhcp_leases;
+mod dns;
+mod heatshrink;
 mod http;
 mod main_core;
+mod mdns;
 mod neopixel;
 mod second_core;
 mod shared;
+mod sntp;
+mod status;
+mod tz;
 mod web_server;
 mod wifi;
 
 use crate::http::EmbassyHttpClient;
 use main_core::enable_disable_led;
 use second_core::control_led;
-use wifi::init_wifi;
+use wifi::{init_wifi, run_mdns, run_sntp};
 
 use crate::web_server::AppProps;
 use picoserve::{AppBuilder, AppRouter, make_static};
 use web_server::web_task;
 
 mod ota;
+mod ota_manifest;
+mod ota_signature;
 use embedded_storage::ReadStorage;
-use esp_bootloader_esp_idf::ota::Slot;
 use esp_bootloader_esp_idf::partitions;
 use esp_storage::FlashStorage;
-use ota::OtaImageState::Valid;
 
 mod config;
 mod db;
@@ -56,9 +68,13 @@ mod macros;
 
 use log_utils::log_banner;
 
-use crate::config::{get_default_credentials, get_wifi_credentials};
+use crate::config::{
+    NtpSettings, SavedNetwork, SavedNetworks, TimeZoneSettings, get_default_credentials,
+    read_admin_password, read_ap_config, read_hostname, read_networks, read_ntp_servers,
+    read_sta_failure_limit, read_static_ip, read_time_zone,
+};
 use crate::db::DbFlash;
-use crate::wifi::WifiMode;
+use crate::wifi::{DEFAULT_STA_FAILURE_LIMIT, WifiMode};
 use embassy_embedded_hal::adapter::BlockingAsync;
 use esp_hal::clock::Clock;
 use esp_hal::system::AppCoreGuard;
@@ -71,9 +87,11 @@ static CLIENT_STATE: StaticCell<TcpClientState<3, 1024, 1024>> = StaticCell::new
 static TCP_CLIENT: StaticCell<TcpClient<'static, 3>> = StaticCell::new();
 
 pub static WIFI_INITIALIZED: AtomicBool = AtomicBool::new(false);
-pub static WIFI_MODE_CLIENT: AtomicBool = AtomicBool::new(false);
 pub static TIME_SYNCED: AtomicBool = AtomicBool::new(false);
 pub static FIRMWARE_UPGRADE_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+pub static DB_MOUNTED: AtomicBool = AtomicBool::new(false);
+/// Set once the web server answered a request
+pub static WEB_REQUEST_SERVED: AtomicBool = AtomicBool::new(false);
 
 const fn or_str(opt: Option<&'static str>, default: &'static str) -> &'static str {
     if let Some(val) = opt {
@@ -87,20 +105,8 @@ const fn or_str(opt: Option<&'static str>, default: &'static str) -> &'static st
 
 const SSID: &str = or_str(option_env!("SSID"), "MyDefaultSSID");
 const PASSWORD: &str = or_str(option_env!("PASSWORD"), "MyDefaultPassword");
-
-#[task(pool_size = 2)]
-pub async fn http_wk(
-    mut http_client: EmbassyHttpClient<'static, 'static, 3>,
-    url: &'static str,
-    period_ms: u64,
-    name: &'static str,
-) {
-    loop {
-        info!("[{}] Running HTTP client", name);
-        let _ = http_client.get(url, 2).await;
-        Timer::after(Duration::from_millis(period_ms)).await;
-    }
-}
+/// WPA2 passphrase of the provisioning AP until one is stored, open when empty
+const AP_PASSWORD: &str = or_str(option_env!("AP_PASSWORD"), "");
 
 type PhysFlash = FlashStorage;
 type AsyncFlash = BlockingAsync<PhysFlash>;
@@ -122,26 +128,31 @@ async fn main(spawner: Spawner) {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
     info!("CPU {:>3} MHz", config.cpu_clock().mhz());
+    status::record_hardware(config.cpu_clock().mhz(), ota_flash.capacity() as u32);
 
     log_banner("OTA Init");
-    {
+    let ota_pending = {
         let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
         let ota_result = run_with_ota(
             &mut ota_flash,
             &mut pt_mem,
-            |ota| -> Result<(), ota::Error> {
+            |ota| -> Result<bool, ota::Error> {
                 let current = ota.current_slot()?;
-                if current != Slot::None {
-                    ota.set_current_ota_state(Valid)?;
-                }
+                ota::record_running_slot(current);
+                let pending = begin_pending_verify(ota)?;
                 info!("current OTA image state {:?}", ota.current_ota_state()?);
                 info!("current OTA {:?} → next {:?}", current, current.next());
-                validate_current_ota_slot(ota)?;
-                Ok(())
+                Ok(pending)
             },
         );
-        try_log!(ota_result, "OTA init/validation failed");
-    }
+        match ota_result {
+            Ok(Ok(pending)) => pending,
+            Ok(Err(e)) | Err(e) => {
+                error!("OTA init/validation failed: {:?}", e);
+                false
+            }
+        }
+    };
 
     log_banner("DB Init");
     let flash = FlashStorage::new();
@@ -155,10 +166,35 @@ async fn main(spawner: Spawner) {
     let kv_mutex: &'static DbMutex = DB.init(Mutex::new(kv));
     {
         let db = kv_mutex.lock().await;
-        if db.mount().await.is_err() {
+        if db.mount().await.is_ok() {
+            DB_MOUNTED.store(true, Ordering::Release);
+        } else {
             info!("Formatting Persistent EKV Storage...");
-            try_log!(db.format().await, "EKV format failed");
+            match db.format().await {
+                Ok(()) => DB_MOUNTED.store(true, Ordering::Release),
+                Err(e) => error!("EKV format failed: {:?}", e),
+            }
+        }
+    }
+
+    try_log!(
+        ota::load_security_floor(kv_mutex).await,
+        "load_security_floor"
+    );
+
+    if ota_pending {
+        match ota::count_boot_attempt(kv_mutex).await {
+            Ok(attempts) if attempts > ota::MAX_BOOT_ATTEMPTS => {
+                error!("Unconfirmed OTA image booted {} times", attempts);
+                ota::rollback(kv_mutex).await;
+            }
+            Ok(attempts) => info!("Unconfirmed OTA image, boot attempt {}", attempts),
+            Err(e) => error!("Failed to count OTA boot attempt: {}", e),
         }
+        try_log!(
+            spawner.spawn(ota_health_task(kv_mutex)),
+            "spawn(ota_health_task)"
+        );
     }
 
     {
@@ -239,36 +275,80 @@ async fn main(spawner: Spawner) {
     );
 
     log_banner("Wifi Init");
-    let (ssid, password, mode) = match get_wifi_credentials(kv_mutex).await {
-        Ok(creds) => {
-            info!("Using stored Wi-Fi credentials");
-            info!("mDNS name {}.local", creds.hostname);
-            (creds.ssid, creds.password, WifiMode::Sta)
+    let networks = match read_networks(kv_mutex).await {
+        Ok(networks) => networks,
+        Err(e) => {
+            error!("Failed to read saved networks: {e}");
+            SavedNetworks::new()
         }
-        Err(_) => match get_default_credentials() {
-            Ok(default_creds)
+    };
+    let default_creds = get_default_credentials().ok();
+    let hostname = match (read_hostname(kv_mutex).await, &default_creds) {
+        (Ok((_, hostname)), _) if !hostname.is_empty() => hostname,
+        (_, Some(default_creds)) => default_creds.hostname.clone(),
+        _ => String::new(),
+    };
+    info!("mDNS name {}.local", hostname);
+    let (networks, mode) = if !networks.is_empty() {
+        info!("Using {} stored Wi-Fi network(s)", networks.len());
+        (networks, WifiMode::Sta)
+    } else {
+        match default_creds {
+            Some(default_creds)
                 if !default_creds.ssid.is_empty() && default_creds.ssid != "MyDefaultSSID" =>
             {
                 info!("Using compile-time Wi-Fi credentials");
-                info!("mDNS name {}.local", default_creds.hostname);
-                (default_creds.ssid, default_creds.password, WifiMode::Sta)
+                let mut networks = SavedNetworks::new();
+                let _ = networks.push(SavedNetwork {
+                    ssid: default_creds.ssid,
+                    psw: default_creds.password,
+                    priority: 0,
+                });
+                (networks, WifiMode::Sta)
             }
             _ => {
                 info!("No valid credentials, starting in AP mode");
-                (String::new(), String::new(), WifiMode::Ap)
+                (networks, WifiMode::Ap)
             }
-        },
+        }
     };
 
-    let stack = match init_wifi(
+    let sta_failure_limit = match read_sta_failure_limit(kv_mutex).await {
+        Ok(limit) => limit.unwrap_or(DEFAULT_STA_FAILURE_LIMIT),
+        Err(e) => {
+            error!("Failed to read STA failure limit: {e}");
+            DEFAULT_STA_FAILURE_LIMIT
+        }
+    };
+
+    let static_ip = match read_static_ip(kv_mutex).await {
+        Ok(static_ip) => static_ip,
+        Err(e) => {
+            error!("Failed to read static IP settings, using DHCP: {e}");
+            None
+        }
+    };
+
+    let ap_config = match read_ap_config(kv_mutex).await {
+        Ok(ap_config) => ap_config,
+        Err(e) => {
+            error!("Failed to read AP settings, using defaults: {e}");
+            Default::default()
+        }
+    };
+
+    let stacks = match init_wifi(
         spawner,
         timer_g0,
         rng,
         peripherals.WIFI,
         peripherals.RADIO_CLK,
-        ssid,
-        password,
+        networks,
+        static_ip,
+        ap_config,
         mode,
+        sta_failure_limit,
+        kv_mutex,
     )
     .await
     {
@@ -283,28 +363,65 @@ async fn main(spawner: Spawner) {
 
     WIFI_INITIALIZED.store(true, Ordering::Release);
     log_banner("System Init finished");
+    let stack = stacks.sta;
+
+    if !hostname.is_empty() {
+        try_log!(spawner.spawn(run_mdns(*stack, hostname)), "spawn(run_mdns)");
+    }
+
+    let time_zone = match read_time_zone(kv_mutex).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to read time zone, using UTC: {e}");
+            TimeZoneSettings::default()
+        }
+    };
+    match time_zone.parse() {
+        Ok(zone) => clock::set_time_zone(zone),
+        Err(e) => error!("Ignoring time zone {:?}: {e}", time_zone.timezone),
+    }
+
+    let ntp_settings = match read_ntp_servers(kv_mutex).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to read NTP servers, using defaults: {e}");
+            NtpSettings::default()
+        }
+    };
+    let ntp_servers = ntp_settings.parse().unwrap_or_default();
+    try_log!(
+        spawner.spawn(run_sntp(*stack, ntp_servers)),
+        "spawn(run_sntp)"
+    );
 
     let client_state = CLIENT_STATE.init(TcpClientState::new());
     let tcp_client = TCP_CLIENT.init(TcpClient::new(*stack, client_state));
     let http_client = EmbassyHttpClient::new(stack, tcp_client);
+    let ota_http_client = EmbassyHttpClient::new(stack, tcp_client);
 
     log_banner("HTTP Clients Init finished");
 
-    log_banner("Starting http worker");
+    log_banner("Starting OTA worker");
+    try_log!(
+        spawner.spawn(ota_task(ota_http_client, kv_mutex)),
+        "spawn(ota_task)"
+    );
+
+    log_banner("Starting update checker");
     try_log!(
-        spawner.spawn(http_wk(
-            http_client,
-            "http://mobile-j.de",
-            120_000,
-            "client1"
-        )),
-        "spawn(http_wk)"
+        spawner.spawn(update_check_task(http_client, kv_mutex, 3_600_000)),
+        "spawn(update_check_task)"
     );
 
     log_banner("Starting web server");
     let sse_message_watch = web_server::init_sse_message_watch();
     let sse_message_sender = sse_message_watch.sender();
-    let app_props = AppProps::new(kv_mutex);
+    match read_admin_password(kv_mutex).await {
+        Ok(Some(hash)) => auth::set_password(hash),
+        Ok(None) => warn!("No admin password set, the web API is open until one is"),
+        Err(e) => error!("Failed to read admin password: {e}"),
+    }
+    let app_props = AppProps::new(kv_mutex, rng);
     let app = make_static!(AppRouter<AppProps>, app_props.build_app());
     let config = make_static!(
         picoserve::Config<Duration>,
@@ -317,6 +434,11 @@ async fn main(spawner: Spawner) {
         .keep_connection_alive()
     );
     for id in 0..web_server::WEB_TASK_POOL_SIZE {
+        let stack = if id < web_server::AP_WEB_TASKS {
+            stacks.ap
+        } else {
+            stacks.sta
+        };
         spawner.must_spawn(web_task(id, *stack, app, config));
     }
 
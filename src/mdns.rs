@@ -0,0 +1,630 @@
+//! mDNS and DNS-SD records (RFC 6762, RFC 6763) for `<hostname>.local` and
+//! the web server's `_http._tcp` service, with AAAA records when there is an
+//! IPv6 address. Encoding only; the socket side is `run_mdns` in `wifi.rs`.
+
+use crate::dns::{
+    self, CLASS_IN, DnsError, FLAG_AA, FLAG_QR, HEADER_LEN, Header, MessageWriter, TYPE_A,
+    TYPE_AAAA, TYPE_ANY, TYPE_PTR, TYPE_SRV, TYPE_TXT,
+};
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+#[cfg(feature = "ipv6")]
+pub const MDNS_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+const TTL_S: u32 = 120;
+/// RFC 6762 6.7: legacy unicast answers are cached briefly and never flush caches
+const LEGACY_TTL_S: u32 = 10;
+/// Set on records only this device answers for
+const CACHE_FLUSH: u16 = 0x8000;
+/// Set in a question's class when the asker wants a unicast answer
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+const LOCAL: &str = "local";
+const SERVICE: [&str; 3] = ["_http", "_tcp", LOCAL];
+const SERVICE_ENUMERATION: [&str; 4] = ["_services", "_dns-sd", "_udp", LOCAL];
+
+/// Records of one response, in the order they are written
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Records {
+    services: bool,
+    ptr: bool,
+    srv: bool,
+    txt: bool,
+    a: bool,
+    aaaa: bool,
+}
+
+impl Records {
+    const ALL: Self = Self {
+        services: true,
+        ptr: true,
+        srv: true,
+        txt: true,
+        a: true,
+        aaaa: true,
+    };
+    const NONE: Self = Self {
+        services: false,
+        ptr: false,
+        srv: false,
+        txt: false,
+        a: false,
+        aaaa: false,
+    };
+
+    fn count(self) -> u16 {
+        [
+            self.services,
+            self.ptr,
+            self.srv,
+            self.txt,
+            self.a,
+            self.aaaa,
+        ]
+        .iter()
+        .filter(|&&set| set)
+        .count() as u16
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            services: self.services | other.services,
+            ptr: self.ptr | other.ptr,
+            srv: self.srv | other.srv,
+            txt: self.txt | other.txt,
+            a: self.a | other.a,
+            aaaa: self.aaaa | other.aaaa,
+        }
+    }
+
+    fn without(self, other: Self) -> Self {
+        Self {
+            services: self.services & !other.services,
+            ptr: self.ptr & !other.ptr,
+            srv: self.srv & !other.srv,
+            txt: self.txt & !other.txt,
+            a: self.a & !other.a,
+            aaaa: self.aaaa & !other.aaaa,
+        }
+    }
+
+    /// Records a resolver will ask for next, sent along as additionals.
+    /// Either address record brings the other one (RFC 6762 6.2).
+    fn additionals(self) -> Self {
+        let host = self.ptr | self.srv | self.a | self.aaaa;
+        Self {
+            srv: self.ptr,
+            txt: self.ptr,
+            a: host,
+            aaaa: host,
+            ..Self::NONE
+        }
+    }
+}
+
+/// Answer to an mDNS query
+pub struct Response {
+    pub len: usize,
+    /// The asker wants the answer sent to it directly instead of the group
+    pub unicast: bool,
+}
+
+/// What the device advertises: `<hostname>.local` and its web server
+pub struct MdnsService<'a> {
+    pub hostname: &'a str,
+    pub address: Option<Ipv4Addr>,
+    pub address_v6: Option<Ipv6Addr>,
+    pub port: u16,
+}
+
+impl MdnsService<'_> {
+    /// Address records only exist for the families the device has an address in
+    fn missing(&self) -> Records {
+        Records {
+            a: self.address.is_none(),
+            aaaa: self.address_v6.is_none(),
+            ..Records::NONE
+        }
+    }
+
+    /// Unsolicited response with every record, sent at startup and whenever
+    /// the address changes
+    pub fn announcement(&self, out: &mut [u8]) -> Result<usize, DnsError> {
+        let records = Records::ALL.without(self.missing());
+        let header = Header {
+            id: 0,
+            flags: FLAG_QR | FLAG_AA,
+            questions: 0,
+            answers: records.count(),
+            authorities: 0,
+            additionals: 0,
+        };
+        let mut writer = MessageWriter::new(out, &header);
+        self.write_records(&mut writer, records, false);
+        writer.finish()
+    }
+
+    /// Answer to `query`, `None` when none of its questions are about this device.
+    /// `legacy` is for queries from a port other than 5353, which get their id and
+    /// questions echoed back like a unicast DNS answer.
+    pub fn answer(
+        &self,
+        query: &[u8],
+        legacy: bool,
+        out: &mut [u8],
+    ) -> Result<Option<Response>, DnsError> {
+        let header = Header::parse(query)?;
+        if !header.is_query() {
+            return Ok(None);
+        }
+
+        let mut answers = Records::NONE;
+        let mut unicast = legacy;
+        let mut questions_end = HEADER_LEN;
+        for question in dns::questions(query) {
+            let question = question?;
+            questions_end = question.end;
+            if question.qclass & !UNICAST_RESPONSE != CLASS_IN {
+                continue;
+            }
+            let matched = self.match_question(query, question.name, question.qtype);
+            if matched != Records::NONE {
+                unicast |= question.qclass & UNICAST_RESPONSE != 0;
+            }
+            answers = answers.union(matched);
+        }
+        let answers = answers.without(self.missing());
+        if answers == Records::NONE {
+            return Ok(None);
+        }
+
+        let additionals = answers
+            .additionals()
+            .without(answers)
+            .without(self.missing());
+        let mut writer = MessageWriter::new(
+            out,
+            &Header {
+                id: if legacy { header.id } else { 0 },
+                flags: FLAG_QR | FLAG_AA,
+                questions: if legacy { header.questions } else { 0 },
+                answers: answers.count(),
+                authorities: 0,
+                additionals: additionals.count(),
+            },
+        );
+        if legacy {
+            writer.bytes(&query[HEADER_LEN..questions_end]);
+        }
+        self.write_records(&mut writer, answers, legacy);
+        self.write_records(&mut writer, additionals, legacy);
+        let len = writer.finish()?;
+        Ok(Some(Response { len, unicast }))
+    }
+
+    fn match_question(&self, query: &[u8], name: usize, qtype: u16) -> Records {
+        let wants = |rtype: u16| qtype == rtype || qtype == TYPE_ANY;
+        if dns::name_matches(query, name, &[self.hostname, LOCAL]) {
+            Records {
+                a: wants(TYPE_A),
+                aaaa: wants(TYPE_AAAA),
+                ..Records::NONE
+            }
+        } else if dns::name_matches(query, name, &SERVICE) {
+            Records {
+                ptr: wants(TYPE_PTR),
+                ..Records::NONE
+            }
+        } else if dns::name_matches(query, name, &self.instance()) {
+            Records {
+                srv: wants(TYPE_SRV),
+                txt: wants(TYPE_TXT),
+                ..Records::NONE
+            }
+        } else if dns::name_matches(query, name, &SERVICE_ENUMERATION) {
+            Records {
+                services: wants(TYPE_PTR),
+                ..Records::NONE
+            }
+        } else {
+            Records::NONE
+        }
+    }
+
+    fn instance(&self) -> [&str; 4] {
+        [self.hostname, SERVICE[0], SERVICE[1], LOCAL]
+    }
+
+    /// Writes `records`; `legacy` ones get a short TTL and no cache-flush bit
+    fn write_records(&self, writer: &mut MessageWriter, records: Records, legacy: bool) {
+        let host = [self.hostname, LOCAL];
+        let instance = self.instance();
+        let (ttl, unique) = if legacy {
+            (LEGACY_TTL_S, CLASS_IN)
+        } else {
+            (TTL_S, CLASS_IN | CACHE_FLUSH)
+        };
+
+        if records.services {
+            writer.name(&SERVICE_ENUMERATION);
+            writer.record_header(TYPE_PTR, CLASS_IN, ttl, dns::name_len(&SERVICE));
+            writer.name(&SERVICE);
+        }
+        if records.ptr {
+            writer.name(&SERVICE);
+            writer.record_header(TYPE_PTR, CLASS_IN, ttl, dns::name_len(&instance));
+            writer.name(&instance);
+        }
+        if records.srv {
+            writer.name(&instance);
+            writer.record_header(TYPE_SRV, unique, ttl, 6 + dns::name_len(&host));
+            // Priority and weight, then the port
+            writer.u16(0);
+            writer.u16(0);
+            writer.u16(self.port);
+            writer.name(&host);
+        }
+        if records.txt {
+            // DNS-SD wants a TXT record even without keys: one empty string
+            writer.name(&instance);
+            writer.record(TYPE_TXT, unique, ttl, &[0]);
+        }
+        if let (true, Some(address)) = (records.a, self.address) {
+            writer.name(&host);
+            writer.record(TYPE_A, unique, ttl, &address.octets());
+        }
+        if let (true, Some(address)) = (records.aaaa, self.address_v6) {
+            writer.name(&host);
+            writer.record(TYPE_AAAA, unique, ttl, &address.octets());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 42);
+    const ADDRESS_V6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1234, 0x56ff, 0xfe78, 0x9abc);
+
+    fn service(address: Option<Ipv4Addr>, address_v6: Option<Ipv6Addr>) -> MdnsService<'static> {
+        MdnsService {
+            hostname: "esp32",
+            address,
+            address_v6,
+            port: 80,
+        }
+    }
+
+    fn dual_stack() -> MdnsService<'static> {
+        service(Some(ADDRESS), Some(ADDRESS_V6))
+    }
+
+    fn query(id: u16, questions: &[(&[&str], u16, u16)]) -> Vec<u8> {
+        let mut buf = [0; 512];
+        let header = Header {
+            id,
+            flags: 0,
+            questions: questions.len() as u16,
+            answers: 0,
+            authorities: 0,
+            additionals: 0,
+        };
+        let mut writer = MessageWriter::new(&mut buf, &header);
+        for (name, qtype, qclass) in questions {
+            writer.name(name);
+            writer.u16(*qtype);
+            writer.u16(*qclass);
+        }
+        let len = writer.finish().unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Record {
+        name: String,
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+        data: Vec<u8>,
+    }
+
+    fn name(packet: &[u8], offset: usize) -> String {
+        let mut labels = Vec::new();
+        dns::for_each_label(packet, offset, |label| {
+            labels.push(String::from_utf8_lossy(label).into_owned())
+        })
+        .unwrap();
+        labels.join(".")
+    }
+
+    /// Header and the answer and additional records of `packet`
+    fn parse(packet: &[u8]) -> (Header, Vec<Record>) {
+        let header = Header::parse(packet).unwrap();
+        let mut offset = dns::questions(packet)
+            .map(|question| question.unwrap().end)
+            .last()
+            .unwrap_or(HEADER_LEN);
+        let mut records = Vec::new();
+        for _ in 0..header.answers + header.additionals {
+            let end = dns::skip_name(packet, offset).unwrap();
+            let fixed = &packet[end..end + 10];
+            let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+            records.push(Record {
+                name: name(packet, offset),
+                rtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+                class: u16::from_be_bytes([fixed[2], fixed[3]]),
+                ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+                data: packet[end + 10..end + 10 + len].to_vec(),
+            });
+            offset = end + 10 + len;
+        }
+        assert_eq!(offset, packet.len());
+        (header, records)
+    }
+
+    fn answer(service: &MdnsService, query: &[u8], legacy: bool) -> Option<(Response, Vec<u8>)> {
+        let mut out = [0; 512];
+        let response = service.answer(query, legacy, &mut out).unwrap()?;
+        let packet = out[..response.len].to_vec();
+        Some((response, packet))
+    }
+
+    fn types(records: &[Record]) -> Vec<u16> {
+        records.iter().map(|record| record.rtype).collect()
+    }
+
+    /// `name` as it is written on the wire
+    fn encoded(name: &[&str]) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let mut writer = MessageWriter::new(&mut buf, &Header::parse(&[0; HEADER_LEN]).unwrap());
+        writer.name(name);
+        let len = writer.finish().unwrap();
+        buf[HEADER_LEN..len].to_vec()
+    }
+
+    #[test]
+    fn announces_every_record() {
+        let mut out = [0; 512];
+        let len = dual_stack().announcement(&mut out).unwrap();
+        let (header, records) = parse(&out[..len]);
+        assert_eq!(header.flags, FLAG_QR | FLAG_AA);
+        assert_eq!((header.id, header.questions, header.answers), (0, 0, 6));
+
+        let flush = CLASS_IN | CACHE_FLUSH;
+        let expected = [
+            (
+                "_services._dns-sd._udp.local",
+                TYPE_PTR,
+                CLASS_IN,
+                encoded(&SERVICE),
+            ),
+            (
+                "_http._tcp.local",
+                TYPE_PTR,
+                CLASS_IN,
+                encoded(&["esp32", "_http", "_tcp", "local"]),
+            ),
+            (
+                "esp32._http._tcp.local",
+                TYPE_SRV,
+                flush,
+                [&[0, 0, 0, 0, 0, 80][..], &encoded(&["esp32", "local"])].concat(),
+            ),
+            ("esp32._http._tcp.local", TYPE_TXT, flush, vec![0]),
+            ("esp32.local", TYPE_A, flush, ADDRESS.octets().to_vec()),
+            (
+                "esp32.local",
+                TYPE_AAAA,
+                flush,
+                ADDRESS_V6.octets().to_vec(),
+            ),
+        ];
+        for (record, (name, rtype, class, data)) in records.iter().zip(expected) {
+            assert_eq!(
+                *record,
+                Record {
+                    name: name.into(),
+                    rtype,
+                    class,
+                    ttl: TTL_S,
+                    data,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn announces_only_families_with_an_address() {
+        let mut out = [0; 512];
+        let len = service(Some(ADDRESS), None).announcement(&mut out).unwrap();
+        let (_, records) = parse(&out[..len]);
+        assert_eq!(
+            types(&records),
+            [TYPE_PTR, TYPE_PTR, TYPE_SRV, TYPE_TXT, TYPE_A]
+        );
+
+        let len = service(None, Some(ADDRESS_V6))
+            .announcement(&mut out)
+            .unwrap();
+        let (_, records) = parse(&out[..len]);
+        assert_eq!(
+            types(&records),
+            [TYPE_PTR, TYPE_PTR, TYPE_SRV, TYPE_TXT, TYPE_AAAA]
+        );
+    }
+
+    #[test]
+    fn answers_a_with_aaaa_additional() {
+        let query = query(0x55, &[(&["ESP32", "Local"], TYPE_A, CLASS_IN)]);
+        let (response, packet) = answer(&dual_stack(), &query, false).unwrap();
+        assert!(!response.unicast);
+        let (header, records) = parse(&packet);
+        assert_eq!((header.id, header.questions), (0, 0));
+        assert_eq!((header.answers, header.additionals), (1, 1));
+        assert_eq!(types(&records), [TYPE_A, TYPE_AAAA]);
+        assert_eq!(records[0].data, ADDRESS.octets());
+    }
+
+    #[test]
+    fn answers_aaaa() {
+        let query = query(0, &[(&["esp32", "local"], TYPE_AAAA, CLASS_IN)]);
+        let (_, packet) = answer(&dual_stack(), &query, false).unwrap();
+        let (_, records) = parse(&packet);
+        assert_eq!(types(&records), [TYPE_AAAA, TYPE_A]);
+        assert_eq!(records[0].data, ADDRESS_V6.octets());
+    }
+
+    #[test]
+    fn answers_any_with_both_addresses() {
+        let query = query(0, &[(&["esp32", "local"], TYPE_ANY, CLASS_IN)]);
+        let (_, packet) = answer(&dual_stack(), &query, false).unwrap();
+        let (header, records) = parse(&packet);
+        assert_eq!((header.answers, header.additionals), (2, 0));
+        assert_eq!(types(&records), [TYPE_A, TYPE_AAAA]);
+    }
+
+    #[test]
+    fn leaves_out_missing_addresses() {
+        let ipv4_only = service(Some(ADDRESS), None);
+        let aaaa = query(0, &[(&["esp32", "local"], TYPE_AAAA, CLASS_IN)]);
+        assert!(answer(&ipv4_only, &aaaa, false).is_none());
+
+        let a = query(0, &[(&["esp32", "local"], TYPE_A, CLASS_IN)]);
+        let (_, packet) = answer(&ipv4_only, &a, false).unwrap();
+        let (header, _) = parse(&packet);
+        assert_eq!((header.answers, header.additionals), (1, 0));
+
+        let ptr = query(0, &[(&SERVICE, TYPE_PTR, CLASS_IN)]);
+        let (_, packet) = answer(&service(None, None), &ptr, false).unwrap();
+        let (_, records) = parse(&packet);
+        assert_eq!(types(&records), [TYPE_PTR, TYPE_SRV, TYPE_TXT]);
+    }
+
+    #[test]
+    fn answers_ptr_with_service_additionals() {
+        let query = query(0, &[(&SERVICE, TYPE_PTR, CLASS_IN)]);
+        let (_, packet) = answer(&dual_stack(), &query, false).unwrap();
+        let (header, records) = parse(&packet);
+        assert_eq!((header.answers, header.additionals), (1, 4));
+        assert_eq!(
+            types(&records),
+            [TYPE_PTR, TYPE_SRV, TYPE_TXT, TYPE_A, TYPE_AAAA]
+        );
+        assert_eq!(records[0].name, "_http._tcp.local");
+    }
+
+    #[test]
+    fn answers_srv_and_txt() {
+        let instance = ["esp32", "_http", "_tcp", "local"];
+        let srv = query(0, &[(&instance, TYPE_SRV, CLASS_IN)]);
+        let (_, packet) = answer(&dual_stack(), &srv, false).unwrap();
+        let (_, records) = parse(&packet);
+        assert_eq!(types(&records), [TYPE_SRV, TYPE_A, TYPE_AAAA]);
+        assert_eq!(records[0].data[4..6], 80u16.to_be_bytes());
+
+        let txt = query(0, &[(&instance, TYPE_TXT, CLASS_IN)]);
+        let (_, packet) = answer(&dual_stack(), &txt, false).unwrap();
+        let (_, records) = parse(&packet);
+        assert_eq!(types(&records), [TYPE_TXT]);
+        assert_eq!(records[0].data, [0]);
+    }
+
+    #[test]
+    fn answers_service_enumeration() {
+        let query = query(0, &[(&SERVICE_ENUMERATION, TYPE_PTR, CLASS_IN)]);
+        let (_, packet) = answer(&dual_stack(), &query, false).unwrap();
+        let (_, records) = parse(&packet);
+        assert_eq!(types(&records), [TYPE_PTR]);
+        assert_eq!(records[0].data, encoded(&SERVICE));
+    }
+
+    #[test]
+    fn merges_questions() {
+        let query = query(
+            0,
+            &[
+                (&["esp32", "local"], TYPE_A, CLASS_IN),
+                (&["other", "local"], TYPE_A, CLASS_IN),
+                (&SERVICE, TYPE_PTR, CLASS_IN),
+            ],
+        );
+        let (_, packet) = answer(&dual_stack(), &query, false).unwrap();
+        let (header, records) = parse(&packet);
+        assert_eq!((header.answers, header.additionals), (2, 3));
+        assert_eq!(
+            types(&records),
+            [TYPE_PTR, TYPE_A, TYPE_SRV, TYPE_TXT, TYPE_AAAA]
+        );
+    }
+
+    #[test]
+    fn ignores_other_names_classes_and_responses() {
+        let service = dual_stack();
+        let other = query(0, &[(&["other", "local"], TYPE_A, CLASS_IN)]);
+        assert!(answer(&service, &other, false).is_none());
+        let chaos = query(0, &[(&["esp32", "local"], TYPE_A, 3)]);
+        assert!(answer(&service, &chaos, false).is_none());
+
+        let mut response = query(0, &[(&["esp32", "local"], TYPE_A, CLASS_IN)]);
+        response[2] |= 0x80;
+        assert!(answer(&service, &response, false).is_none());
+    }
+
+    #[test]
+    fn honours_unicast_response_bit() {
+        let unmatched = query(
+            0,
+            &[
+                (&["other", "local"], TYPE_A, CLASS_IN | UNICAST_RESPONSE),
+                (&["esp32", "local"], TYPE_A, CLASS_IN),
+            ],
+        );
+        let (response, _) = answer(&dual_stack(), &unmatched, false).unwrap();
+        assert!(!response.unicast, "only matched questions count");
+
+        let matched = query(
+            0,
+            &[(&["esp32", "local"], TYPE_A, CLASS_IN | UNICAST_RESPONSE)],
+        );
+        let (response, _) = answer(&dual_stack(), &matched, false).unwrap();
+        assert!(response.unicast);
+    }
+
+    #[test]
+    fn legacy_unicast_echoes_id_and_questions() {
+        let query = query(0xbeef, &[(&["esp32", "local"], TYPE_A, CLASS_IN)]);
+        let (response, packet) = answer(&dual_stack(), &query, true).unwrap();
+        assert!(response.unicast);
+        let (header, records) = parse(&packet);
+        assert_eq!((header.id, header.questions), (0xbeef, 1));
+        assert_eq!(packet[HEADER_LEN..query.len()], query[HEADER_LEN..]);
+        for record in &records {
+            assert_eq!(record.ttl, LEGACY_TTL_S);
+            assert_eq!(record.class, CLASS_IN);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        let mut out = [0; 512];
+        let query = query(0, &[(&["esp32", "local"], TYPE_A, CLASS_IN)]);
+        let service = dual_stack();
+        assert_eq!(
+            service.answer(&query[..8], false, &mut out).err(),
+            Some(DnsError::Truncated)
+        );
+        assert_eq!(
+            service
+                .answer(&query[..query.len() - 1], false, &mut out)
+                .err(),
+            Some(DnsError::Truncated)
+        );
+        assert_eq!(
+            service.answer(&query, false, &mut out[..20]).err(),
+            Some(DnsError::BufferTooSmall)
+        );
+    }
+}
@@ -1,11 +1,172 @@
+use crate::config::{
+    DbError, read_bytes_setting, read_u32_setting, write_bytes_setting, write_u32_setting,
+};
+use crate::db::AlignedBuf;
+use crate::delta::{DeltaDecoder, DeltaError, PATCH_MAGIC};
+use crate::heatshrink::{HeatshrinkDecoder, HeatshrinkError};
+use crate::http::{self, BodySink, EmbassyHttpClient};
+use crate::ota_signature::{
+    DIGEST_LEN, DIGEST_STATE_LEN, ImageDigest, OTA_PUBLIC_KEY, SECURITY_VERSION, TRAILER_LEN,
+    parse_trailer, verify_image,
+};
+use crate::wifi::{WIFI_STATUS, WifiMode};
+use crate::{DB_MOUNTED, DbMutex, FIRMWARE_UPGRADE_IN_PROGRESS, WEB_REQUEST_SERVED};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use embassy_executor::task;
+use embassy_futures::select::{Either, select};
+use embassy_futures::yield_now;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::ReadStorage;
+use embedded_storage::nor_flash::NorFlash;
 pub(crate) use esp_bootloader_esp_idf::ota::{Ota, OtaImageState, Slot};
 use esp_bootloader_esp_idf::partitions::{
     self, AppPartitionSubType, DataPartitionSubType, PartitionTable, PartitionType,
 };
-use esp_storage::FlashStorage;
+use esp_hal::rom::crc::crc32_le;
+use esp_storage::{FlashStorage, FlashStorageError};
+use heapless::String;
 use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+/// First byte of every `esp_image_header_t`
+const IMAGE_MAGIC: u8 = 0xE9;
+/// Size of `esp_image_header_t`
+const IMAGE_HEADER_LEN: usize = 24;
+/// Upper bound the bootloader accepts for the segment count
+const IMAGE_MAX_SEGMENTS: u8 = 16;
+/// `esp_chip_id_t` of the ESP32-S3
+const IMAGE_CHIP_ID: u16 = 0x0009;
+/// First byte of a heatshrink compressed image: a literal tag bit followed by `IMAGE_MAGIC`
+const HEATSHRINK_IMAGE_MAGIC: u8 = 0x80 | (IMAGE_MAGIC >> 1);
+
+/// Size of `esp_ota_select_entry_t`, one per otadata sector
+const OTA_SELECT_ENTRY_LEN: usize = 32;
+
+const SECTOR_SIZE: u32 = 4096;
+const WRITE_BUF_SIZE: usize = 512;
+const HTTP_TIMEOUT_S: u64 = 10;
+/// Attempts of one HTTP update, each resuming where the previous one stopped
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+const DOWNLOAD_RETRY_DELAY_S: u64 = 10;
+/// Download progress is saved every this many bytes, a multiple of `SECTOR_SIZE`
+const CHECKPOINT_INTERVAL: u32 = 64 * 1024;
+const PROGRESS_KEY: &[u8] = b"ota.progress";
+const PROGRESS_HEADER_LEN: usize = 12 + DIGEST_STATE_LEN;
+
+/// Boots of an unconfirmed image before it is rolled back
+pub const MAX_BOOT_ATTEMPTS: u32 = 3;
+const BOOT_ATTEMPTS_KEY: &[u8] = b"ota.boot_attempts";
+const SECURITY_FLOOR_KEY: &[u8] = b"ota.secver_floor";
+const HEALTH_DEADLINE_KEY: &[u8] = b"ota.health_deadline_s";
+const HEALTH_CHECKS_KEY: &[u8] = b"ota.health_checks";
+/// Version of the image last rolled back, the manifest check passes it over
+const ROLLED_BACK_KEY: &[u8] = b"ota.rolled_back";
+const DEFAULT_HEALTH_DEADLINE_S: u32 = 120;
+pub const MIN_HEALTH_DEADLINE_S: u32 = 10;
+pub const MAX_HEALTH_DEADLINE_S: u32 = 3600;
+
+/// Image the `ota_task` should install next
+pub struct OtaRequest {
+    pub url: String<128>,
+    /// Size of the signed image, lets the download do without `Content-Length`
+    pub size: Option<u32>,
+    /// SHA-256 of the app image without the signature
+    pub digest: Option<[u8; DIGEST_LEN]>,
+}
+
+pub static OTA_REQUEST: Signal<CriticalSectionRawMutex, OtaRequest> = Signal::new();
+/// Asks the `ota_task` to reboot once the current HTTP response went out
+pub static REBOOT_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Bytes received by the running (or last) update
+pub static OTA_BYTES_RECEIVED: AtomicU32 = AtomicU32::new(0);
+/// Announced size of the running (or last) update, 0 if unknown
+pub static OTA_BYTES_TOTAL: AtomicU32 = AtomicU32::new(0);
+
+/// Lowest security version an OTA image may carry, mirrored from `ota.secver_floor`
+static SECURITY_FLOOR: AtomicU32 = AtomicU32::new(0);
+
+/// App partition index (0 = `ota_0`, 1 = `ota_1`) this firmware was booted from
+static RUNNING_SLOT: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Error reading the partition table or the OTA data partition
+    Partition(partitions::Error),
+
+    /// Error erasing or writing the app partition
+    Flash(FlashStorageError),
+
+    /// Error downloading the image
+    Http(http::Error),
 
-pub type Error = partitions::Error;
+    /// Image does not start with a valid ESP32-S3 app header
+    InvalidHeader,
+
+    /// Image is empty, larger than the slot or shorter than announced
+    InvalidLength,
+
+    /// Another firmware upgrade is already running
+    Busy,
+
+    /// Firmware was built without `OTA_PUBLIC_KEY`, so no image can be trusted
+    MissingPublicKey,
+
+    /// Image is unsigned, tampered with or signed by another key
+    InvalidSignature,
+
+    /// Image digest differs from the one announced for it
+    DigestMismatch,
+
+    /// Image carries a security version below the anti-rollback floor
+    SecurityVersionTooOld,
+
+    /// Heatshrink stream ends inside a token
+    InvalidCompression,
+
+    /// Delta patch is malformed or does not rebuild a complete image
+    InvalidPatch,
+
+    /// Delta patch was made against another image than the running one
+    SourceMismatch,
+}
+
+impl From<partitions::Error> for Error {
+    fn from(error: partitions::Error) -> Self {
+        Self::Partition(error)
+    }
+}
+
+impl From<FlashStorageError> for Error {
+    fn from(error: FlashStorageError) -> Self {
+        Self::Flash(error)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(error: http::Error) -> Self {
+        Self::Http(error)
+    }
+}
+
+impl From<HeatshrinkError> for Error {
+    fn from(error: HeatshrinkError) -> Self {
+        match error {
+            HeatshrinkError::Truncated => Self::InvalidCompression,
+        }
+    }
+}
+
+impl From<DeltaError> for Error {
+    fn from(error: DeltaError) -> Self {
+        match error {
+            DeltaError::InvalidPatch => Self::InvalidPatch,
+            DeltaError::SourceMismatch => Self::SourceMismatch,
+        }
+    }
+}
 
 pub fn run_with_ota<F, R>(
     flash: &mut FlashStorage,
@@ -20,21 +181,21 @@ where
 
     let ota_data = pt
         .find_partition(PartitionType::Data(DataPartitionSubType::Ota))?
-        .ok_or(Error::Invalid)?;
+        .ok_or(partitions::Error::Invalid)?;
 
     let mut ota_storage = ota_data.as_embedded_storage(flash);
     let mut ota = Ota::new(&mut ota_storage).map_err(|e| {
         error!("OTA init failed: {e:?}");
-        Error::Invalid
+        partitions::Error::Invalid
     })?;
 
     let ota0_offset = pt
         .find_partition(PartitionType::App(AppPartitionSubType::Ota0))?
-        .ok_or(Error::Invalid)?
+        .ok_or(partitions::Error::Invalid)?
         .offset();
     let ota1_offset = pt
         .find_partition(PartitionType::App(AppPartitionSubType::Ota1))?
-        .ok_or(Error::Invalid)?
+        .ok_or(partitions::Error::Invalid)?
         .offset();
     info!("Ota0 offset {}, Ota1 offset {}", ota0_offset, ota1_offset);
     info!("OTA initialised successfully");
@@ -42,7 +203,6 @@ where
     Ok(operation(&mut ota))
 }
 
-#[allow(dead_code)]
 pub fn set_next_ota_slot(next_slot: Slot, ota: &mut Ota<FlashStorage>) -> Result<(), Error> {
     info!("Setting OTA slot to {next_slot:?}");
     ota.set_current_slot(next_slot)?;
@@ -50,7 +210,6 @@ pub fn set_next_ota_slot(next_slot: Slot, ota: &mut Ota<FlashStorage>) -> Result
     Ok(())
 }
 
-#[allow(dead_code)]
 pub fn validate_current_ota_slot(ota: &mut Ota<FlashStorage>) -> Result<(), Error> {
     let state = ota.current_ota_state()?;
     let slot = ota.current_slot()?;
@@ -62,3 +221,987 @@ pub fn validate_current_ota_slot(ota: &mut Ota<FlashStorage>) -> Result<(), Erro
 
     Ok(())
 }
+
+/// Moves a freshly installed image from `New` to `PendingVerify`.
+/// Returns whether the running image still has to be confirmed.
+pub fn begin_pending_verify(ota: &mut Ota<FlashStorage>) -> Result<bool, Error> {
+    if ota.current_slot()? == Slot::None {
+        return Ok(false);
+    }
+    match ota.current_ota_state()? {
+        OtaImageState::New => {
+            info!("Marking current OTA slot as PENDING_VERIFY");
+            ota.set_current_ota_state(OtaImageState::PendingVerify)?;
+            Ok(true)
+        }
+        OtaImageState::PendingVerify => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// Marks the running image `Invalid`, selects the previous slot and reboots into it.
+/// The boot counter starts over, it belonged to the image given up on.
+pub async fn rollback(db: &'static DbMutex) -> ! {
+    if let Err(e) = reset_boot_attempts(db).await {
+        error!("Failed to reset OTA boot attempts: {e}");
+    }
+    // Or the next manifest poll installs the same image again
+    let version = env!("CARGO_PKG_VERSION");
+    if let Err(e) = write_bytes_setting(db, ROLLED_BACK_KEY, version.as_bytes()).await {
+        error!("Failed to record rolled back version {version}: {e}");
+    }
+    let mut flash = FlashStorage::new();
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let result = run_with_ota(&mut flash, &mut pt_mem, |ota| -> Result<(), Error> {
+        let current = ota.current_slot()?;
+        let previous = inactive_slot(current);
+        error!("Rolling back from {current:?} to {previous:?}");
+        ota.set_current_ota_state(OtaImageState::Invalid)?;
+        ota.set_current_slot(previous)?;
+        ota.set_current_ota_state(OtaImageState::Valid)?;
+        Ok(())
+    });
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) | Err(e) => error!("OTA rollback failed: {e:?}"),
+    }
+    reboot()
+}
+
+/// Counts one more boot of an unconfirmed image in the `configs` store
+pub async fn count_boot_attempt(db: &'static DbMutex) -> Result<u32, DbError> {
+    let attempts = read_u32_setting(db, BOOT_ATTEMPTS_KEY).await?.unwrap_or(0) + 1;
+    write_u32_setting(db, BOOT_ATTEMPTS_KEY, attempts).await?;
+    Ok(attempts)
+}
+
+/// Version of the last image that failed its health checks, if any
+pub async fn rolled_back_version(db: &'static DbMutex) -> Result<Option<String<32>>, DbError> {
+    let mut buf = [0u8; 32];
+    let version = read_bytes_setting(db, ROLLED_BACK_KEY, &mut buf)
+        .await?
+        .and_then(|n| core::str::from_utf8(&buf[..n]).ok())
+        .and_then(|version| String::try_from(version).ok());
+    Ok(version)
+}
+
+/// Starts the boot count over, for a confirmed image or one about to be booted
+pub async fn reset_boot_attempts(db: &'static DbMutex) -> Result<(), DbError> {
+    write_u32_setting(db, BOOT_ATTEMPTS_KEY, 0).await
+}
+
+/// Condition a new image has to reach before it is marked `Valid`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheck {
+    DbMounted,
+    /// The station got an IPv4 address, or on a device set to AP mode the AP
+    /// is up and served a web request
+    NetworkUp,
+    /// The web server answered at least one request
+    WebRequestServed,
+}
+
+impl HealthCheck {
+    const ALL: [HealthCheck; 3] = [
+        HealthCheck::DbMounted,
+        HealthCheck::NetworkUp,
+        HealthCheck::WebRequestServed,
+    ];
+
+    fn passed(self) -> bool {
+        match self {
+            HealthCheck::DbMounted => DB_MOUNTED.load(Ordering::Acquire),
+            HealthCheck::NetworkUp => WIFI_STATUS.try_get().is_some_and(|status| {
+                let station_up = status.link_up && status.ip.is_some();
+                // The fallback AP of a failing station does not count
+                let ap_only_up = status.mode == WifiMode::Ap
+                    && status.ap_up
+                    && WEB_REQUEST_SERVED.load(Ordering::Acquire);
+                station_up || ap_only_up
+            }),
+            HealthCheck::WebRequestServed => WEB_REQUEST_SERVED.load(Ordering::Acquire),
+        }
+    }
+
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// What a new image is checked for, kept under `ota.health_checks` and
+/// `ota.health_deadline_s`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HealthSettings {
+    pub checks: heapless::Vec<HealthCheck, 3>,
+    pub deadline_s: u32,
+}
+
+impl HealthSettings {
+    pub fn is_valid(&self) -> bool {
+        (MIN_HEALTH_DEADLINE_S..=MAX_HEALTH_DEADLINE_S).contains(&self.deadline_s)
+    }
+}
+
+/// All checks and `DEFAULT_HEALTH_DEADLINE_S` until settings are stored
+pub async fn read_health_settings(db: &'static DbMutex) -> Result<HealthSettings, DbError> {
+    let mask = read_u32_setting(db, HEALTH_CHECKS_KEY)
+        .await?
+        .unwrap_or(u32::MAX);
+    let deadline_s = read_u32_setting(db, HEALTH_DEADLINE_KEY)
+        .await?
+        .unwrap_or(DEFAULT_HEALTH_DEADLINE_S);
+    Ok(HealthSettings {
+        checks: HealthCheck::ALL
+            .into_iter()
+            .filter(|check| mask & check.bit() != 0)
+            .collect(),
+        deadline_s,
+    })
+}
+
+pub async fn write_health_settings(
+    db: &'static DbMutex,
+    settings: &HealthSettings,
+) -> Result<(), DbError> {
+    let mask = settings
+        .checks
+        .iter()
+        .fold(0, |mask, check| mask | check.bit());
+    info!(
+        "OTA health checks {:?}, deadline {}s",
+        settings.checks, settings.deadline_s
+    );
+    write_u32_setting(db, HEALTH_CHECKS_KEY, mask).await?;
+    write_u32_setting(db, HEALTH_DEADLINE_KEY, settings.deadline_s).await
+}
+
+/// Confirms a `PendingVerify` image once the configured checks pass, or rolls
+/// it back when they don't within the deadline.
+#[task]
+pub async fn ota_health_task(db: &'static DbMutex) {
+    let HealthSettings { checks, deadline_s } = match read_health_settings(db).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to read OTA health settings: {e}");
+            HealthSettings {
+                checks: HealthCheck::ALL.into_iter().collect(),
+                deadline_s: DEFAULT_HEALTH_DEADLINE_S,
+            }
+        }
+    };
+    let deadline = Instant::now() + Duration::from_secs(deadline_s as u64);
+    info!("OTA health checks {checks:?}, deadline {deadline_s}s");
+
+    while let Some(failed) = checks.iter().find(|check| !check.passed()) {
+        if Instant::now() >= deadline {
+            error!("OTA health check {failed:?} did not pass within {deadline_s}s");
+            rollback(db).await;
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+
+    let mut flash = FlashStorage::new();
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    match run_with_ota(&mut flash, &mut pt_mem, validate_current_ota_slot) {
+        Ok(Ok(())) => info!("OTA image confirmed healthy"),
+        Ok(Err(e)) | Err(e) => error!("Failed to confirm OTA image: {e:?}"),
+    }
+    if let Err(e) = reset_boot_attempts(db).await {
+        error!("Failed to reset OTA boot attempts: {e}");
+    }
+    if let Err(e) = advance_security_floor(db).await {
+        error!("Failed to advance security version floor: {e}");
+    }
+}
+
+/// Loads the anti-rollback floor from the `configs` store
+pub async fn load_security_floor(db: &'static DbMutex) -> Result<u32, DbError> {
+    let floor = read_u32_setting(db, SECURITY_FLOOR_KEY).await?.unwrap_or(0);
+    SECURITY_FLOOR.store(floor, Ordering::Relaxed);
+    info!("Security version {SECURITY_VERSION}, floor {floor}");
+    Ok(floor)
+}
+
+/// Raises the floor to the running build's security version. Only called once
+/// the image is confirmed healthy, so a broken image can still roll back.
+async fn advance_security_floor(db: &'static DbMutex) -> Result<(), DbError> {
+    let floor = load_security_floor(db).await?;
+    if SECURITY_VERSION > floor {
+        write_u32_setting(db, SECURITY_FLOOR_KEY, SECURITY_VERSION).await?;
+        SECURITY_FLOOR.store(SECURITY_VERSION, Ordering::Relaxed);
+        info!("Security version floor raised to {SECURITY_VERSION}");
+    }
+    Ok(())
+}
+
+/// Slot that is not running. There is no factory partition, so with blank
+/// OTA data (`Slot::None`) the bootloader runs `ota_0`.
+pub fn inactive_slot(current: Slot) -> Slot {
+    match current {
+        Slot::Slot1 => Slot::Slot0,
+        _ => Slot::Slot1,
+    }
+}
+
+/// Remember the slot the bootloader picked, before anything changes the OTA data
+pub fn record_running_slot(current: Slot) {
+    let index = match current {
+        Slot::Slot1 => 1,
+        _ => 0,
+    };
+    RUNNING_SLOT.store(index, Ordering::Relaxed);
+}
+
+pub fn running_slot() -> Slot {
+    match RUNNING_SLOT.load(Ordering::Relaxed) {
+        1 => Slot::Slot1,
+        _ => Slot::Slot0,
+    }
+}
+
+pub fn slot_name(slot: Slot) -> &'static str {
+    match slot {
+        Slot::None => "none",
+        Slot::Slot0 => "ota_0",
+        Slot::Slot1 => "ota_1",
+    }
+}
+
+/// Name of a raw `esp_ota_img_states_t` value
+fn state_name(raw: u32) -> &'static str {
+    match raw {
+        0 => "new",
+        1 => "pending_verify",
+        2 => "valid",
+        3 => "invalid",
+        4 => "aborted",
+        _ => "undefined",
+    }
+}
+
+#[derive(Serialize)]
+pub struct SlotStatus {
+    name: &'static str,
+    offset: Option<u32>,
+    size: Option<u32>,
+    seq: u32,
+    state: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct UpgradeProgress {
+    in_progress: bool,
+    received: u32,
+    total: u32,
+}
+
+#[derive(Serialize)]
+pub struct OtaStatus {
+    version: &'static str,
+    build_timestamp: u64,
+    security_version: u32,
+    security_floor: u32,
+    running_slot: &'static str,
+    boot_slot: &'static str,
+    slots: [SlotStatus; 2],
+    upgrade: UpgradeProgress,
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Fills in partition offsets and the OTA data entry of both slots.
+/// Returns the slot the bootloader will pick next.
+fn read_slot_status(
+    flash: &mut FlashStorage,
+    partition_buf: &mut [u8; partitions::PARTITION_TABLE_MAX_LEN],
+    slots: &mut [SlotStatus; 2],
+) -> Result<Slot, Error> {
+    let pt = partitions::read_partition_table(flash, partition_buf)?;
+    for (slot, sub_type) in slots
+        .iter_mut()
+        .zip([AppPartitionSubType::Ota0, AppPartitionSubType::Ota1])
+    {
+        if let Some(partition) = pt.find_partition(PartitionType::App(sub_type))? {
+            slot.offset = Some(partition.offset());
+            slot.size = Some(partition.len());
+        }
+    }
+
+    let ota_data = pt
+        .find_partition(PartitionType::Data(DataPartitionSubType::Ota))?
+        .ok_or(partitions::Error::Invalid)?;
+
+    let mut boot_seq = 0;
+    let mut boot_slot = Slot::None;
+    for sector in 0..2 {
+        let mut entry = AlignedBuf([0u8; OTA_SELECT_ENTRY_LEN]);
+        ReadStorage::read(
+            flash,
+            ota_data.offset() + sector * SECTOR_SIZE,
+            &mut entry.0,
+        )?;
+        let seq = le_u32(&entry.0[0..4]);
+        let state = le_u32(&entry.0[24..28]);
+        let crc = le_u32(&entry.0[28..32]);
+        if seq == 0 || seq == u32::MAX || crc32_le(u32::MAX, &entry.0[0..4]) != crc {
+            continue;
+        }
+
+        let index = ((seq - 1) % 2) as usize;
+        if seq > slots[index].seq {
+            slots[index].seq = seq;
+            slots[index].state = state_name(state);
+        }
+        if seq > boot_seq {
+            boot_seq = seq;
+            boot_slot = if index == 0 { Slot::Slot0 } else { Slot::Slot1 };
+        }
+    }
+    Ok(boot_slot)
+}
+
+/// Snapshot of both slots and the running update, for `GET /api/ota`
+pub fn status() -> OtaStatus {
+    let mut flash = FlashStorage::new();
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let mut slots = [Slot::Slot0, Slot::Slot1].map(|slot| SlotStatus {
+        name: slot_name(slot),
+        offset: None,
+        size: None,
+        seq: 0,
+        state: state_name(u32::MAX),
+    });
+    let boot_slot = match read_slot_status(&mut flash, &mut pt_mem, &mut slots) {
+        Ok(slot) => slot,
+        Err(e) => {
+            error!("Failed to read OTA slot status: {e:?}");
+            Slot::None
+        }
+    };
+
+    OtaStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or(0),
+        security_version: SECURITY_VERSION,
+        security_floor: SECURITY_FLOOR.load(Ordering::Relaxed),
+        running_slot: slot_name(running_slot()),
+        boot_slot: slot_name(boot_slot),
+        slots,
+        upgrade: UpgradeProgress {
+            in_progress: FIRMWARE_UPGRADE_IN_PROGRESS.load(Ordering::Acquire),
+            received: OTA_BYTES_RECEIVED.load(Ordering::Relaxed),
+            total: OTA_BYTES_TOTAL.load(Ordering::Relaxed),
+        },
+    }
+}
+
+/// Select the slot that is not running for the next boot, e.g. for a manual rollback.
+/// Refuses slots that do not start with an app image header.
+pub async fn switch_to_other_slot(db: &'static DbMutex) -> Result<Slot, Error> {
+    let _guard = UpgradeGuard::acquire()?;
+    let mut flash = FlashStorage::new();
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+
+    let target = inactive_slot(running_slot());
+    let (offset, _) = app_partition(&mut flash, &mut pt_mem, target)?;
+    let mut header = AlignedBuf([0u8; IMAGE_HEADER_LEN]);
+    ReadStorage::read(&mut flash, offset, &mut header.0)?;
+    check_image_header(&header.0)?;
+
+    run_with_ota(&mut flash, &mut pt_mem, |ota| {
+        set_next_ota_slot(target, ota)
+    })??;
+    if let Err(e) = reset_boot_attempts(db).await {
+        error!("Failed to reset OTA boot attempts: {e}");
+    }
+    Ok(target)
+}
+
+/// Returns `(offset, size)` of the app partition backing `slot`
+pub fn app_partition(
+    flash: &mut FlashStorage,
+    partition_buf: &mut [u8; partitions::PARTITION_TABLE_MAX_LEN],
+    slot: Slot,
+) -> Result<(u32, u32), Error> {
+    let sub_type = match slot {
+        Slot::Slot1 => AppPartitionSubType::Ota1,
+        _ => AppPartitionSubType::Ota0,
+    };
+    let pt = partitions::read_partition_table(flash, partition_buf)?;
+    let partition = pt
+        .find_partition(PartitionType::App(sub_type))?
+        .ok_or(partitions::Error::Invalid)?;
+    Ok((partition.offset(), partition.len()))
+}
+
+/// Checks the `esp_image_header_t` at the start of an app image
+pub fn check_image_header(header: &[u8]) -> Result<(), Error> {
+    if header.len() < IMAGE_HEADER_LEN || header[0] != IMAGE_MAGIC {
+        return Err(Error::InvalidHeader);
+    }
+    let segments = header[1];
+    let chip_id = u16::from_le_bytes([header[12], header[13]]);
+    if segments == 0 || segments > IMAGE_MAX_SEGMENTS || chip_id != IMAGE_CHIP_ID {
+        return Err(Error::InvalidHeader);
+    }
+    Ok(())
+}
+
+/// Holds `FIRMWARE_UPGRADE_IN_PROGRESS` for as long as it lives
+pub struct UpgradeGuard(());
+
+impl UpgradeGuard {
+    pub fn acquire() -> Result<Self, Error> {
+        if FIRMWARE_UPGRADE_IN_PROGRESS.swap(true, Ordering::AcqRel) {
+            return Err(Error::Busy);
+        }
+        Ok(Self(()))
+    }
+}
+
+impl Drop for UpgradeGuard {
+    fn drop(&mut self) {
+        FIRMWARE_UPGRADE_IN_PROGRESS.store(false, Ordering::Release);
+    }
+}
+
+/// Erases and writes the inactive slot, hashing the image as it reaches flash
+struct SlotWriter {
+    flash: FlashStorage,
+    offset: u32,
+    capacity: u32,
+    erased: u32,
+    written: u32,
+    buf: AlignedBuf<WRITE_BUF_SIZE>,
+    buf_len: usize,
+    digest: ImageDigest,
+}
+
+impl SlotWriter {
+    fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let n = (WRITE_BUF_SIZE - self.buf_len).min(data.len());
+            self.buf.0[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+            if self.buf_len == WRITE_BUF_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.buf_len == 0 {
+            return Ok(());
+        }
+        if self.written == 0 {
+            check_image_header(&self.buf.0[..self.buf_len])?;
+        }
+        self.digest.update(&self.buf.0[..self.buf_len]);
+
+        // Flash writes are word sized, pad the tail of the image with erased bytes
+        let padded_len = self.buf_len.next_multiple_of(4);
+        self.buf.0[self.buf_len..padded_len].fill(0xFF);
+
+        let end = self.written + padded_len as u32;
+        if end > self.capacity {
+            return Err(Error::InvalidLength);
+        }
+        while self.erased < end {
+            let sector = self.offset + self.erased;
+            NorFlash::erase(&mut self.flash, sector, sector + SECTOR_SIZE)?;
+            self.erased += SECTOR_SIZE;
+        }
+        NorFlash::write(
+            &mut self.flash,
+            self.offset + self.written,
+            &self.buf.0[..padded_len],
+        )?;
+
+        self.written += self.buf_len as u32;
+        self.buf_len = 0;
+        Ok(())
+    }
+}
+
+/// Wire format of the image body, told apart by its first byte
+enum Encoding {
+    /// No body byte seen yet
+    Unknown,
+    /// Plain app image
+    Raw,
+    /// Heatshrink stream, boxed so the window only takes heap while an update runs
+    Heatshrink(Box<HeatshrinkDecoder>),
+    /// Delta patch against the image in the running slot
+    Delta(Box<DeltaDecoder<FlashStorage>>),
+}
+
+/// Streams a signed, optionally compressed or delta encoded app image into the
+/// inactive OTA slot.
+/// The trailer is kept in RAM and checked before the slot is selected.
+pub struct OtaWriter {
+    slot: Slot,
+    slot_writer: SlotWriter,
+    source_partition: (u32, u32),
+    encoding: Encoding,
+    body_len: Option<u32>,
+    body_received: u32,
+    expected_digest: Option<[u8; DIGEST_LEN]>,
+    trailer: [u8; TRAILER_LEN],
+    trailer_len: usize,
+    _guard: UpgradeGuard,
+}
+
+impl OtaWriter {
+    pub fn new() -> Result<Self, Error> {
+        if OTA_PUBLIC_KEY.is_none() {
+            error!("Built without OTA_PUBLIC_KEY, refusing firmware updates");
+            return Err(Error::MissingPublicKey);
+        }
+        let guard = UpgradeGuard::acquire()?;
+        let mut flash = FlashStorage::new();
+        let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+
+        // Not the OTA data: after an update or `switch_to_other_slot` without a
+        // reboot it already names the other slot, and that one is running
+        let current = running_slot();
+        let slot = inactive_slot(current);
+        let (offset, capacity) = app_partition(&mut flash, &mut pt_mem, slot)?;
+        let source_partition = app_partition(&mut flash, &mut pt_mem, current)?;
+        info!("OTA target {slot:?} at offset {offset:#x}, {capacity} bytes");
+        OTA_BYTES_RECEIVED.store(0, Ordering::Relaxed);
+        OTA_BYTES_TOTAL.store(0, Ordering::Relaxed);
+
+        Ok(Self {
+            slot,
+            slot_writer: SlotWriter {
+                flash,
+                offset,
+                capacity,
+                erased: 0,
+                written: 0,
+                buf: AlignedBuf([0; WRITE_BUF_SIZE]),
+                buf_len: 0,
+                digest: ImageDigest::new(),
+            },
+            source_partition,
+            encoding: Encoding::Unknown,
+            body_len: None,
+            body_received: 0,
+            expected_digest: None,
+            trailer: [0; TRAILER_LEN],
+            trailer_len: 0,
+            _guard: guard,
+        })
+    }
+
+    /// Announce the signed image length. It is required to tell the trailer
+    /// apart and lets oversized images fail before anything is written.
+    pub fn set_expected_len(&mut self, len: usize) -> Result<(), Error> {
+        let len = u32::try_from(len).map_err(|_| Error::InvalidLength)?;
+        let body_len = len.saturating_sub(TRAILER_LEN as u32);
+        let capacity = self.slot_writer.capacity;
+        if body_len == 0 || body_len > capacity {
+            error!("Image of {len} bytes does not fit slot of {capacity} bytes");
+            return Err(Error::InvalidLength);
+        }
+        self.body_len = Some(body_len);
+        OTA_BYTES_TOTAL.store(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Announce the SHA-256 of the app image, checked in addition to the signature
+    pub fn set_expected_digest(&mut self, digest: [u8; DIGEST_LEN]) {
+        self.expected_digest = Some(digest);
+    }
+
+    /// Bytes accepted so far, including the ones still buffered
+    pub fn received(&self) -> u32 {
+        self.body_received + self.trailer_len as u32
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let body_len = self.body_len.ok_or(Error::InvalidLength)?;
+        let body_part = ((body_len - self.body_received) as usize).min(data.len());
+        let (body, trailer) = data.split_at(body_part);
+
+        let trailer_end = self.trailer_len + trailer.len();
+        if trailer_end > TRAILER_LEN {
+            return Err(Error::InvalidLength);
+        }
+        self.trailer[self.trailer_len..trailer_end].copy_from_slice(trailer);
+        self.trailer_len = trailer_end;
+
+        if let (Encoding::Unknown, Some(&first)) = (&self.encoding, body.first()) {
+            self.encoding = match first {
+                IMAGE_MAGIC => Encoding::Raw,
+                HEATSHRINK_IMAGE_MAGIC => {
+                    info!("Heatshrink compressed image");
+                    Encoding::Heatshrink(Box::new(HeatshrinkDecoder::new()))
+                }
+                magic if magic == PATCH_MAGIC[0] => {
+                    info!("Delta patch against the running image");
+                    let (offset, capacity) = self.source_partition;
+                    Encoding::Delta(Box::new(DeltaDecoder::new(
+                        FlashStorage::new(),
+                        offset,
+                        capacity,
+                    )))
+                }
+                _ => return Err(Error::InvalidHeader),
+            };
+        }
+
+        let slot_writer = &mut self.slot_writer;
+        match &mut self.encoding {
+            Encoding::Unknown => {}
+            Encoding::Raw => slot_writer.write(body)?,
+            Encoding::Heatshrink(decoder) => {
+                decoder.decode(body, &mut |out: &[u8]| slot_writer.write(out))?
+            }
+            Encoding::Delta(decoder) => {
+                let mut rest = body;
+                while !rest.is_empty() {
+                    let n = decoder.decode(rest, &mut |out: &[u8]| slot_writer.write(out))?;
+                    rest = &rest[n..];
+                    while decoder.hash_source::<Error>()? {
+                        yield_now().await;
+                    }
+                }
+            }
+        }
+        self.body_received += body.len() as u32;
+
+        OTA_BYTES_RECEIVED.store(self.received(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Bytes committed to flash and the digest state over them, if a download
+    /// could resume from here: a raw image cut at a sector boundary
+    fn checkpoint(&self) -> Option<(u32, [u8; DIGEST_STATE_LEN])> {
+        let writer = &self.slot_writer;
+        if !matches!(self.encoding, Encoding::Raw)
+            || writer.buf_len != 0
+            || self.trailer_len != 0
+            || writer.written == 0
+            || writer.written % SECTOR_SIZE != 0
+        {
+            return None;
+        }
+        Some((writer.written, writer.digest.save()?))
+    }
+
+    /// Continue an interrupted download of the same image into the same slot
+    fn resume(&mut self, progress: &OtaProgress) -> Result<(), Error> {
+        if progress.offset != self.slot_writer.offset
+            || progress.written == 0
+            || progress.written % SECTOR_SIZE != 0
+            || self
+                .body_len
+                .is_some_and(|body_len| body_len + TRAILER_LEN as u32 != progress.len)
+        {
+            return Err(Error::InvalidLength);
+        }
+        if self.body_len.is_none() {
+            self.set_expected_len(progress.len as usize)?;
+        }
+        if self
+            .body_len
+            .is_some_and(|body_len| progress.written >= body_len)
+        {
+            return Err(Error::InvalidLength);
+        }
+
+        // The sector after the checkpoint may hold bytes written before the
+        // interruption, so it is erased again
+        let writer = &mut self.slot_writer;
+        writer.written = progress.written;
+        writer.erased = progress.written;
+        writer.buf_len = 0;
+        writer.digest = ImageDigest::restore(&progress.digest);
+        self.encoding = Encoding::Raw;
+        self.body_received = progress.written;
+        OTA_BYTES_RECEIVED.store(self.received(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Drop everything received so far and start again from the first byte
+    fn rewind(&mut self) {
+        let writer = &mut self.slot_writer;
+        writer.written = 0;
+        writer.erased = 0;
+        writer.buf_len = 0;
+        writer.digest = ImageDigest::new();
+        self.encoding = Encoding::Unknown;
+        self.body_received = 0;
+        self.trailer_len = 0;
+        OTA_BYTES_RECEIVED.store(0, Ordering::Relaxed);
+    }
+
+    /// Flush the remaining bytes, check length, signature and security version
+    /// and make the written slot the next boot slot, with a fresh boot count
+    pub async fn finish(mut self, db: &'static DbMutex) -> Result<Slot, Error> {
+        match &self.encoding {
+            Encoding::Heatshrink(decoder) => decoder.finish()?,
+            Encoding::Delta(decoder) => decoder.finish()?,
+            Encoding::Unknown | Encoding::Raw => {}
+        }
+        self.slot_writer.flush()?;
+        let written = self.slot_writer.written;
+        if written == 0
+            || self.body_len != Some(self.body_received)
+            || self.trailer_len != TRAILER_LEN
+        {
+            error!(
+                "Image length mismatch: got {} + {} trailer bytes, expected {:?}",
+                self.body_received, self.trailer_len, self.body_len
+            );
+            return Err(Error::InvalidLength);
+        }
+
+        // The digest covers the rebuilt image, whatever the wire format was
+        let public_key = OTA_PUBLIC_KEY.ok_or(Error::MissingPublicKey)?;
+        let digest = core::mem::take(&mut self.slot_writer.digest).finalize();
+        if self
+            .expected_digest
+            .is_some_and(|expected| expected != digest)
+        {
+            error!("OTA image digest mismatch");
+            return Err(Error::DigestMismatch);
+        }
+        let (security_version, signature) = parse_trailer(&self.trailer);
+        if !verify_image(&public_key, &digest, security_version, signature) {
+            error!("OTA image signature verification failed");
+            return Err(Error::InvalidSignature);
+        }
+        info!("OTA image signature verified");
+
+        let floor = SECURITY_FLOOR.load(Ordering::Relaxed);
+        if security_version < floor {
+            error!("OTA image security version {security_version} is below floor {floor}");
+            return Err(Error::SecurityVersionTooOld);
+        }
+
+        let slot = self.slot;
+        let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+        run_with_ota(&mut self.slot_writer.flash, &mut pt_mem, |ota| {
+            set_next_ota_slot(slot, ota)
+        })??;
+        info!("OTA image of {written} bytes written to {slot:?}");
+        if let Err(e) = reset_boot_attempts(db).await {
+            error!("Failed to reset OTA boot attempts: {e}");
+        }
+        Ok(slot)
+    }
+}
+
+impl BodySink for OtaWriter {
+    type Error = Error;
+
+    fn begin(&mut self, content_length: Option<usize>, offset: usize) -> Result<(), Self::Error> {
+        if offset != self.received() as usize {
+            if offset != 0 {
+                return Err(Error::InvalidLength);
+            }
+            info!("Server sent the whole image, starting over");
+            self.rewind();
+        }
+        match (content_length.map(|len| len + offset), self.body_len) {
+            (Some(len), None) => self.set_expected_len(len),
+            (None, Some(_)) => Ok(()),
+            (Some(len), Some(body_len)) if len == body_len as usize + TRAILER_LEN => Ok(()),
+            _ => Err(Error::InvalidLength),
+        }
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), Self::Error> {
+        OtaWriter::write(self, chunk).await
+    }
+}
+
+/// Download state kept under `ota.progress` so an interrupted HTTP update
+/// can continue with a `Range` request, even after a reboot
+struct OtaProgress {
+    url: String<128>,
+    /// Length of the signed image
+    len: u32,
+    /// Flash offset of the slot being written
+    offset: u32,
+    /// Image bytes in flash
+    written: u32,
+    /// Digest state over the `written` bytes
+    digest: [u8; DIGEST_STATE_LEN],
+}
+
+impl OtaProgress {
+    fn encode(&self, buf: &mut [u8; PROGRESS_HEADER_LEN + 128]) -> usize {
+        buf[0..4].copy_from_slice(&self.len.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.offset.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.written.to_le_bytes());
+        buf[12..PROGRESS_HEADER_LEN].copy_from_slice(&self.digest);
+        let end = PROGRESS_HEADER_LEN + self.url.len();
+        buf[PROGRESS_HEADER_LEN..end].copy_from_slice(self.url.as_bytes());
+        end
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() <= PROGRESS_HEADER_LEN {
+            return None;
+        }
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let mut digest = [0; DIGEST_STATE_LEN];
+        digest.copy_from_slice(&data[12..PROGRESS_HEADER_LEN]);
+        let url = core::str::from_utf8(&data[PROGRESS_HEADER_LEN..]).ok()?;
+        Some(Self {
+            url: String::try_from(url).ok()?,
+            len: word(0),
+            offset: word(4),
+            written: word(8),
+            digest,
+        })
+    }
+}
+
+/// Saved progress of a download from `url`, if there is any
+async fn load_progress(db: &'static DbMutex, url: &str) -> Result<Option<OtaProgress>, DbError> {
+    let mut buf = [0u8; PROGRESS_HEADER_LEN + 128];
+    let Some(n) = read_bytes_setting(db, PROGRESS_KEY, &mut buf).await? else {
+        return Ok(None);
+    };
+    Ok(OtaProgress::decode(&buf[..n]).filter(|progress| progress.url == url))
+}
+
+async fn save_progress(db: &'static DbMutex, progress: &OtaProgress) -> Result<(), DbError> {
+    let mut buf = [0u8; PROGRESS_HEADER_LEN + 128];
+    let n = progress.encode(&mut buf);
+    write_bytes_setting(db, PROGRESS_KEY, &buf[..n]).await
+}
+
+async fn clear_progress(db: &'static DbMutex) -> Result<(), DbError> {
+    write_bytes_setting(db, PROGRESS_KEY, &[]).await
+}
+
+/// Feeds an HTTP download into an [`OtaWriter`], saving its progress on the way
+struct ResumableDownload<'a> {
+    writer: &'a mut OtaWriter,
+    db: &'static DbMutex,
+    url: &'a String<128>,
+    saved: u32,
+}
+
+impl BodySink for ResumableDownload<'_> {
+    type Error = Error;
+
+    fn begin(&mut self, content_length: Option<usize>, offset: usize) -> Result<(), Self::Error> {
+        self.writer.begin(content_length, offset)?;
+        self.saved = self.writer.slot_writer.written;
+        Ok(())
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), Self::Error> {
+        self.writer.write(chunk).await?;
+        let Some((written, digest)) = self.writer.checkpoint() else {
+            return Ok(());
+        };
+        if written < self.saved + CHECKPOINT_INTERVAL {
+            return Ok(());
+        }
+        let progress = OtaProgress {
+            url: self.url.clone(),
+            len: self.writer.body_len.unwrap_or(0) + TRAILER_LEN as u32,
+            offset: self.writer.slot_writer.offset,
+            written,
+            digest,
+        };
+        match save_progress(self.db, &progress).await {
+            Ok(()) => self.saved = written,
+            Err(e) => error!("Failed to save OTA progress: {e}"),
+        }
+        Ok(())
+    }
+}
+
+/// Download the image of `request` into the inactive slot and select it for the next boot.
+/// A download of the same URL that was cut off earlier continues where it stopped.
+pub async fn update_from_url<const N: usize, const TX_SZ: usize, const RX_SZ: usize>(
+    http_client: &mut EmbassyHttpClient<'_, '_, N, TX_SZ, RX_SZ>,
+    db: &'static DbMutex,
+    request: &OtaRequest,
+) -> Result<Slot, Error> {
+    let mut writer = OtaWriter::new()?;
+    if let Some(size) = request.size {
+        writer.set_expected_len(size as usize)?;
+    }
+    if let Some(digest) = request.digest {
+        writer.set_expected_digest(digest);
+    }
+    match load_progress(db, &request.url).await {
+        Ok(Some(progress)) => match writer.resume(&progress) {
+            Ok(()) => info!("Resuming download at {} bytes", progress.written),
+            Err(e) => info!("Saved OTA progress does not apply: {e:?}"),
+        },
+        Ok(None) => {}
+        Err(e) => error!("Failed to read OTA progress: {e}"),
+    }
+
+    let offset = writer.received() as usize;
+    info!("Downloading firmware from {} at {offset}", request.url);
+    let mut download = ResumableDownload {
+        writer: &mut writer,
+        db,
+        url: &request.url,
+        saved: 0,
+    };
+    // Progress survives a failed transfer; anything after it starts over
+    http_client
+        .get_to_sink(&request.url, HTTP_TIMEOUT_S, offset, &mut download)
+        .await?;
+    let result = writer.finish(db).await;
+    if let Err(e) = clear_progress(db).await {
+        error!("Failed to clear OTA progress: {e}");
+    }
+    result
+}
+
+pub fn reboot() -> ! {
+    info!("Rebooting...");
+    esp_hal::system::software_reset()
+}
+
+#[task]
+pub async fn ota_task(
+    mut http_client: EmbassyHttpClient<'static, 'static, 3>,
+    db: &'static DbMutex,
+) {
+    loop {
+        let request = match select(OTA_REQUEST.wait(), REBOOT_REQUEST.wait()).await {
+            Either::First(request) => request,
+            Either::Second(()) => {
+                // Give the web server time to send the response
+                Timer::after(Duration::from_secs(1)).await;
+                reboot();
+            }
+        };
+        for attempt in 1..=DOWNLOAD_ATTEMPTS {
+            match update_from_url(&mut http_client, db, &request).await {
+                Ok(slot) => {
+                    info!("Firmware update to {slot:?} complete");
+                    Timer::after(Duration::from_secs(1)).await;
+                    reboot();
+                }
+                Err(Error::Http(e)) if attempt < DOWNLOAD_ATTEMPTS => {
+                    error!("Firmware download attempt {attempt} failed: {e:?}");
+                    Timer::after(Duration::from_secs(DOWNLOAD_RETRY_DELAY_S)).await;
+                }
+                Err(e) => {
+                    error!("Firmware update from {} failed: {e:?}", request.url);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Periodic update check against a JSON manifest:
+//!
+//! ```json
+//! {"version":"0.3.0","url":"http://host/firmware.signed.bin","size":1234567,
+//!  "sha256":"<64 hex chars>","rollout":25}
+//! ```
+//!
+//! `size` is the size of the signed file at `url`, `sha256` the digest of the
+//! app image without the signature and `rollout` the percentage of devices
+//! that should install it.
+
+use crate::config::{DbError, read_manifest_url};
+use crate::http::{self, EmbassyHttpClient};
+use crate::ota::{OTA_REQUEST, OtaRequest, rolled_back_version};
+use crate::ota_signature::{DIGEST_LEN, parse_hex};
+use crate::{DbMutex, FIRMWARE_UPGRADE_IN_PROGRESS};
+use core::sync::atomic::Ordering;
+use ekv::ReadError;
+use embassy_executor::task;
+use embassy_time::{Duration, Timer};
+use esp_hal::efuse::Efuse;
+use heapless::String;
+use log::{error, info};
+use serde::Deserialize;
+
+const HTTP_TIMEOUT_S: u64 = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    pub version: String<16>,
+    pub url: String<128>,
+    pub size: u32,
+    pub sha256: String<64>,
+    pub rollout: u8,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Error reading the manifest URL
+    Storage(DbError),
+
+    /// Error fetching the manifest
+    Http(http::Error),
+
+    /// Manifest is not valid JSON or misses fields
+    Json(serde_json_core::de::Error),
+
+    /// `sha256` is not 64 hex characters
+    InvalidDigest,
+}
+
+impl From<DbError> for Error {
+    fn from(error: DbError) -> Self {
+        Self::Storage(error)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(error: http::Error) -> Self {
+        Self::Http(error)
+    }
+}
+
+impl From<serde_json_core::de::Error> for Error {
+    fn from(error: serde_json_core::de::Error) -> Self {
+        Self::Json(error)
+    }
+}
+
+/// Parses `MAJOR.MINOR.PATCH`, ignoring a leading `v` and any pre-release or build suffix
+pub fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let patch = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+/// `true` only if both versions parse and `candidate` is strictly newer
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    match (parse_version(candidate), parse_version(current)) {
+        (Some(candidate), Some(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+/// `true` if both versions parse to the same release
+pub fn is_same_version(a: &str, b: &str) -> bool {
+    parse_version(a).is_some_and(|a| parse_version(b) == Some(a))
+}
+
+/// Stable bucket in `0..100` derived from the MAC. FNV-1a spreads the
+/// consecutive MACs of one production batch over all buckets.
+pub fn rollout_bucket(mac: &[u8; 6]) -> u8 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for &b in mac {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    (hash % 100) as u8
+}
+
+pub fn in_rollout(bucket: u8, rollout_percent: u8) -> bool {
+    bucket < rollout_percent
+}
+
+/// Returns the update to install, if the manifest offers one to this device
+async fn check_for_update(
+    http_client: &mut EmbassyHttpClient<'static, 'static, 3>,
+    db: &'static DbMutex,
+    bucket: u8,
+) -> Result<Option<OtaRequest>, Error> {
+    let url = match read_manifest_url(db).await {
+        Ok((_, url)) if !url.is_empty() => url,
+        Ok(_) | Err(DbError::Read(ReadError::KeyNotFound)) => {
+            info!("No OTA manifest URL configured");
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let body = http_client.get(&url, HTTP_TIMEOUT_S).await?;
+    let (manifest, _) = serde_json_core::from_slice::<Manifest>(&body)?;
+    let current = env!("CARGO_PKG_VERSION");
+    info!(
+        "Manifest offers {} at {}% rollout, running {}",
+        manifest.version, manifest.rollout, current
+    );
+
+    if !is_newer(&manifest.version, current) {
+        return Ok(None);
+    }
+    // An image that failed its health checks here is not tried again
+    let rolled_back = rolled_back_version(db).await?;
+    if rolled_back.is_some_and(|version| is_same_version(&manifest.version, &version)) {
+        info!("{} was rolled back before, skipping it", manifest.version);
+        return Ok(None);
+    }
+    if !in_rollout(bucket, manifest.rollout) {
+        info!("Bucket {} is outside the rollout", bucket);
+        return Ok(None);
+    }
+
+    let digest = parse_hex::<DIGEST_LEN>(&manifest.sha256).ok_or(Error::InvalidDigest)?;
+    Ok(Some(OtaRequest {
+        url: manifest.url,
+        size: Some(manifest.size),
+        digest: Some(digest),
+    }))
+}
+
+#[task]
+pub async fn update_check_task(
+    mut http_client: EmbassyHttpClient<'static, 'static, 3>,
+    db: &'static DbMutex,
+    period_ms: u64,
+) {
+    let bucket = rollout_bucket(&Efuse::read_base_mac_address());
+    info!("Update checker started, rollout bucket {}", bucket);
+
+    loop {
+        if !FIRMWARE_UPGRADE_IN_PROGRESS.load(Ordering::Acquire) {
+            match check_for_update(&mut http_client, db, bucket).await {
+                Ok(Some(request)) => {
+                    info!("Starting OTA from {}", request.url);
+                    OTA_REQUEST.signal(request);
+                }
+                Ok(None) => {}
+                Err(e) => error!("Update check failed: {e:?}"),
+            }
+        }
+        Timer::after(Duration::from_millis(period_ms)).await;
+    }
+}
@@ -0,0 +1,342 @@
+//! Detached Ed25519 signatures of OTA images.
+//!
+//! A signed image is the plain app image followed by a trailer of the
+//! little-endian `u32` security version and a 64-byte Ed25519 signature over
+//! the SHA-256 digest of the app image concatenated with that security version.
+
+use ed25519_compact::{PublicKey, Signature};
+use sha2::compress256;
+use sha2::digest::generic_array::GenericArray;
+
+pub const SIGNATURE_LEN: usize = 64;
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const DIGEST_LEN: usize = 32;
+/// Security version followed by the signature
+pub const TRAILER_LEN: usize = 4 + SIGNATURE_LEN;
+
+/// Anti-rollback version of this build, from `SECURITY_VERSION` (decimal, default 0)
+pub const SECURITY_VERSION: u32 = parse_u32(option_env!("SECURITY_VERSION"));
+
+/// Verification key baked in at build time from `OTA_PUBLIC_KEY` (64 hex characters)
+pub const OTA_PUBLIC_KEY: Option<[u8; PUBLIC_KEY_LEN]> =
+    parse_hex_key(option_env!("OTA_PUBLIC_KEY"));
+
+const fn hex_nibble(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+const fn parse_u32(dec: Option<&str>) -> u32 {
+    let Some(dec) = dec else {
+        return 0;
+    };
+    let bytes = dec.as_bytes();
+    let mut value: u32 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii_digit(),
+            "SECURITY_VERSION must be decimal"
+        );
+        value = value * 10 + (bytes[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+/// Decodes exactly `N` bytes of hex
+pub const fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let bytes = hex.as_bytes();
+    if bytes.len() != N * 2 {
+        return None;
+    }
+
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < N {
+        let (Some(hi), Some(lo)) = (hex_nibble(bytes[2 * i]), hex_nibble(bytes[2 * i + 1])) else {
+            return None;
+        };
+        out[i] = hi << 4 | lo;
+        i += 1;
+    }
+    Some(out)
+}
+
+const fn parse_hex_key(hex: Option<&str>) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    match hex {
+        Some(hex) => parse_hex(hex),
+        None => None,
+    }
+}
+
+const SHA256_INIT: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+const BLOCK_LEN: usize = 64;
+
+/// Serialized [`ImageDigest`]: the eight state words and the hashed length
+pub const DIGEST_STATE_LEN: usize = 8 * 4 + 8;
+
+/// Running SHA-256 over the app image as it is written. Its state can be
+/// saved on a block boundary so an interrupted download can carry on.
+pub struct ImageDigest {
+    state: [u32; 8],
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    len: u64,
+}
+
+impl Default for ImageDigest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageDigest {
+    pub fn new() -> Self {
+        Self {
+            state: SHA256_INIT,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let n = (BLOCK_LEN - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len == BLOCK_LEN {
+                self.compress();
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; DIGEST_LEN] {
+        let bit_len = self.len * 8;
+        self.block[self.block_len] = 0x80;
+        self.block_len += 1;
+        if self.block_len > BLOCK_LEN - 8 {
+            self.block[self.block_len..].fill(0);
+            self.compress();
+        }
+        self.block[self.block_len..BLOCK_LEN - 8].fill(0);
+        self.block[BLOCK_LEN - 8..].copy_from_slice(&bit_len.to_be_bytes());
+        self.compress();
+
+        let mut digest = [0; DIGEST_LEN];
+        for (out, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// State to resume from, only available between blocks
+    pub fn save(&self) -> Option<[u8; DIGEST_STATE_LEN]> {
+        if self.block_len != 0 {
+            return None;
+        }
+        let mut saved = [0; DIGEST_STATE_LEN];
+        for (out, word) in saved.chunks_exact_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_le_bytes());
+        }
+        saved[32..].copy_from_slice(&self.len.to_le_bytes());
+        Some(saved)
+    }
+
+    pub fn restore(saved: &[u8; DIGEST_STATE_LEN]) -> Self {
+        let mut digest = Self::new();
+        for (word, bytes) in digest.state.iter_mut().zip(saved.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let mut len = [0; 8];
+        len.copy_from_slice(&saved[32..]);
+        digest.len = u64::from_le_bytes(len);
+        digest
+    }
+
+    fn compress(&mut self) {
+        compress256(
+            &mut self.state,
+            core::slice::from_ref(GenericArray::from_slice(&self.block)),
+        );
+        self.block_len = 0;
+    }
+}
+
+/// Splits a trailer into security version and signature
+pub fn parse_trailer(trailer: &[u8; TRAILER_LEN]) -> (u32, &[u8]) {
+    let (version, signature) = trailer.split_at(4);
+    let version = u32::from_le_bytes([version[0], version[1], version[2], version[3]]);
+    (version, signature)
+}
+
+/// Checks `signature` over the image `digest` and `security_version` against `public_key`
+pub fn verify_image(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    digest: &[u8; DIGEST_LEN],
+    security_version: u32,
+    signature: &[u8],
+) -> bool {
+    let Ok(public_key) = PublicKey::from_slice(public_key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    let mut message = [0u8; DIGEST_LEN + 4];
+    message[..DIGEST_LEN].copy_from_slice(digest);
+    message[DIGEST_LEN..].copy_from_slice(&security_version.to_le_bytes());
+    public_key.verify(message, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    /// Signed by the key with seed 00 01 .. 1f over the image below and security version 3
+    const PUBLIC_KEY: &str = "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+    const OTHER_PUBLIC_KEY: &str =
+        "2152f8d19b791d24453242e15f2eab6cb7cffa7b6a5ed30097960e069881db12";
+    const SIGNATURE: &str = "06eff41fcf4ddb47e98ccbb5320294906f07e8592fb0baf4a48bf1ef19ca02a5\
+                             2f5998e930f4338bbf3d4c54ed1dc3206234a2a811d2ff9318aa42078d1c3405";
+    const IMAGE_DIGEST: &str = "c80e08c74aa86b57e5c76587f4234dcdd40dedd34a7dcf61d1a760b3edadd25b";
+    const SECURITY_VERSION: u32 = 3;
+
+    /// App image magic, then filler
+    fn image() -> Vec<u8> {
+        let mut image = vec![0xE9];
+        image.extend((1..1000u32).map(|i| (i * 7 + 3) as u8));
+        image
+    }
+
+    fn digest_of(data: &[u8]) -> [u8; DIGEST_LEN] {
+        let mut digest = ImageDigest::new();
+        digest.update(data);
+        digest.finalize()
+    }
+
+    fn trailer(security_version: u32) -> [u8; TRAILER_LEN] {
+        let mut trailer = [0; TRAILER_LEN];
+        trailer[..4].copy_from_slice(&security_version.to_le_bytes());
+        trailer[4..].copy_from_slice(&parse_hex::<SIGNATURE_LEN>(SIGNATURE).unwrap());
+        trailer
+    }
+
+    fn verify(image: &[u8], trailer: &[u8; TRAILER_LEN], key: &str) -> bool {
+        let (security_version, signature) = parse_trailer(trailer);
+        let key = parse_hex(key).unwrap();
+        verify_image(&key, &digest_of(image), security_version, signature)
+    }
+
+    #[test]
+    fn image_digest_matches_known_vector() {
+        assert_eq!(digest_of(&image()), parse_hex(IMAGE_DIGEST).unwrap());
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        assert!(verify(&image(), &trailer(SECURITY_VERSION), PUBLIC_KEY));
+    }
+
+    #[test]
+    fn rejects_tampered_image() {
+        let mut flipped = image();
+        flipped[500] ^= 0x01;
+        assert!(!verify(&flipped, &trailer(SECURITY_VERSION), PUBLIC_KEY));
+        let mut longer = image();
+        longer.push(0);
+        assert!(!verify(&longer, &trailer(SECURITY_VERSION), PUBLIC_KEY));
+    }
+
+    #[test]
+    fn rejects_tampered_security_version() {
+        assert!(!verify(
+            &image(),
+            &trailer(SECURITY_VERSION + 1),
+            PUBLIC_KEY
+        ));
+        assert!(!verify(&image(), &trailer(0), PUBLIC_KEY));
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let mut trailer = trailer(SECURITY_VERSION);
+        trailer[TRAILER_LEN - 1] ^= 0x80;
+        assert!(!verify(&image(), &trailer, PUBLIC_KEY));
+    }
+
+    #[test]
+    fn rejects_other_key() {
+        assert!(!verify(
+            &image(),
+            &trailer(SECURITY_VERSION),
+            OTHER_PUBLIC_KEY
+        ));
+        assert!(!verify_image(
+            &[0; PUBLIC_KEY_LEN],
+            &parse_hex(IMAGE_DIGEST).unwrap(),
+            SECURITY_VERSION,
+            &trailer(SECURITY_VERSION)[4..],
+        ));
+    }
+
+    #[test]
+    fn digest_matches_sha256_for_all_padding_cases() {
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 31 + 7) as u8).collect();
+        for len in [0, 1, 55, 56, 63, 64, 65, 119, 120, 128, 300] {
+            let expected: [u8; DIGEST_LEN] = Sha256::digest(&data[..len]).into();
+            assert_eq!(digest_of(&data[..len]), expected, "length {len}");
+        }
+    }
+
+    #[test]
+    fn digest_in_pieces_matches_one_shot() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i ^ (i >> 3)) as u8).collect();
+        let expected: [u8; DIGEST_LEN] = Sha256::digest(&data).into();
+        for piece in [1, 7, 63, 64, 65, 333] {
+            let mut digest = ImageDigest::new();
+            data.chunks(piece).for_each(|chunk| digest.update(chunk));
+            assert_eq!(digest.finalize(), expected, "pieces of {piece}");
+        }
+    }
+
+    #[test]
+    fn saved_state_resumes_to_one_shot_digest() {
+        let data: Vec<u8> = (0..4096u32 + 100).map(|i| (i * 13) as u8).collect();
+        let expected: [u8; DIGEST_LEN] = Sha256::digest(&data).into();
+        for split in [64, 1024, 4096] {
+            let mut digest = ImageDigest::new();
+            digest.update(&data[..split]);
+            let saved = digest.save().expect("block boundary");
+            let mut resumed = ImageDigest::restore(&saved);
+            resumed.update(&data[split..]);
+            assert_eq!(resumed.finalize(), expected, "split at {split}");
+        }
+    }
+
+    #[test]
+    fn state_is_only_saved_between_blocks() {
+        let mut digest = ImageDigest::new();
+        digest.update(&[0; 100]);
+        assert!(digest.save().is_none());
+        digest.update(&[0; 28]);
+        assert!(digest.save().is_some());
+    }
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(parse_hex::<2>("0aFf"), Some([0x0a, 0xff]));
+        assert_eq!(parse_hex::<2>("0aF"), None);
+        assert_eq!(parse_hex::<2>("0aFg"), None);
+    }
+}
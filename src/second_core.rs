@@ -1,5 +1,6 @@
 use crate::neopixel::NeoPixel;
-use crate::{FIRMWARE_UPGRADE_IN_PROGRESS, WIFI_INITIALIZED, WIFI_MODE_CLIENT, try_log};
+use crate::wifi::WIFI_STATUS;
+use crate::{FIRMWARE_UPGRADE_IN_PROGRESS, try_log};
 use core::sync::atomic::Ordering;
 use embassy_executor::task;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
@@ -40,21 +41,23 @@ pub async fn control_led(
         brightness = if control.wait().await { 2 } else { 1 };
 
         // system-state → colour mapping
+        let wifi = WIFI_STATUS.try_get();
+        let wifi = wifi.as_ref();
         match (
             FIRMWARE_UPGRADE_IN_PROGRESS.load(Ordering::Acquire),
-            WIFI_INITIALIZED.load(Ordering::Acquire),
-            WIFI_MODE_CLIENT.load(Ordering::Acquire),
+            wifi.is_some_and(|status| status.ip.is_some()),
+            wifi.is_some_and(|status| status.ap_up),
         ) {
             (true, _, _) => {
                 // dark-orange : firmware upgrade
                 (r, g, b) = (255, 140, 0);
             }
-            (false, true, true) => {
-                // green : Wi-Fi client connected
+            (false, true, _) => {
+                // green : Wi-Fi client connected with an address
                 (r, g, b) = (0, 255, 0);
             }
-            (false, true, false) => {
-                // blue : AP mode
+            (false, false, true) => {
+                // blue : AP up, in AP mode or as the fallback
                 (r, g, b) = (0, 0, 255);
             }
             _ => {
@@ -0,0 +1,263 @@
+//! SNTP (RFC 4330) client packets: the request and what is needed of the
+//! server's answer to set the clock. No I/O here; `run_sntp` in `wifi.rs`
+//! does the exchange and `clock.rs` keeps the result.
+
+pub const NTP_PORT: u16 = 123;
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const NTP_UNIX_OFFSET_S: u64 = 2_208_988_800;
+/// NTP seconds below this are taken to be in era 1, after the 2036 rollover
+const ERA_PIVOT_S: u64 = 1 << 31;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+/// Leap indicator of a server whose clock is not synchronized
+const LEAP_ALARM: u8 = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum SntpError {
+    /// Shorter than an NTP header
+    Truncated,
+
+    /// Not a server answer of a version we speak
+    NotAResponse,
+
+    /// Stratum 0: the server tells us to go away or try later
+    KissOfDeath,
+
+    /// The server has no usable time itself
+    Unsynchronized,
+
+    /// Not an answer to our last request
+    Mismatch,
+}
+
+/// Client request. Its transmit timestamp carries `cookie`, which the server
+/// echoes as the originate timestamp; SNTP clients may put anything there.
+pub fn request(cookie: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    packet[0] = VERSION << 3 | MODE_CLIENT;
+    packet[40..].copy_from_slice(&cookie.to_be_bytes());
+    packet
+}
+
+/// Server answer, timestamps in microseconds since the Unix epoch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Response {
+    pub stratum: u8,
+    /// When the server got the request
+    pub receive_us: u64,
+    /// When the server sent the answer
+    pub transmit_us: u64,
+}
+
+impl Response {
+    /// Checks `packet` answers the request sent with `cookie`
+    pub fn parse(packet: &[u8], cookie: u64) -> Result<Self, SntpError> {
+        let packet: &[u8; PACKET_LEN] = packet
+            .get(..PACKET_LEN)
+            .and_then(|header| header.try_into().ok())
+            .ok_or(SntpError::Truncated)?;
+        let leap = packet[0] >> 6;
+        let version = packet[0] >> 3 & 0x07;
+        let mode = packet[0] & 0x07;
+        let stratum = packet[1];
+        if mode != MODE_SERVER || !(1..=VERSION).contains(&version) {
+            return Err(SntpError::NotAResponse);
+        }
+        if stratum == 0 {
+            return Err(SntpError::KissOfDeath);
+        }
+        if leap == LEAP_ALARM || stratum > 15 {
+            return Err(SntpError::Unsynchronized);
+        }
+        if timestamp(packet, 24) != cookie {
+            return Err(SntpError::Mismatch);
+        }
+        let transmit = timestamp(packet, 40);
+        if transmit == 0 {
+            return Err(SntpError::Unsynchronized);
+        }
+        Ok(Self {
+            stratum,
+            receive_us: unix_micros(timestamp(packet, 32)),
+            transmit_us: unix_micros(transmit),
+        })
+    }
+
+    /// What to add to the local clock to get Unix time, given the local times
+    /// the request went out and the answer came in (RFC 4330 5)
+    pub fn clock_offset_us(&self, sent_us: u64, received_us: u64) -> i64 {
+        let to_server = self.receive_us as i64 - sent_us as i64;
+        let from_server = self.transmit_us as i64 - received_us as i64;
+        (to_server + from_server) / 2
+    }
+
+    /// Time on the wire, without the server's processing time
+    pub fn round_trip_us(&self, sent_us: u64, received_us: u64) -> u64 {
+        let total = received_us.saturating_sub(sent_us);
+        total.saturating_sub(self.transmit_us.saturating_sub(self.receive_us))
+    }
+}
+
+fn timestamp(packet: &[u8; PACKET_LEN], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&packet[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// NTP timestamp (seconds and 2^-32 fractions since 1900) to Unix microseconds
+pub fn unix_micros(timestamp: u64) -> u64 {
+    let mut seconds = timestamp >> 32;
+    if seconds < ERA_PIVOT_S {
+        seconds += 1 << 32;
+    }
+    let micros = ((timestamp & 0xFFFF_FFFF) * 1_000_000) >> 32;
+    seconds.saturating_sub(NTP_UNIX_OFFSET_S) * 1_000_000 + micros
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIE: u64 = 0x0123_4567_89ab_cdef;
+    /// 2024-01-01T00:00:00Z
+    const NTP_2024: u64 = 3_913_056_000 << 32;
+    const UNIX_2024_US: u64 = 1_704_067_200_000_000;
+
+    fn response(first: u8, stratum: u8, originate: u64, receive: u64, transmit: u64) -> Vec<u8> {
+        let mut packet = vec![0u8; PACKET_LEN];
+        packet[0] = first;
+        packet[1] = stratum;
+        packet[24..32].copy_from_slice(&originate.to_be_bytes());
+        packet[32..40].copy_from_slice(&receive.to_be_bytes());
+        packet[40..48].copy_from_slice(&transmit.to_be_bytes());
+        packet
+    }
+
+    /// Version 4 server answer with leap indicator 0
+    fn answer(originate: u64, receive: u64, transmit: u64) -> Vec<u8> {
+        response(0x24, 2, originate, receive, transmit)
+    }
+
+    #[test]
+    fn request_is_a_v4_client_packet_with_the_cookie() {
+        let packet = request(COOKIE);
+        assert_eq!(packet[0], 0x23);
+        assert!(packet[1..40].iter().all(|&byte| byte == 0));
+        assert_eq!(packet[40..], COOKIE.to_be_bytes());
+    }
+
+    #[test]
+    fn parses_answer() {
+        let packet = answer(COOKIE, NTP_2024 | 0x8000_0000, NTP_2024 | 0xC000_0000);
+        assert_eq!(
+            Response::parse(&packet, COOKIE),
+            Ok(Response {
+                stratum: 2,
+                receive_us: UNIX_2024_US + 500_000,
+                transmit_us: UNIX_2024_US + 750_000,
+            })
+        );
+        // Extensions or a MAC after the header are ignored
+        let mut longer = packet.clone();
+        longer.extend_from_slice(&[0; 20]);
+        assert!(Response::parse(&longer, COOKIE).is_ok());
+    }
+
+    #[test]
+    fn accepts_older_versions() {
+        for version in 1..=4 {
+            let packet = response(version << 3 | MODE_SERVER, 1, COOKIE, NTP_2024, NTP_2024);
+            assert!(
+                Response::parse(&packet, COOKIE).is_ok(),
+                "version {version}"
+            );
+        }
+        let packet = response(5 << 3 | MODE_SERVER, 1, COOKIE, NTP_2024, NTP_2024);
+        assert_eq!(
+            Response::parse(&packet, COOKIE),
+            Err(SntpError::NotAResponse)
+        );
+    }
+
+    #[test]
+    fn rejects_short_packet() {
+        let packet = answer(COOKIE, NTP_2024, NTP_2024);
+        assert_eq!(
+            Response::parse(&packet[..PACKET_LEN - 1], COOKIE),
+            Err(SntpError::Truncated)
+        );
+        assert_eq!(Response::parse(&[], COOKIE), Err(SntpError::Truncated));
+    }
+
+    #[test]
+    fn rejects_cookie_mismatch() {
+        let packet = answer(COOKIE ^ 1, NTP_2024, NTP_2024);
+        assert_eq!(Response::parse(&packet, COOKIE), Err(SntpError::Mismatch));
+        let packet = answer(0, NTP_2024, NTP_2024);
+        assert_eq!(Response::parse(&packet, COOKIE), Err(SntpError::Mismatch));
+    }
+
+    #[test]
+    fn rejects_zero_transmit_timestamp() {
+        let packet = answer(COOKIE, NTP_2024, 0);
+        assert_eq!(
+            Response::parse(&packet, COOKIE),
+            Err(SntpError::Unsynchronized)
+        );
+    }
+
+    #[test]
+    fn rejects_requests_kiss_of_death_and_unsynchronized_servers() {
+        let client = response(0x23, 2, COOKIE, NTP_2024, NTP_2024);
+        assert_eq!(
+            Response::parse(&client, COOKIE),
+            Err(SntpError::NotAResponse)
+        );
+        // Stratum 0 with "RATE" as the reference id
+        let mut kiss = response(0xE4, 0, COOKIE, 0, 0);
+        kiss[12..16].copy_from_slice(b"RATE");
+        assert_eq!(Response::parse(&kiss, COOKIE), Err(SntpError::KissOfDeath));
+        let alarm = response(0xE4, 2, COOKIE, NTP_2024, NTP_2024);
+        assert_eq!(
+            Response::parse(&alarm, COOKIE),
+            Err(SntpError::Unsynchronized)
+        );
+        let stratum_16 = response(0x24, 16, COOKIE, NTP_2024, NTP_2024);
+        assert_eq!(
+            Response::parse(&stratum_16, COOKIE),
+            Err(SntpError::Unsynchronized)
+        );
+    }
+
+    #[test]
+    fn converts_timestamps_across_the_2036_rollover() {
+        assert_eq!(unix_micros(NTP_2024), UNIX_2024_US);
+        assert_eq!(unix_micros(NTP_2024 | 0xFFFF_FFFF), UNIX_2024_US + 999_999);
+        // Era 1 starts at 2036-02-07T06:28:16Z
+        assert_eq!(unix_micros(0), 2_085_978_496_000_000);
+        assert_eq!(unix_micros(1 << 32), 2_085_978_497_000_000);
+        assert_eq!(
+            unix_micros(((ERA_PIVOT_S - 1) << 32) + (1 << 31)),
+            ((ERA_PIVOT_S - 1 + (1 << 32) - NTP_UNIX_OFFSET_S) * 1_000_000) + 500_000
+        );
+    }
+
+    #[test]
+    fn computes_offset_and_round_trip() {
+        let response = Response {
+            stratum: 1,
+            receive_us: UNIX_2024_US + 1_000,
+            transmit_us: UNIX_2024_US + 1_500,
+        };
+        // Sent at local 10_000, answered at local 12_500: 1 ms each way,
+        // 0.5 ms in the server
+        assert_eq!(response.round_trip_us(10_000, 12_500), 2_000);
+        let offset = response.clock_offset_us(10_000, 12_500);
+        assert_eq!(offset, UNIX_2024_US as i64 + 1_000 - 11_000);
+        assert_eq!(response.round_trip_us(12_500, 10_000), 0);
+    }
+}
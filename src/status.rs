@@ -0,0 +1,69 @@
+//! Device status for `GET /api/status`: build, uptime, resources, Wi-Fi and
+//! the global flags, in one JSON object for monitoring scripts and the dashboard.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_time::Instant;
+use esp_hal::rtc_cntl::reset_reason;
+use esp_hal::system::Cpu;
+use heapless::String;
+use serde::Serialize;
+
+use crate::wifi::{WIFI_STATUS, WifiMode};
+use crate::{FIRMWARE_UPGRADE_IN_PROGRESS, TIME_SYNCED, clock};
+
+/// Set once at boot, both only known to `main`
+static CPU_MHZ: AtomicU32 = AtomicU32::new(0);
+static FLASH_SIZE: AtomicU32 = AtomicU32::new(0);
+
+pub fn record_hardware(cpu_mhz: u32, flash_size: u32) {
+    CPU_MHZ.store(cpu_mhz, Ordering::Relaxed);
+    FLASH_SIZE.store(flash_size, Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+pub struct SystemStatus {
+    version: &'static str,
+    uptime_s: u64,
+    /// Why the chip last reset, as esp-hal names it
+    reset_reason: String<32>,
+    cpu_mhz: u32,
+    heap_free: usize,
+    heap_used: usize,
+    flash_size: u32,
+    wifi_mode: Option<WifiMode>,
+    ip: Option<String<15>>,
+    rssi: Option<i32>,
+    /// Seconds since the Unix epoch, once SNTP set the clock
+    time: Option<u64>,
+    firmware_upgrade_in_progress: bool,
+    time_synced: bool,
+}
+
+pub fn system_status() -> SystemStatus {
+    let mut reason = String::new();
+    match reset_reason(Cpu::ProCpu) {
+        Some(cause) => {
+            let _ = write!(reason, "{cause:?}");
+        }
+        None => {
+            let _ = reason.push_str("Unknown");
+        }
+    }
+    let wifi = WIFI_STATUS.try_get();
+    SystemStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_s: Instant::now().as_secs(),
+        reset_reason: reason,
+        cpu_mhz: CPU_MHZ.load(Ordering::Relaxed),
+        heap_free: esp_alloc::HEAP.free(),
+        heap_used: esp_alloc::HEAP.used(),
+        flash_size: FLASH_SIZE.load(Ordering::Relaxed),
+        wifi_mode: wifi.as_ref().map(|wifi| wifi.mode),
+        rssi: wifi.as_ref().and_then(|wifi| wifi.rssi),
+        ip: wifi.and_then(|wifi| wifi.ip),
+        time: clock::now_utc(),
+        firmware_upgrade_in_progress: FIRMWARE_UPGRADE_IN_PROGRESS.load(Ordering::Relaxed),
+        time_synced: TIME_SYNCED.load(Ordering::Relaxed),
+    }
+}
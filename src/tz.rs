@@ -0,0 +1,619 @@
+//! POSIX TZ strings such as `CET-1CEST,M3.5.0,M10.5.0/3`, and UTC to local
+//! time conversion with them. No zone database and no I/O here; the clock in
+//! `clock.rs` applies the zone from the settings.
+
+use core::fmt;
+use heapless::String;
+
+const DAY_S: i64 = 86_400;
+const HOUR_S: i32 = 3600;
+/// Rule times may go past a day to express e.g. "Saturday 24:00" (RFC 8536 3.3.1)
+const MAX_RULE_HOURS: u32 = 167;
+/// Offsets stay within a day
+const MAX_OFFSET_HOURS: u32 = 24;
+/// Changes at 02:00 local time unless the rule says otherwise
+const DEFAULT_RULE_TIME_S: i32 = 2 * HOUR_S;
+
+pub type ZoneName = String<8>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TzError {
+    /// Zone abbreviation missing, shorter than 3 letters or too long
+    Name,
+    /// Malformed or out of range UTC offset
+    Offset,
+    /// Malformed DST start or end rule
+    Rule,
+    /// Text left after a complete zone
+    Trailing,
+}
+
+impl TzError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TzError::Name => "Zone names must be 3-8 letters, or <+0330> style",
+            TzError::Offset => "Invalid UTC offset",
+            TzError::Rule => "Invalid DST rule",
+            TzError::Trailing => "Unexpected text after the zone",
+        }
+    }
+}
+
+impl fmt::Display for TzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Day a DST change falls on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleDate {
+    /// `Jn`: day 1-365, February 29 is never counted
+    Julian(u16),
+    /// `n`: day 0-365, February 29 counts in leap years
+    ZeroBased(u16),
+    /// `Mm.w.d`: weekday `d` (0 is Sunday) of week `w` of month `m`, week 5 is the last
+    MonthWeekDay { month: u8, week: u8, weekday: u8 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rule {
+    pub date: RuleDate,
+    /// Local time of the change in seconds after midnight, in the time in
+    /// effect before it. May be negative or past a day.
+    pub time_s: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dst {
+    pub name: ZoneName,
+    /// Seconds east of UTC
+    pub offset_s: i32,
+    pub start: Rule,
+    pub end: Rule,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeZone {
+    pub name: ZoneName,
+    /// Seconds east of UTC, the opposite sign of the TZ string
+    pub offset_s: i32,
+    pub dst: Option<Dst>,
+}
+
+/// Broken-down local time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalTime {
+    pub year: i32,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 is Sunday
+    pub weekday: u8,
+    /// Day of the year, 0 is January 1
+    pub yday: u16,
+    /// Seconds east of UTC in effect
+    pub offset_s: i32,
+    pub is_dst: bool,
+}
+
+/// ISO 8601 with the offset, like `2025-03-30T03:00:00+02:00`
+impl fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.offset_s < 0 { '-' } else { '+' };
+        let offset_min = self.offset_s.unsigned_abs() / 60;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{sign}{:02}:{:02}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            offset_min / 60,
+            offset_min % 60
+        )
+    }
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self {
+            name: String::try_from("UTC").unwrap_or_default(),
+            offset_s: 0,
+            dst: None,
+        }
+    }
+
+    /// Parses `std offset [dst [offset] [,start[/time],end[/time]]]`. A DST
+    /// zone without rules follows the US ones, like glibc does.
+    pub fn parse(tz: &str) -> Result<Self, TzError> {
+        let mut cursor = Cursor {
+            text: tz.trim().as_bytes(),
+            pos: 0,
+        };
+        let name = cursor.name()?;
+        let offset_s = -cursor.offset(MAX_OFFSET_HOURS).ok_or(TzError::Offset)?;
+        if cursor.at_end() {
+            return Ok(Self {
+                name,
+                offset_s,
+                dst: None,
+            });
+        }
+
+        let dst_name = cursor.name()?;
+        let dst_offset_s = match cursor.peek() {
+            None | Some(b',') => offset_s + HOUR_S,
+            Some(_) => -cursor.offset(MAX_OFFSET_HOURS).ok_or(TzError::Offset)?,
+        };
+        let (start, end) = if cursor.at_end() {
+            (
+                Rule {
+                    date: RuleDate::MonthWeekDay {
+                        month: 3,
+                        week: 2,
+                        weekday: 0,
+                    },
+                    time_s: DEFAULT_RULE_TIME_S,
+                },
+                Rule {
+                    date: RuleDate::MonthWeekDay {
+                        month: 11,
+                        week: 1,
+                        weekday: 0,
+                    },
+                    time_s: DEFAULT_RULE_TIME_S,
+                },
+            )
+        } else {
+            if !cursor.eat(b',') {
+                return Err(TzError::Rule);
+            }
+            let start = cursor.rule().ok_or(TzError::Rule)?;
+            if !cursor.eat(b',') {
+                return Err(TzError::Rule);
+            }
+            (start, cursor.rule().ok_or(TzError::Rule)?)
+        };
+        if !cursor.at_end() {
+            return Err(TzError::Trailing);
+        }
+        Ok(Self {
+            name,
+            offset_s,
+            dst: Some(Dst {
+                name: dst_name,
+                offset_s: dst_offset_s,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// UTC offset in effect at `utc_s` seconds since the epoch, and whether it is DST
+    pub fn offset_at(&self, utc_s: i64) -> (i32, bool) {
+        let Some(dst) = &self.dst else {
+            return (self.offset_s, false);
+        };
+        let year = civil_from_days((utc_s + self.offset_s as i64).div_euclid(DAY_S)).0;
+        // Start is given in standard time, end in DST
+        let start = dst.start.utc_s(year, self.offset_s);
+        let end = dst.end.utc_s(year, dst.offset_s);
+        let in_dst = if start < end {
+            (start..end).contains(&utc_s)
+        } else {
+            // Southern hemisphere: DST spans the new year
+            !(end..start).contains(&utc_s)
+        };
+        if in_dst {
+            (dst.offset_s, true)
+        } else {
+            (self.offset_s, false)
+        }
+    }
+
+    /// Abbreviation of the zone, `CET` or `CEST` for the example above
+    pub fn abbreviation(&self, is_dst: bool) -> &str {
+        match &self.dst {
+            Some(dst) if is_dst => &dst.name,
+            _ => &self.name,
+        }
+    }
+
+    pub fn to_local(&self, utc_s: i64) -> LocalTime {
+        let (offset_s, is_dst) = self.offset_at(utc_s);
+        let local_s = utc_s + offset_s as i64;
+        let days = local_s.div_euclid(DAY_S);
+        let seconds = local_s.rem_euclid(DAY_S) as u32;
+        let (year, month, day) = civil_from_days(days);
+        LocalTime {
+            year: year as i32,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+            weekday: weekday(days),
+            yday: (days - days_from_civil(year, 1, 1)) as u16,
+            offset_s,
+            is_dst,
+        }
+    }
+}
+
+impl Rule {
+    /// When the change happens in `year`, as UTC seconds since the epoch,
+    /// given the offset in effect before it
+    fn utc_s(&self, year: i64, offset_before_s: i32) -> i64 {
+        self.date.day(year) * DAY_S + self.time_s as i64 - offset_before_s as i64
+    }
+}
+
+impl RuleDate {
+    /// The day in `year`, as days since the epoch
+    fn day(&self, year: i64) -> i64 {
+        let jan1 = days_from_civil(year, 1, 1);
+        match *self {
+            RuleDate::Julian(n) => {
+                let skipped_leap_day = is_leap(year) && n >= 60;
+                jan1 + n as i64 - 1 + skipped_leap_day as i64
+            }
+            RuleDate::ZeroBased(n) => jan1 + n as i64,
+            RuleDate::MonthWeekDay {
+                month,
+                week,
+                weekday: wanted,
+            } => {
+                let first = days_from_civil(year, month, 1);
+                let last = first + days_in_month(year, month) as i64 - 1;
+                let first_match = first + (wanted as i64 - weekday(first) as i64).rem_euclid(7);
+                let mut day = first_match + 7 * (week as i64 - 1);
+                while day > last {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+}
+
+struct Cursor<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.text.len()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        self.pos += found as usize;
+        found
+    }
+
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &'a [u8] {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn number(&mut self, max: u32) -> Option<u32> {
+        let digits = self.take_while(|b| b.is_ascii_digit());
+        if digits.is_empty() || digits.len() > 3 {
+            return None;
+        }
+        let value = digits
+            .iter()
+            .fold(0, |value, digit| value * 10 + (digit - b'0') as u32);
+        (value <= max).then_some(value)
+    }
+
+    /// `CET`, or `<+0330>` for names with digits or signs
+    fn name(&mut self) -> Result<ZoneName, TzError> {
+        let name = if self.eat(b'<') {
+            let name = self.take_while(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'-');
+            let name = core::str::from_utf8(name).map_err(|_| TzError::Name)?;
+            if !self.eat(b'>') {
+                return Err(TzError::Name);
+            }
+            name
+        } else {
+            core::str::from_utf8(self.take_while(|b| b.is_ascii_alphabetic()))
+                .map_err(|_| TzError::Name)?
+        };
+        if name.len() < 3 {
+            return Err(TzError::Name);
+        }
+        String::try_from(name).map_err(|_| TzError::Name)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds, positive west of Greenwich as POSIX has it
+    fn offset(&mut self, max_hours: u32) -> Option<i32> {
+        let negative = self.eat(b'-');
+        if !negative {
+            self.eat(b'+');
+        }
+        let mut seconds = self.number(max_hours)? * 3600;
+        if self.eat(b':') {
+            seconds += self.number(59)? * 60;
+            if self.eat(b':') {
+                seconds += self.number(59)?;
+            }
+        }
+        let seconds = seconds as i32;
+        Some(if negative { -seconds } else { seconds })
+    }
+
+    /// `Jn`, `n` or `Mm.w.d`, optionally followed by `/time`
+    fn rule(&mut self) -> Option<Rule> {
+        let date = if self.eat(b'J') {
+            RuleDate::Julian(self.number(365).filter(|&n| n >= 1)? as u16)
+        } else if self.eat(b'M') {
+            let month = self.number(12).filter(|&m| m >= 1)? as u8;
+            self.eat(b'.').then_some(())?;
+            let week = self.number(5).filter(|&w| w >= 1)? as u8;
+            self.eat(b'.').then_some(())?;
+            let weekday = self.number(6)? as u8;
+            RuleDate::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            }
+        } else {
+            RuleDate::ZeroBased(self.number(365)? as u16)
+        };
+        let time_s = if self.eat(b'/') {
+            self.offset(MAX_RULE_HOURS)?
+        } else {
+            DEFAULT_RULE_TIME_S
+        };
+        Some(Rule { date, time_s })
+    }
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 0 is Sunday; the epoch was a Thursday
+fn weekday(days: i64) -> u8 {
+    (days + 4).rem_euclid(7) as u8
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of [`days_from_civil`]
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CET: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+    const AEST: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+
+    fn local(tz: &str, utc_s: i64) -> std::string::String {
+        TimeZone::parse(tz).unwrap().to_local(utc_s).to_string()
+    }
+
+    /// Local times just before and at a change
+    fn change(tz: &str, utc_s: i64) -> (std::string::String, std::string::String) {
+        (local(tz, utc_s - 1), local(tz, utc_s))
+    }
+
+    #[test]
+    fn parses_cet() {
+        let zone = TimeZone::parse(CET).unwrap();
+        assert_eq!(zone.name, "CET");
+        assert_eq!(zone.offset_s, 3600);
+        let dst = zone.dst.unwrap();
+        assert_eq!(dst.name, "CEST");
+        assert_eq!(dst.offset_s, 7200);
+        assert_eq!(
+            dst.start,
+            Rule {
+                date: RuleDate::MonthWeekDay {
+                    month: 3,
+                    week: 5,
+                    weekday: 0
+                },
+                time_s: 7200,
+            }
+        );
+        assert_eq!(dst.end.time_s, 3 * 3600);
+    }
+
+    #[test]
+    fn cet_transitions() {
+        // 2025-03-30 02:00 CET becomes 03:00 CEST
+        assert_eq!(
+            change(CET, 1_743_296_400),
+            (
+                "2025-03-30T01:59:59+01:00".into(),
+                "2025-03-30T03:00:00+02:00".into()
+            )
+        );
+        // 2025-10-26 03:00 CEST becomes 02:00 CET
+        assert_eq!(
+            change(CET, 1_761_440_400),
+            (
+                "2025-10-26T02:59:59+02:00".into(),
+                "2025-10-26T02:00:00+01:00".into()
+            )
+        );
+        let zone = TimeZone::parse(CET).unwrap();
+        assert_eq!(zone.abbreviation(zone.offset_at(1_743_296_400).1), "CEST");
+        assert_eq!(zone.abbreviation(zone.offset_at(1_761_440_400).1), "CET");
+    }
+
+    #[test]
+    fn aest_transitions_span_the_new_year() {
+        // 2025-04-06 03:00 AEDT becomes 02:00 AEST
+        assert_eq!(
+            change(AEST, 1_743_868_800),
+            (
+                "2025-04-06T02:59:59+11:00".into(),
+                "2025-04-06T02:00:00+10:00".into()
+            )
+        );
+        // 2025-10-05 02:00 AEST becomes 03:00 AEDT
+        assert_eq!(
+            change(AEST, 1_759_593_600),
+            (
+                "2025-10-05T01:59:59+10:00".into(),
+                "2025-10-05T03:00:00+11:00".into()
+            )
+        );
+        let zone = TimeZone::parse(AEST).unwrap();
+        // Midsummer on both sides of the new year
+        assert_eq!(zone.offset_at(1_735_689_600), (11 * 3600, true));
+        assert_eq!(zone.offset_at(1_735_689_600 - 86_400), (11 * 3600, true));
+        assert_eq!(zone.offset_at(1_751_328_000), (10 * 3600, false));
+    }
+
+    #[test]
+    fn est5edt_follows_us_rules() {
+        let zone = TimeZone::parse("EST5EDT").unwrap();
+        assert_eq!(zone.offset_s, -5 * 3600);
+        assert_eq!(zone.dst.as_ref().unwrap().offset_s, -4 * 3600);
+        // 2025-03-09 02:00 EST becomes 03:00 EDT
+        assert_eq!(
+            change("EST5EDT", 1_741_503_600),
+            (
+                "2025-03-09T01:59:59-05:00".into(),
+                "2025-03-09T03:00:00-04:00".into()
+            )
+        );
+        // 2025-11-02 02:00 EDT becomes 01:00 EST
+        assert_eq!(
+            change("EST5EDT", 1_762_063_200),
+            (
+                "2025-11-02T01:59:59-04:00".into(),
+                "2025-11-02T01:00:00-05:00".into()
+            )
+        );
+        assert_eq!(TimeZone::parse("EST5EDT,M3.2.0,M11.1.0").unwrap(), zone);
+    }
+
+    #[test]
+    fn parses_zones_without_dst() {
+        let utc = TimeZone::parse("UTC0").unwrap();
+        assert_eq!(utc, TimeZone::utc());
+        assert_eq!(local("UTC0", 0), "1970-01-01T00:00:00+00:00");
+        assert_eq!(local("UTC0", -1), "1969-12-31T23:59:59+00:00");
+
+        let ist = TimeZone::parse("IST-5:30").unwrap();
+        assert_eq!(ist.name, "IST");
+        assert_eq!(ist.offset_s, 5 * 3600 + 30 * 60);
+        assert_eq!(ist.dst, None);
+        assert_eq!(
+            local("IST-5:30", 1_709_208_000),
+            "2024-02-29T17:30:00+05:30"
+        );
+
+        let iran = TimeZone::parse("<+0330>-3:30").unwrap();
+        assert_eq!(iran.name, "+0330");
+        assert_eq!(iran.offset_s, 3 * 3600 + 30 * 60);
+        assert_eq!(iran.abbreviation(false), "+0330");
+
+        let west = TimeZone::parse("<-03>3").unwrap();
+        assert_eq!((west.name.as_str(), west.offset_s), ("-03", -3 * 3600));
+        let seconds = TimeZone::parse("LMT+0:01:15").unwrap();
+        assert_eq!(seconds.offset_s, -75);
+    }
+
+    #[test]
+    fn parses_julian_and_zero_based_rules() {
+        let zone = TimeZone::parse("AAA3BBB,J60/0,300").unwrap();
+        let dst = zone.dst.unwrap();
+        assert_eq!(dst.start.date, RuleDate::Julian(60));
+        assert_eq!(dst.start.time_s, 0);
+        assert_eq!(dst.end.date, RuleDate::ZeroBased(300));
+        // J60 is March 1 whether or not there is a February 29
+        assert_eq!(dst.start.date.day(2024), days_from_civil(2024, 3, 1));
+        assert_eq!(dst.start.date.day(2025), days_from_civil(2025, 3, 1));
+        assert_eq!(
+            RuleDate::ZeroBased(59).day(2024),
+            days_from_civil(2024, 2, 29)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_zones() {
+        for (tz, error) in [
+            ("", TzError::Name),
+            ("UT0", TzError::Name),
+            ("TOOLONGNAME0", TzError::Name),
+            ("<+0330-3:30", TzError::Name),
+            ("CET", TzError::Offset),
+            ("CET-25", TzError::Offset),
+            ("CET-1:60", TzError::Offset),
+            ("CET-1CEST,M3.5.0", TzError::Rule),
+            ("CET-1CEST,M13.5.0,M10.5.0", TzError::Rule),
+            ("CET-1CEST,M3.6.0,M10.5.0", TzError::Rule),
+            ("CET-1CEST,M3.5.7,M10.5.0", TzError::Rule),
+            ("CET-1CEST,J0,M10.5.0", TzError::Rule),
+            ("CET-1CEST;M3.5.0,M10.5.0", TzError::Offset),
+            ("CET-1CEST-2;M3.5.0,M10.5.0", TzError::Rule),
+            ("CET-1CEST,M3.5.0,M10.5.0/3x", TzError::Trailing),
+        ] {
+            assert_eq!(TimeZone::parse(tz), Err(error), "{tz}");
+        }
+    }
+
+    #[test]
+    fn converts_civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        for days in [-719_468, -1, 0, 11_016, 19_782, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        let time = TimeZone::utc().to_local(1_709_208_000);
+        assert_eq!((time.weekday, time.yday), (4, 59));
+    }
+}
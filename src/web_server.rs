@@ -1,21 +1,52 @@
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
 use heapless::String;
 
-use crate::DbMutex;
-use crate::config::{WifiSettings, update_wifi_settings};
+use crate::auth::{self, Credentials, PasswordHash, SALT_LEN, SESSION_LIFETIME, TOKEN_LEN};
+use crate::clock;
+use crate::config::{
+    ApSettings, ManifestSettings, NetworkOrder, NetworkSsid, NetworksError, NtpSettings,
+    ReservationError, ReservationSettings, SavedNetwork, SavedNetworks, TimeZoneSettings,
+    WifiSettings, add_dhcp_reservation, add_network, read_ap_config, read_dhcp_reservations,
+    read_networks, read_ntp_servers, read_time_zone, remove_dhcp_reservation, remove_network,
+    reorder_networks, update_manifest_url, update_wifi_settings, write_admin_password,
+    write_ap_config, write_ntp_servers, write_time_zone,
+};
+use crate::dhcp_leases::{MAX_RESERVATIONS, Reservations};
+use crate::ota::{self, OtaWriter, Slot};
+use crate::status;
+use crate::wifi::{self, WifiCommand, WifiMode};
+use crate::{DbMutex, WEB_REQUEST_SERVED};
+use embassy_net::tcp::TcpSocket;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::watch::Watch;
 use embassy_time::{Duration, Timer};
+use esp_hal::rng::Rng;
 use esp_hal::xtensa_lx::_export::critical_section;
-use log::{info, warn};
+use log::{error, info, warn};
 use picoserve::extract::Json;
-use picoserve::io::embedded_io_async;
+use picoserve::io::{Read, embedded_io_async};
+use picoserve::request::{Path, Request, RequestParts};
 use picoserve::response::sse;
 use picoserve::response::ws;
-use picoserve::routing::{get, get_service, post};
-use picoserve::{AppBuilder, AppRouter};
+use picoserve::response::{IntoResponse, Redirect, ResponseWriter, StatusCode};
+use picoserve::routing::{
+    Layer, Next, RequestHandlerService, get, get_service, post, post_service,
+};
+use picoserve::{AppBuilder, AppRouter, ResponseSent};
+use serde::{Deserialize, Serialize};
 use static_cell::StaticCell;
 
+pub const WEB_PORT: u16 = 80;
 pub const WEB_TASK_POOL_SIZE: usize = 6;
+/// Web tasks serving the access point, the others serve the station
+pub const AP_WEB_TASKS: usize = 2;
+const OTA_CHUNK_SIZE: usize = 512;
+const OTA_PROGRESS_STEP: u32 = 64 * 1024;
+/// Room for a `WifiStatus` as JSON, IPv6 addresses included
+const WIFI_STATUS_JSON_LEN: usize = 320;
+/// Answer delay after a wrong password, to slow down guessing
+const LOGIN_FAILURE_DELAY: Duration = Duration::from_secs(1);
 
 pub type MessageWatch = Watch<CriticalSectionRawMutex, String<128>, 1>;
 static SSE_MESSAGE_WATCH: StaticCell<MessageWatch> = StaticCell::new();
@@ -36,6 +67,37 @@ pub fn get_sse_watch_ref() -> &'static MessageWatch {
     critical_section::with(|_| unsafe { WATCH_REF.expect("Message watch not initialized") })
 }
 
+#[derive(Deserialize)]
+struct ModeRequest {
+    mode: WifiMode,
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    password: String<64>,
+}
+
+/// Answer of `POST /api/login`, the token goes in `Authorization: Bearer`
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String<{ TOKEN_LEN * 2 }>,
+    expires_in_s: u64,
+}
+
+/// Answer of `GET /api/time`, without times until SNTP set the clock
+#[derive(Serialize)]
+struct TimeStatus {
+    synced: bool,
+    /// Seconds since the Unix epoch
+    utc: Option<u64>,
+    /// ISO 8601 with the offset
+    local: Option<String<25>>,
+    /// Abbreviation in effect, like `CEST`
+    zone: String<8>,
+    dst: bool,
+    timezone: String<64>,
+}
+
 pub struct SseEvents {}
 
 impl SseEvents {
@@ -50,11 +112,77 @@ pub fn create_sse_events() -> SseEvents {
 
 pub struct AppProps {
     db: &'static DbMutex,
+    /// Salts and login tokens
+    rng: Rng,
 }
 
 impl AppProps {
-    pub fn new(db: &'static DbMutex) -> Self {
-        Self { db }
+    pub fn new(db: &'static DbMutex, rng: Rng) -> Self {
+        Self { db, rng }
+    }
+}
+
+/// Asks for the admin password, with HTTP Basic as user `admin` or as a token
+/// from `POST /api/login`, for everything but reads and the login itself.
+/// Until a password is set everything is open, so the device can be provisioned.
+struct AuthLayer;
+
+impl AuthLayer {
+    /// Writes, and the scan since it triggers the radio and lists the
+    /// neighbourhood. `Path` compares decoded, like the router matches, so
+    /// `/api/wifi/%73can` is no way around it.
+    fn needs_auth(method: &str, path: Path<'_>) -> bool {
+        if path == "/api/wifi/scan" {
+            return true;
+        }
+        !matches!(method, "GET" | "HEAD") && path != "/api/login"
+    }
+}
+
+impl<State, PathParameters> Layer<State, PathParameters> for AuthLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        if !auth::password_set() || !Self::needs_auth(request_parts.method(), request_parts.path())
+        {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+        let credentials = request_parts
+            .headers()
+            .get("Authorization")
+            .and_then(|header| Credentials::parse(header.as_raw()));
+        match credentials {
+            Some(credentials) if auth::is_authorized(&credentials) => {
+                return next.run(state, path_parameters, response_writer).await;
+            }
+            // Same delay as `/api/login`, or Basic would be the faster way to guess
+            Some(_) => Timer::after(LOGIN_FAILURE_DELAY).await,
+            None => {}
+        }
+
+        warn!(
+            "Unauthorized {} {}",
+            request_parts.method(),
+            request_parts.path().encoded()
+        );
+        let connection = next.into_request().body_connection.finalize().await?;
+        picoserve::response::Response::new(StatusCode::UNAUTHORIZED, "Login required\r\n")
+            .with_header("WWW-Authenticate", "Bearer realm=\"esp32\"")
+            .write_to(connection, response_writer)
+            .await
     }
 }
 
@@ -63,6 +191,7 @@ impl AppBuilder for AppProps {
 
     fn build_app(self) -> picoserve::Router<Self::PathRouter> {
         let db = self.db;
+        let rng = self.rng;
 
         picoserve::Router::new()
             .route(
@@ -90,6 +219,11 @@ impl AppBuilder for AppProps {
                     "http/index.js"
                 ))),
             )
+            // Connectivity checks of Android, Apple and Windows: anything but the
+            // expected answer makes them open the captive portal
+            .route("/generate_204", get(|| async { Redirect::to("/") }))
+            .route("/hotspot-detect.html", get(|| async { Redirect::to("/") }))
+            .route("/connecttest.txt", get(|| async { Redirect::to("/") }))
             .route(
                 "/ws",
                 get(|upgrade: picoserve::response::WebSocketUpgrade| {
@@ -103,14 +237,453 @@ impl AppBuilder for AppProps {
             .route(
                 "/settings",
                 post(move |Json(settings): Json<WifiSettings>| async move {
-                    let _ = update_wifi_settings(&settings, db).await;
-                    picoserve::response::DebugValue((
+                    // Checked before anything is saved, stored once the rest is
+                    let admin_password = if settings.admin_password.is_empty() {
+                        None
+                    } else {
+                        let mut salt = [0u8; SALT_LEN];
+                        let mut rng = rng;
+                        rng.read(&mut salt);
+                        let Some(hash) = PasswordHash::new(&settings.admin_password, salt) else {
+                            return Err((
+                                StatusCode::BAD_REQUEST,
+                                "Admin password must be 8-64 characters\r\n",
+                            ));
+                        };
+                        Some(hash)
+                    };
+                    if let Err(e) = settings.static_ip() {
+                        warn!("Rejected Wi-Fi settings: {e}");
+                        return Err((StatusCode::BAD_REQUEST, e.as_str()));
+                    }
+                    // Nothing is saved unless the network takes the credentials,
+                    // a typo must not push out the network the device is on
+                    let network = SavedNetwork {
+                        ssid: settings.ssid.clone(),
+                        psw: settings.psw.clone(),
+                        priority: 0,
+                    };
+                    if let Err(e) = wifi::connect(network).await {
+                        warn!("Wi-Fi settings not saved, connecting failed: {e:?}");
+                        return Err((
+                            StatusCode::BAD_GATEWAY,
+                            "Could not connect, settings not saved\r\n",
+                        ));
+                    }
+                    if let Err(e) = update_wifi_settings(&settings, db).await {
+                        error!("Failed to save Wi-Fi settings: {e}");
+                        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"));
+                    }
+                    if let Some(hash) = admin_password {
+                        match write_admin_password(db, &hash).await {
+                            Ok(()) => auth::set_password(hash),
+                            Err(e) => {
+                                error!("Failed to store admin password: {e}");
+                                return Err((
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    "Storage error\r\n",
+                                ));
+                            }
+                        }
+                    }
+                    match read_networks(db).await {
+                        Ok(networks) => wifi::set_known_networks(networks).await,
+                        Err(e) => error!("Failed to reload saved networks: {e}"),
+                    }
+                    Ok(picoserve::response::DebugValue((
                         ("hostname", settings.hostname),
                         ("ssid", settings.ssid),
-                        ("psw", settings.psw),
-                    ))
+                    )))
+                }),
+            )
+            .route(
+                "/api/login",
+                post(move |Json(request): Json<LoginRequest>| async move {
+                    if !auth::password_set() {
+                        return Err((StatusCode::CONFLICT, "No admin password set\r\n"));
+                    }
+                    let mut token = [0u8; TOKEN_LEN];
+                    let mut rng = rng;
+                    rng.read(&mut token);
+                    if !auth::login(&request.password, token) {
+                        warn!("Failed admin login");
+                        Timer::after(LOGIN_FAILURE_DELAY).await;
+                        return Err((StatusCode::UNAUTHORIZED, "Wrong password\r\n"));
+                    }
+                    info!("Admin logged in");
+                    Ok(picoserve::response::Json(LoginResponse {
+                        token: auth::format_token(&token),
+                        expires_in_s: SESSION_LIFETIME.as_secs(),
+                    }))
+                }),
+            )
+            .route(
+                "/api/status",
+                get(|| async { picoserve::response::Json(status::system_status()) }),
+            )
+            .route(
+                "/api/wifi",
+                get(|| async {
+                    match wifi::WIFI_STATUS.try_get() {
+                        Some(status) => Ok(picoserve::response::Json(status)),
+                        None => Err((StatusCode::SERVICE_UNAVAILABLE, "Wi-Fi not started\r\n")),
+                    }
+                }),
+            )
+            .route(
+                "/api/wifi/scan",
+                get(|| async {
+                    match wifi::scan().await {
+                        Ok(networks) => Ok(picoserve::response::Json(networks)),
+                        Err(e) => {
+                            error!("Wi-Fi scan failed: {e:?}");
+                            Err((StatusCode::SERVICE_UNAVAILABLE, "Wi-Fi scan failed\r\n"))
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/api/wifi/networks",
+                get(move || async move {
+                    match read_networks(db).await {
+                        Ok(networks) => Ok(picoserve::response::Json(networks)),
+                        Err(e) => {
+                            error!("Failed to read saved networks: {e}");
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"))
+                        }
+                    }
+                })
+                .post(move |Json(network): Json<SavedNetwork>| async move {
+                    networks_updated(add_network(network, db).await).await
+                }),
+            )
+            .route(
+                "/api/wifi/networks/remove",
+                post(move |Json(network): Json<NetworkSsid>| async move {
+                    networks_updated(remove_network(&network.ssid, db).await).await
+                }),
+            )
+            .route(
+                "/api/wifi/networks/order",
+                post(move |Json(order): Json<NetworkOrder>| async move {
+                    networks_updated(reorder_networks(&order, db).await).await
+                }),
+            )
+            .route(
+                "/api/wifi/ap",
+                get(move || async move {
+                    match read_ap_config(db).await {
+                        Ok(config) => Ok(picoserve::response::Json(ApSettings::from(&config))),
+                        Err(e) => {
+                            error!("Failed to read AP settings: {e}");
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"))
+                        }
+                    }
+                })
+                .post(move |Json(settings): Json<ApSettings>| async move {
+                    let stored = match read_ap_config(db).await {
+                        Ok(stored) => stored,
+                        Err(e) => {
+                            error!("Failed to read AP settings: {e}");
+                            return (StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n");
+                        }
+                    };
+                    let config = match settings.parse(&stored) {
+                        Ok(config) => config,
+                        Err(e) => return (StatusCode::BAD_REQUEST, e.as_str()),
+                    };
+                    match write_ap_config(db, &config).await {
+                        Ok(()) => (StatusCode::OK, "Saved, applies after a reboot\r\n"),
+                        Err(e) => {
+                            error!("Failed to store AP settings: {e}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n")
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/api/dhcp/leases",
+                get(|| async { picoserve::response::Json(wifi::dhcp_leases().await) }),
+            )
+            .route(
+                "/api/dhcp/reservations",
+                get(move || async move {
+                    match read_dhcp_reservations(db).await {
+                        Ok(reservations) => Ok(reservations_json(&reservations)),
+                        Err(e) => {
+                            error!("Failed to read DHCP reservations: {e}");
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"))
+                        }
+                    }
+                })
+                .post(
+                    move |Json(settings): Json<ReservationSettings>| async move {
+                        let ap = match read_ap_config(db).await {
+                            Ok(ap) => ap,
+                            Err(e) => return reservations_updated(Err(e.into())).await,
+                        };
+                        match settings.parse(&ap) {
+                            Ok(reservation) => {
+                                reservations_updated(add_dhcp_reservation(reservation, db).await)
+                                    .await
+                            }
+                            Err(e) => reservations_updated(Err(e)).await,
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/api/dhcp/reservations/remove",
+                post(
+                    move |Json(settings): Json<ReservationSettings>| async move {
+                        match settings.mac() {
+                            Ok(mac) => {
+                                reservations_updated(remove_dhcp_reservation(&mac, db).await).await
+                            }
+                            Err(e) => reservations_updated(Err(e)).await,
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/api/wifi/connect",
+                post(move |Json(request): Json<NetworkSsid>| async move {
+                    let networks = match read_networks(db).await {
+                        Ok(networks) => networks,
+                        Err(e) => {
+                            error!("Failed to read saved networks: {e}");
+                            return (StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n");
+                        }
+                    };
+                    match networks
+                        .into_iter()
+                        .find(|network| network.ssid == request.ssid)
+                    {
+                        Some(network) => {
+                            wifi::send_command(WifiCommand::Connect(network)).await;
+                            (StatusCode::OK, "Connecting\r\n")
+                        }
+                        None => (StatusCode::NOT_FOUND, "Unknown network\r\n"),
+                    }
+                }),
+            )
+            .route(
+                "/api/wifi/mode",
+                post(|Json(request): Json<ModeRequest>| async move {
+                    wifi::send_command(WifiCommand::SetMode(request.mode)).await;
+                    "Switching\r\n"
+                }),
+            )
+            .route(
+                "/api/wifi/disconnect",
+                post(|| async {
+                    wifi::send_command(WifiCommand::Disconnect).await;
+                    "Disconnecting\r\n"
                 }),
             )
+            .route(
+                "/api/time",
+                get(move || async move {
+                    let timezone = match read_time_zone(db).await {
+                        Ok(settings) => settings.timezone,
+                        Err(e) => {
+                            error!("Failed to read time zone: {e}");
+                            String::new()
+                        }
+                    };
+                    let zone = clock::time_zone();
+                    let local = clock::now_local();
+                    let dst = local.is_some_and(|local| local.is_dst);
+                    picoserve::response::Json(TimeStatus {
+                        synced: local.is_some(),
+                        utc: clock::now_utc(),
+                        local: local.map(|local| {
+                            let mut text = String::new();
+                            let _ = write!(text, "{local}");
+                            text
+                        }),
+                        zone: String::try_from(zone.abbreviation(dst)).unwrap_or_default(),
+                        dst,
+                        timezone,
+                    })
+                }),
+            )
+            .route(
+                "/api/time/zone",
+                post(move |Json(settings): Json<TimeZoneSettings>| async move {
+                    let zone = match settings.parse() {
+                        Ok(zone) => zone,
+                        Err(e) => return (StatusCode::BAD_REQUEST, e.as_str()),
+                    };
+                    match write_time_zone(db, &settings).await {
+                        Ok(()) => {
+                            clock::set_time_zone(zone);
+                            (StatusCode::OK, "Saved\r\n")
+                        }
+                        Err(e) => {
+                            error!("Failed to store time zone: {e}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n")
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/api/time/ntp",
+                get(move || async move {
+                    match read_ntp_servers(db).await {
+                        Ok(settings) => Ok(picoserve::response::Json(settings)),
+                        Err(e) => {
+                            error!("Failed to read NTP servers: {e}");
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"))
+                        }
+                    }
+                })
+                .post(move |Json(settings): Json<NtpSettings>| async move {
+                    let Some(servers) = settings.parse() else {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            "Expected 1-4 comma separated servers\r\n",
+                        );
+                    };
+                    match write_ntp_servers(db, &servers).await {
+                        Ok(()) => {
+                            wifi::set_ntp_servers(servers);
+                            (StatusCode::OK, "Saved, syncing\r\n")
+                        }
+                        Err(e) => {
+                            error!("Failed to store NTP servers: {e}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n")
+                        }
+                    }
+                }),
+            )
+            .route("/ota", post_service(OtaUpload { db }))
+            .route(
+                "/api/ota",
+                get(|| async { picoserve::response::Json(ota::status()) }),
+            )
+            .route(
+                "/api/ota/switch",
+                post(move || async move {
+                    match ota::switch_to_other_slot(db).await {
+                        Ok(Slot::Slot1) => (StatusCode::OK, "Next boot from ota_1\r\n"),
+                        Ok(_) => (StatusCode::OK, "Next boot from ota_0\r\n"),
+                        Err(ota::Error::Busy) => {
+                            (StatusCode::CONFLICT, "Firmware upgrade in progress\r\n")
+                        }
+                        Err(ota::Error::InvalidHeader) => {
+                            (StatusCode::BAD_REQUEST, "Other slot holds no app image\r\n")
+                        }
+                        Err(e) => {
+                            error!("OTA slot switch failed: {e:?}");
+                            (
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                "OTA slot switch failed\r\n",
+                            )
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/api/ota/manifest",
+                post(move |Json(settings): Json<ManifestSettings>| async move {
+                    match update_manifest_url(&settings, db).await {
+                        Ok(()) => (StatusCode::OK, "OK\r\n"),
+                        Err(e) => {
+                            error!("Failed to store manifest URL: {e}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n")
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/api/ota/health",
+                get(move || async move {
+                    match ota::read_health_settings(db).await {
+                        Ok(settings) => Ok(picoserve::response::Json(settings)),
+                        Err(e) => {
+                            error!("Failed to read OTA health settings: {e}");
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"))
+                        }
+                    }
+                })
+                .post(
+                    move |Json(settings): Json<ota::HealthSettings>| async move {
+                        if !settings.is_valid() {
+                            return (
+                                StatusCode::BAD_REQUEST,
+                                "Deadline must be 10-3600 seconds\r\n",
+                            );
+                        }
+                        match ota::write_health_settings(db, &settings).await {
+                            Ok(()) => (StatusCode::OK, "Saved, applies to the next update\r\n"),
+                            Err(e) => {
+                                error!("Failed to store OTA health settings: {e}");
+                                (StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n")
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/reboot",
+                post(|| async {
+                    ota::REBOOT_REQUEST.signal(());
+                    "Rebooting\r\n"
+                }),
+            )
+            .layer(AuthLayer)
+    }
+}
+
+/// Hands a changed network list to the Wi-Fi task and answers with it
+async fn networks_updated(
+    result: Result<SavedNetworks, NetworksError>,
+) -> Result<picoserve::response::Json<SavedNetworks>, (StatusCode, &'static str)> {
+    match result {
+        Ok(networks) => {
+            wifi::set_known_networks(networks.clone()).await;
+            Ok(picoserve::response::Json(networks))
+        }
+        Err(NetworksError::EmptySsid) => Err((StatusCode::BAD_REQUEST, "SSID is empty\r\n")),
+        Err(NetworksError::Full) => Err((StatusCode::CONFLICT, "No room for another network\r\n")),
+        Err(NetworksError::UnknownNetwork) => Err((StatusCode::NOT_FOUND, "Unknown network\r\n")),
+        Err(NetworksError::Storage(e)) => {
+            error!("Failed to save networks: {e}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"))
+        }
+    }
+}
+
+type ReservationsJson =
+    picoserve::response::Json<heapless::Vec<ReservationSettings, MAX_RESERVATIONS>>;
+
+fn reservations_json(reservations: &Reservations) -> ReservationsJson {
+    picoserve::response::Json(reservations.iter().map(ReservationSettings::from).collect())
+}
+
+/// Hands changed reservations to the DHCP server and answers with them
+async fn reservations_updated(
+    result: Result<Reservations, ReservationError>,
+) -> Result<ReservationsJson, (StatusCode, &'static str)> {
+    match result {
+        Ok(reservations) => {
+            wifi::set_dhcp_reservations(&reservations).await;
+            Ok(reservations_json(&reservations))
+        }
+        Err(ReservationError::Mac) => Err((StatusCode::BAD_REQUEST, "Invalid MAC address\r\n")),
+        Err(ReservationError::Address) => Err((
+            StatusCode::BAD_REQUEST,
+            "Address must be a free host of the AP subnet\r\n",
+        )),
+        Err(ReservationError::Full) => {
+            Err((StatusCode::CONFLICT, "No room for another reservation\r\n"))
+        }
+        Err(ReservationError::Unknown) => Err((StatusCode::NOT_FOUND, "Unknown reservation\r\n")),
+        Err(ReservationError::Storage(e)) => {
+            error!("Failed to save DHCP reservations: {e}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage error\r\n"))
+        }
     }
 }
 
@@ -121,22 +694,30 @@ pub async fn web_task(
     app: &'static AppRouter<AppProps>,
     config: &'static picoserve::Config<Duration>,
 ) -> ! {
-    let port = 80;
     let mut tcp_rx_buffer = [0; 512];
     let mut tcp_tx_buffer = [0; 521];
     let mut http_buffer = [0; 1024];
 
-    picoserve::listen_and_serve(
-        id,
-        app,
-        config,
-        stack,
-        port,
-        &mut tcp_rx_buffer,
-        &mut tcp_tx_buffer,
-        &mut http_buffer,
-    )
-    .await
+    // picoserve::listen_and_serve, but noting the first request answered for the
+    // OTA health check. Listening on the port alone takes IPv6 connections too
+    // when the stack has IPv6.
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
+        if let Err(e) = socket.accept(WEB_PORT).await {
+            warn!("{id}: accept error: {e:?}");
+            continue;
+        }
+        let remote = socket.remote_endpoint();
+        match picoserve::serve(app, config, &mut http_buffer, socket).await {
+            Ok(requests) => {
+                info!("{id}: {requests} requests handled from {remote:?}");
+                if requests > 0 {
+                    WEB_REQUEST_SERVED.store(true, Ordering::Release);
+                }
+            }
+            Err(e) => error!("{id}: {e:?}"),
+        }
+    }
 }
 
 impl sse::EventSource for SseEvents {
@@ -154,15 +735,24 @@ impl sse::EventSource for SseEvents {
             }
         };
         writer.write_event("message_changed", "").await?;
+        // Streams beyond the receiver limit go without Wi-Fi status
+        let mut wifi_status = wifi::WIFI_STATUS.receiver();
 
         loop {
-            match embassy_futures::select::select(
+            let wifi_changed = async {
+                match &mut wifi_status {
+                    Some(receiver) => receiver.changed().await,
+                    None => core::future::pending().await,
+                }
+            };
+            match embassy_futures::select::select3(
                 receiver.changed(),
                 Timer::after(Duration::from_secs(10)),
+                wifi_changed,
             )
             .await
             {
-                embassy_futures::select::Either::First(result) => {
+                embassy_futures::select::Either3::First(result) => {
                     if result.is_empty() {
                         info!("SSE Result: {}. its Closed?", result);
                         break Ok(());
@@ -175,9 +765,108 @@ impl sse::EventSource for SseEvents {
 
                     writer.write_event("message_changed", message_slice).await?;
                 }
-                embassy_futures::select::Either::Second(_) => {
+                embassy_futures::select::Either3::Second(_) => {
                     writer.write_keepalive().await?;
                 }
+                embassy_futures::select::Either3::Third(status) => {
+                    let mut json = [0u8; WIFI_STATUS_JSON_LEN];
+                    match serde_json_core::to_slice(&status, &mut json) {
+                        Ok(n) => {
+                            let json = core::str::from_utf8(&json[..n]).unwrap_or_default();
+                            writer.write_event("wifi", json).await?;
+                        }
+                        Err(e) => error!("Failed to encode Wi-Fi status: {e:?}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends `message` to the browsers listening on `/events`. Messages from
+/// before the web server started are dropped.
+pub fn publish_sse(message: core::fmt::Arguments<'_>) {
+    let Some(watch) = critical_section::with(|_| unsafe { WATCH_REF }) else {
+        return;
+    };
+    let mut msg: String<128> = String::new();
+    if msg.write_fmt(message).is_ok() {
+        watch.sender().send(msg);
+    }
+}
+
+/// Streams a raw app image from the request body into the inactive OTA slot
+struct OtaUpload {
+    db: &'static DbMutex,
+}
+
+impl OtaUpload {
+    async fn receive_image<R: Read>(
+        &self,
+        reader: &mut R,
+        content_length: usize,
+    ) -> Result<Result<Slot, ota::Error>, R::Error> {
+        let mut writer = match OtaWriter::new() {
+            Ok(w) => w,
+            Err(e) => return Ok(Err(e)),
+        };
+        if let Err(e) = writer.set_expected_len(content_length) {
+            return Ok(Err(e));
+        }
+
+        let mut chunk = [0; OTA_CHUNK_SIZE];
+        let mut next_report = OTA_PROGRESS_STEP;
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            if let Err(e) = writer.write(&chunk[..n]).await {
+                return Ok(Err(e));
+            }
+            if writer.received() >= next_report {
+                publish_sse(format_args!("OTA {}/{}", writer.received(), content_length));
+                next_report += OTA_PROGRESS_STEP;
+            }
+        }
+
+        Ok(writer.finish(self.db).await)
+    }
+}
+
+impl RequestHandlerService<()> for OtaUpload {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        (): &(),
+        (): (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let content_length = request.body_connection.content_length();
+        info!("OTA upload of {content_length} bytes");
+
+        let result = {
+            let mut reader = request.body_connection.body().reader();
+            self.receive_image(&mut reader, content_length).await?
+        };
+        let connection = request.body_connection.finalize().await?;
+
+        match result {
+            Ok(slot) => {
+                publish_sse(format_args!("OTA done, next boot from {slot:?}"));
+                format_args!("OK: {content_length} bytes written to {slot:?}\r\n")
+                    .write_to(connection, response_writer)
+                    .await
+            }
+            Err(e) => {
+                error!("OTA upload failed: {e:?}");
+                publish_sse(format_args!("OTA failed: {e:?}"));
+                (
+                    StatusCode::BAD_REQUEST,
+                    format_args!("OTA failed: {e:?}\r\n"),
+                )
+                    .write_to(connection, response_writer)
+                    .await
             }
         }
     }
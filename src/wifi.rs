@@ -1,19 +1,39 @@
-use core::net::Ipv4Addr;
-use core::str::FromStr;
-use core::sync::atomic::Ordering;
+use core::fmt::Write;
+use core::net::{Ipv4Addr, Ipv6Addr};
 use embassy_executor::{Spawner, task};
+use embassy_futures::select::{Either, Either3, select, select3};
 use embassy_net::{Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Instant, Timer, with_deadline, with_timeout};
 use esp_wifi::{
     EspWifiController, InitializationError, init,
     wifi::{
-        ClientConfiguration, Configuration, WifiController, WifiDevice, WifiError, WifiEvent,
-        WifiState,
+        AccessPointInfo, AuthMethod, ClientConfiguration, Configuration, WifiController,
+        WifiDevice, WifiError, WifiEvent, WifiState, ap_state, sta_state,
     },
 };
 use log::{error, info};
+use serde::{Deserialize, Serialize};
 
-use crate::WIFI_MODE_CLIENT;
+use crate::DbMutex;
+use crate::clock;
+use crate::config::{
+    ApConfig, MAX_NETWORKS, NtpServers, SavedNetwork, SavedNetworks, StaticIpConfig,
+    read_dhcp_leases, read_dhcp_reservations, write_dhcp_leases,
+};
+use crate::dhcp_leases::{
+    LEASE_RECORD_LEN, LeaseTable, LeaseUpdate, MAX_LEASES, Mac, Reservation, format_mac,
+};
+use crate::dns;
+#[cfg(feature = "ipv6")]
+use crate::mdns::MDNS_GROUP_V6;
+use crate::mdns::{MDNS_GROUP, MDNS_PORT, MdnsService};
+use crate::sntp::{self, NTP_PORT, SntpError};
+use crate::web_server::{WEB_PORT, WEB_TASK_POOL_SIZE, publish_sse};
 use esp_hal::peripherals::RADIO_CLK;
 use esp_hal::peripherals::TIMG0;
 use esp_hal::peripherals::WIFI;
@@ -23,8 +43,27 @@ use esp_wifi::wifi::AccessPointConfiguration;
 use heapless::String;
 use static_cell::StaticCell;
 
-pub static STACK_RESOURCES: StaticCell<StackResources<10>> = StaticCell::new();
-pub static WIFI_STACK: StaticCell<Stack> = StaticCell::new();
+pub static STA_STACK_RESOURCES: StaticCell<StackResources<10>> = StaticCell::new();
+pub static STA_STACK: StaticCell<Stack> = StaticCell::new();
+pub static AP_STACK_RESOURCES: StaticCell<StackResources<10>> = StaticCell::new();
+pub static AP_STACK: StaticCell<Stack> = StaticCell::new();
+
+/// Default AP SSID, followed by the end of the MAC address so boards side by side differ
+const AP_SSID_PREFIX: &str = "esp-wifi";
+const DNS_PORT: u16 = 53;
+/// Unsolicited announcements after start and address changes (RFC 6762 8.3)
+const MDNS_ANNOUNCEMENTS: u8 = 2;
+/// Failed station connects in a row before the AP comes up, unless `wifi.sta_fail_limit` says otherwise
+pub const DEFAULT_STA_FAILURE_LIMIT: u32 = 5;
+/// Pause between connect attempts
+const RETRY_DELAY: Duration = Duration::from_millis(5000);
+/// How long the AP stays up after a switch from AP mode to a network succeeded
+const AP_HANDOVER_DELAY: Duration = Duration::from_secs(10);
+/// Clock re-sync period once SNTP succeeded
+const SNTP_INTERVAL: Duration = Duration::from_secs(3600);
+/// Pause before trying the servers again after none answered
+const SNTP_RETRY_DELAY: Duration = Duration::from_secs(30);
+const SNTP_TIMEOUT: Duration = Duration::from_secs(5);
 
 // When you are okay with using a nightly compiler it's better to use https://docs.rs/static_cell/2.1.0/static_cell/macro.make_static.html
 macro_rules! mk_static {
@@ -36,38 +75,253 @@ macro_rules! mk_static {
     }};
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum WifiMode {
     Sta,
     Ap,
 }
 
+/// Live Wi-Fi state, refreshed by `wifi_connection` every `STATUS_INTERVAL`
+/// and on every change
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WifiStatus {
+    pub mode: WifiMode,
+    /// Station connected to a network
+    pub link_up: bool,
+    /// Access point running, in AP mode or as the fallback
+    pub ap_up: bool,
+    pub ssid: Option<String<32>>,
+    /// Station address
+    pub ip: Option<String<15>>,
+    /// Station IPv6 addresses; embassy-net keeps one, the link-local one
+    #[cfg(feature = "ipv6")]
+    pub ipv6: heapless::Vec<String<39>, 1>,
+    /// Signal of the network in dBm
+    pub rssi: Option<i32>,
+    pub channel: Option<u8>,
+    /// Station connects after the first one
+    pub reconnects: u32,
+}
+
+/// One receiver per SSE stream, each web task serves at most one
+pub const WIFI_STATUS_RECEIVERS: usize = WEB_TASK_POOL_SIZE;
+pub static WIFI_STATUS: Watch<CriticalSectionRawMutex, WifiStatus, WIFI_STATUS_RECEIVERS> =
+    Watch::new();
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Networks `wifi_connection` picks from, kept in sync with the saved list
+static KNOWN_NETWORKS: Mutex<CriticalSectionRawMutex, SavedNetworks> =
+    Mutex::new(SavedNetworks::new());
+
+/// Makes `networks` the candidates of the next connect attempt
+pub async fn set_known_networks(networks: SavedNetworks) {
+    *KNOWN_NETWORKS.lock().await = networks;
+}
+
+/// Most networks a scan reports
+pub const SCAN_MAX_RESULTS: usize = 16;
+const SCAN_TIMEOUT_S: u64 = 10;
+/// Covers the connect attempt and the way back to the previous network
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Requests for the `wifi_connection` task, which owns the `WifiController`
+pub enum WifiCommand {
+    Scan,
+    /// Connect to this network now, back to the previous one if that fails
+    Connect(SavedNetwork),
+    /// Switch between station and access point
+    SetMode(WifiMode),
+    /// Disconnect the station and keep it so until the next command
+    Disconnect,
+}
+
+static WIFI_COMMANDS: Channel<CriticalSectionRawMutex, WifiCommand, 4> = Channel::new();
+static SCAN_RESULT: Signal<CriticalSectionRawMutex, Result<ScanResults, WifiError>> = Signal::new();
+/// One scan at a time, so every caller gets its own result
+static SCAN_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+static CONNECT_RESULT: Signal<CriticalSectionRawMutex, Result<(), WifiError>> = Signal::new();
+/// One connect attempt at a time, for the same reason
+static CONNECT_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+#[derive(Debug, Serialize)]
+pub struct ScannedNetwork {
+    ssid: String<32>,
+    bssid: String<17>,
+    rssi: i8,
+    channel: u8,
+    auth: &'static str,
+}
+
+pub type ScanResults = heapless::Vec<ScannedNetwork, SCAN_MAX_RESULTS>;
+
+impl From<&AccessPointInfo> for ScannedNetwork {
+    fn from(ap: &AccessPointInfo) -> Self {
+        Self {
+            ssid: ap.ssid.clone(),
+            bssid: format_mac(&ap.bssid),
+            rssi: ap.signal_strength,
+            channel: ap.channel,
+            auth: auth_method_name(ap.auth_method),
+        }
+    }
+}
+
+fn auth_method_name(auth: Option<AuthMethod>) -> &'static str {
+    match auth {
+        None | Some(AuthMethod::None) => "open",
+        Some(AuthMethod::WEP) => "wep",
+        Some(AuthMethod::WPA) => "wpa",
+        Some(AuthMethod::WPA2Personal) => "wpa2",
+        Some(AuthMethod::WPAWPA2Personal) => "wpa/wpa2",
+        Some(AuthMethod::WPA2Enterprise) => "wpa2-enterprise",
+        Some(AuthMethod::WPA3Personal) => "wpa3",
+        Some(AuthMethod::WPA2WPA3Personal) => "wpa2/wpa3",
+        Some(AuthMethod::WAPIPersonal) => "wapi",
+    }
+}
+
+/// Scans for networks through the `wifi_connection` task
+pub async fn scan() -> Result<ScanResults, Error> {
+    let _guard = SCAN_LOCK.lock().await;
+    SCAN_RESULT.reset();
+    WIFI_COMMANDS.send(WifiCommand::Scan).await;
+    match with_timeout(Duration::from_secs(SCAN_TIMEOUT_S), SCAN_RESULT.wait()).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+/// Connects to `network` through the `wifi_connection` task and waits for the
+/// outcome. On failure the station is back on the network it was on.
+pub async fn connect(network: SavedNetwork) -> Result<(), Error> {
+    let _guard = CONNECT_LOCK.lock().await;
+    CONNECT_RESULT.reset();
+    WIFI_COMMANDS.send(WifiCommand::Connect(network)).await;
+    match with_timeout(CONNECT_TIMEOUT, CONNECT_RESULT.wait()).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+/// Hands `command` to the `wifi_connection` task. The outcome of a
+/// reconfiguration is reported over SSE.
+pub async fn send_command(command: WifiCommand) {
+    WIFI_COMMANDS.send(command).await;
+}
+
+async fn run_scan(controller: &mut WifiController<'static>) {
+    info!("Scanning for networks");
+    let result = controller
+        .scan_n_async::<SCAN_MAX_RESULTS>()
+        .await
+        .map(|(networks, _)| networks.iter().map(ScannedNetwork::from).collect());
+    SCAN_RESULT.signal(result);
+}
+
+/// DHCP server: the pool, reservations and leases handed out on the AP
+pub static DHCP_LEASES: Mutex<CriticalSectionRawMutex, LeaseTable> = Mutex::new(LeaseTable::new());
+
+/// A lease as listed by `/api/dhcp/leases`
+#[derive(Debug, Serialize)]
+pub struct DhcpLease {
+    mac: String<17>,
+    ip: String<15>,
+    expires_in_s: u64,
+    reserved: bool,
+}
+
+pub type DhcpLeases = heapless::Vec<DhcpLease, MAX_LEASES>;
+
+/// Clients currently holding an address on the AP
+pub async fn dhcp_leases() -> DhcpLeases {
+    let now = Instant::now().as_secs();
+    let table = DHCP_LEASES.lock().await;
+    table
+        .leases()
+        .iter()
+        .filter(|lease| lease.expires_s > now)
+        .map(|lease| {
+            let mut ip = String::new();
+            let _ = write!(ip, "{}", lease.ip);
+            DhcpLease {
+                mac: format_mac(&lease.mac),
+                ip,
+                expires_in_s: lease.expires_s - now,
+                reserved: table.reserved_for(&lease.mac).is_some(),
+            }
+        })
+        .collect()
+}
+
+/// Applies changed reservations to the running DHCP server
+pub async fn set_dhcp_reservations(reservations: &[Reservation]) {
+    DHCP_LEASES.lock().await.set_reservations(reservations);
+}
+
+async fn save_dhcp_leases(db: &'static DbMutex) {
+    let mut data = [0u8; MAX_LEASES * LEASE_RECORD_LEN];
+    let len = DHCP_LEASES
+        .lock()
+        .await
+        .encode_leases(Instant::now().as_secs(), &mut data);
+    if let Err(e) = write_dhcp_leases(db, &data[..len]).await {
+        error!("DHCP server: failed to save leases: {e}");
+    }
+}
+
+/// DHCP server of the AP. Leases live in `DHCP_LEASES` for the life of the task,
+/// and in storage too when the AP settings ask for it.
 #[task]
-async fn run_dhcp(stack: Stack<'static>, gw_ip_addr: &'static str) {
-    use core::net::{Ipv4Addr, SocketAddrV4};
+async fn run_dhcp(stack: Stack<'static>, ap: ApConfig, db: &'static DbMutex) {
+    use core::net::{SocketAddr, SocketAddrV4};
 
     use edge_dhcp::{
-        io::{self, DEFAULT_SERVER_PORT},
-        server::{Server, ServerOptions},
+        Options, Packet,
+        io::DEFAULT_SERVER_PORT,
+        server::{Action, ServerOptions},
     };
-    use edge_nal::UdpBind;
+    use edge_nal::{UdpBind, UdpReceive, UdpSend};
     use edge_nal_embassy::{Udp, UdpBuffers};
 
-    let ip = match Ipv4Addr::from_str(gw_ip_addr) {
-        Ok(ip) => ip,
-        Err(e) => {
-            error!("DHCP server: invalid gateway IP address '{gw_ip_addr}': {e}");
-            return;
+    let ip = ap.gateway;
+    let (range_start, range_end) = ap.dhcp_range();
+    let netmask = ap.netmask();
+    info!("DHCP server: leases {range_start} - {range_end}");
+
+    {
+        let mut table = DHCP_LEASES.lock().await;
+        table.set_range(range_start, range_end);
+        match read_dhcp_reservations(db).await {
+            Ok(reservations) => table.set_reservations(&reservations),
+            Err(e) => error!("DHCP server: failed to read reservations: {e}"),
         }
-    };
+        if ap.persist_leases {
+            let mut data = [0u8; MAX_LEASES * LEASE_RECORD_LEN];
+            match read_dhcp_leases(db, &mut data).await {
+                Ok(len) => {
+                    table.restore_leases(&data[..len], Instant::now().as_secs());
+                    info!("DHCP server: restored {} leases", table.leases().len());
+                }
+                Err(e) => error!("DHCP server: failed to read saved leases: {e}"),
+            }
+        }
+    }
 
-    let mut buf = [0u8; 1500];
+    let mut rx_buf = [0u8; 1500];
+    let mut tx_buf = [0u8; 1500];
 
     let mut gw_buf = [Ipv4Addr::UNSPECIFIED];
+    let mut options = ServerOptions::new(ip, Some(&mut gw_buf));
+    options.subnet = Some(netmask);
+    // The captive portal DNS server runs on the gateway
+    let dns = [ip];
+    options.dns = &dns;
 
     let buffers = UdpBuffers::<3, 1024, 1024, 10>::new();
     let unbound_socket = Udp::new(stack, &buffers);
     let mut bound_socket = match unbound_socket
-        .bind(core::net::SocketAddr::V4(SocketAddrV4::new(
+        .bind(SocketAddr::V4(SocketAddrV4::new(
             Ipv4Addr::UNSPECIFIED,
             DEFAULT_SERVER_PORT,
         )))
@@ -81,20 +335,401 @@ async fn run_dhcp(stack: Stack<'static>, gw_ip_addr: &'static str) {
     };
 
     loop {
-        if let Err(e) = io::server::run(
-            &mut Server::<_, 64>::new_with_et(ip),
-            &ServerOptions::new(ip, Some(&mut gw_buf)),
-            &mut bound_socket,
-            &mut buf,
-        )
+        let (len, remote) = match bound_socket.receive(&mut rx_buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("DHCP server error: {e:?}");
+                Timer::after(Duration::from_millis(500)).await;
+                continue;
+            }
+        };
+        let Ok(request) = Packet::decode(&rx_buf[..len]) else {
+            continue;
+        };
+
+        let now = Instant::now().as_secs();
+        let mut changed = false;
+        let mut opt_buf = Options::buf();
+        let reply = match options.process(&request) {
+            Some(Action::Discover(requested, chaddr)) => {
+                let mac = client_mac(chaddr);
+                let offered = DHCP_LEASES.lock().await.offer(&mac, requested, now);
+                offered.map(|ip| options.offer(&request, ip, &mut opt_buf))
+            }
+            Some(Action::Request(ip, chaddr)) => {
+                let mac = client_mac(chaddr);
+                let update =
+                    DHCP_LEASES
+                        .lock()
+                        .await
+                        .confirm(&mac, ip, now, options.lease_duration_secs);
+                if update == LeaseUpdate::Added {
+                    info!("DHCP server: {ip} leased to {}", format_mac(&mac));
+                    changed = true;
+                }
+                let acked = (update != LeaseUpdate::Rejected).then_some(ip);
+                Some(options.ack_nak(&request, acked, &mut opt_buf))
+            }
+            Some(Action::Release(_, chaddr) | Action::Decline(_, chaddr)) => {
+                changed = DHCP_LEASES.lock().await.release(&client_mac(chaddr));
+                None
+            }
+            None => None,
+        };
+
+        if let Some(reply) = reply {
+            // Clients without an address yet can only hear broadcasts
+            let remote = match remote {
+                SocketAddr::V4(addr) if request.broadcast || addr.ip().is_unspecified() => {
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, addr.port()))
+                }
+                remote => remote,
+            };
+            match reply.encode(&mut tx_buf) {
+                Ok(data) => {
+                    if let Err(e) = bound_socket.send(remote, data).await {
+                        error!("DHCP server: failed to send reply: {e:?}");
+                    }
+                }
+                Err(e) => error!("DHCP server: failed to encode reply: {e:?}"),
+            }
+        }
+
+        if changed && ap.persist_leases {
+            save_dhcp_leases(db).await;
+        }
+    }
+}
+
+/// Ethernet address of a client, the first bytes of the DHCP hardware address
+fn client_mac(chaddr: &[u8; 16]) -> Mac {
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&chaddr[..6]);
+    mac
+}
+
+/// Network stacks of both Wi-Fi interfaces
+pub struct WifiStacks {
+    /// Station interface, configured by DHCP
+    pub sta: &'static Stack<'static>,
+    /// Access point interface with the DHCP server, up in AP mode or after
+    /// the station kept failing
+    pub ap: &'static Stack<'static>,
+}
+
+/// Captive portal DNS: every A query on the AP is answered with the gateway address,
+/// so phones joining the AP open the settings page on their own
+#[task]
+async fn run_dns(stack: Stack<'static>, gw_ip_addr: Ipv4Addr) {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(DNS_PORT) {
+        error!("DNS server: failed to bind socket: {e:?}");
+        return;
+    }
+
+    let mut query = [0u8; 512];
+    let mut answer = [0u8; 512];
+    loop {
+        let (n, meta) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("DNS server: receive error: {e:?}");
+                continue;
+            }
+        };
+        match dns::captive_portal_answer(&query[..n], gw_ip_addr, &mut answer) {
+            Ok(len) => {
+                if let Err(e) = socket.send_to(&answer[..len], meta).await {
+                    error!("DNS server: send error: {e:?}");
+                }
+            }
+            Err(e) => info!("DNS server: ignoring packet from {}: {e:?}", meta.endpoint),
+        }
+    }
+}
+
+static NTP_SERVERS: Signal<CriticalSectionRawMutex, NtpServers> = Signal::new();
+
+/// Hands a changed server list to the SNTP task, which syncs with it right away
+pub fn set_ntp_servers(servers: NtpServers) {
+    NTP_SERVERS.signal(servers);
+}
+
+#[derive(Debug)]
+enum SntpSyncError {
+    Resolve,
+    Send,
+    Receive,
+    Timeout,
+    Reply(SntpError),
+}
+
+/// Keeps the clock in `clock.rs` set from the first of `servers` that answers
+#[task]
+pub async fn run_sntp(stack: Stack<'static>, mut servers: NtpServers) {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 256];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0u8; 256];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // Any local port will do
+    if let Err(e) = socket.bind(0) {
+        error!("SNTP: failed to bind socket: {e:?}");
+        return;
+    }
+
+    loop {
+        stack.wait_config_up().await;
+        let mut synced = false;
+        for server in &servers {
+            match sync_clock(stack, &mut socket, server).await {
+                Ok(()) => {
+                    synced = true;
+                    break;
+                }
+                Err(e) => info!("SNTP: no time from {server}: {e:?}"),
+            }
+        }
+        let delay = if synced {
+            SNTP_INTERVAL
+        } else {
+            SNTP_RETRY_DELAY
+        };
+        if let Either::Second(changed) = select(Timer::after(delay), NTP_SERVERS.wait()).await {
+            servers = changed;
+        }
+    }
+}
+
+async fn sync_clock(
+    stack: Stack<'static>,
+    socket: &mut embassy_net::udp::UdpSocket<'_>,
+    server: &str,
+) -> Result<(), SntpSyncError> {
+    use embassy_net::IpEndpoint;
+    use embassy_net::dns::DnsQueryType;
+
+    let address = stack
+        .dns_query(server, DnsQueryType::A)
+        .await
+        .ok()
+        .and_then(|addresses| addresses.first().copied())
+        .ok_or(SntpSyncError::Resolve)?;
+
+    let sent_us = Instant::now().as_micros();
+    // The send time doubles as the cookie the server echoes back
+    socket
+        .send_to(&sntp::request(sent_us), IpEndpoint::new(address, NTP_PORT))
         .await
-        {
-            error!("DHCP server error: {e:?}");
+        .map_err(|_| SntpSyncError::Send)?;
+
+    let mut packet = [0u8; 128];
+    let deadline = Instant::now() + SNTP_TIMEOUT;
+    loop {
+        let (n, meta) = with_deadline(deadline, socket.recv_from(&mut packet))
+            .await
+            .map_err(|_| SntpSyncError::Timeout)?
+            .map_err(|_| SntpSyncError::Receive)?;
+        let received_us = Instant::now().as_micros();
+        if meta.endpoint.addr != address {
+            continue;
+        }
+        let response = match sntp::Response::parse(&packet[..n], sent_us) {
+            // A late answer to an earlier request
+            Err(SntpError::Mismatch) => continue,
+            response => response.map_err(SntpSyncError::Reply)?,
+        };
+
+        let offset = response.clock_offset_us(sent_us, received_us);
+        let round_trip_ms = response.round_trip_us(sent_us, received_us) / 1000;
+        match clock::set_utc_offset(offset) {
+            None => info!(
+                "SNTP: clock set from {server} (stratum {}, {round_trip_ms} ms round trip), Unix time {}",
+                response.stratum,
+                clock::now_utc().unwrap_or_default()
+            ),
+            Some(step_us) => info!(
+                "SNTP: clock adjusted by {} ms from {server}",
+                step_us / 1000
+            ),
+        }
+        return Ok(());
+    }
+}
+
+/// mDNS responder for `<hostname>.local` and the web server's `_http._tcp`
+/// service, announcing the records again whenever the address changes
+#[task]
+pub async fn run_mdns(stack: Stack<'static>, hostname: String<32>) {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+    if let Err(e) = stack.join_multicast_group(MDNS_GROUP) {
+        error!("mDNS: failed to join multicast group: {e:?}");
+        return;
+    }
+    #[cfg(feature = "ipv6")]
+    if let Err(e) = stack.join_multicast_group(MDNS_GROUP_V6) {
+        error!("mDNS: failed to join IPv6 multicast group: {e:?}");
+    }
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // Bound to the port only, so queries come in over both IPv4 and IPv6
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        error!("mDNS: failed to bind socket: {e:?}");
+        return;
+    }
+    info!("mDNS responder for {hostname}.local");
+
+    let mut query = [0u8; 512];
+    let mut answer = [0u8; 512];
+    let mut announced = (None, None);
+    let mut announcements_left = 0;
+    loop {
+        let addresses = (
+            stack.config_v4().map(|config| config.address.address()),
+            sta_ipv6_address(stack),
+        );
+        if addresses != announced {
+            announced = addresses;
+            announcements_left = MDNS_ANNOUNCEMENTS;
+        }
+        if addresses == (None, None) {
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        }
+        let service = MdnsService {
+            hostname: &hostname,
+            address: addresses.0,
+            address_v6: addresses.1,
+            port: WEB_PORT,
+        };
+
+        if announcements_left > 0 {
+            announcements_left -= 1;
+            match service.announcement(&mut answer) {
+                Ok(len) => {
+                    for group in mdns_groups(addresses) {
+                        if let Err(e) = socket.send_to(&answer[..len], group).await {
+                            error!("mDNS: announcement failed: {e:?}");
+                        }
+                    }
+                }
+                Err(e) => error!("mDNS: failed to encode announcement: {e:?}"),
+            }
+        }
+
+        // Wake up every second to notice address changes
+        let received = select(
+            socket.recv_from(&mut query),
+            Timer::after(Duration::from_secs(1)),
+        )
+        .await;
+        let (n, meta) = match received {
+            Either::First(Ok(received)) => received,
+            Either::First(Err(e)) => {
+                error!("mDNS: receive error: {e:?}");
+                continue;
+            }
+            Either::Second(()) => continue,
+        };
+        let legacy = meta.endpoint.port != MDNS_PORT;
+        match service.answer(&query[..n], legacy, &mut answer) {
+            Ok(Some(response)) => {
+                let target = if response.unicast {
+                    meta.endpoint
+                } else {
+                    mdns_group_of(meta.endpoint.addr)
+                };
+                if let Err(e) = socket.send_to(&answer[..response.len], target).await {
+                    error!("mDNS: send error: {e:?}");
+                }
+            }
+            Ok(None) => {}
+            Err(e) => info!("mDNS: ignoring packet from {}: {e:?}", meta.endpoint),
         }
-        Timer::after(Duration::from_millis(500)).await;
     }
 }
 
+/// mDNS group of the address family of `address`
+fn mdns_group_of(address: embassy_net::IpAddress) -> embassy_net::IpEndpoint {
+    use embassy_net::{IpAddress, IpEndpoint};
+
+    match address {
+        #[cfg(feature = "ipv6")]
+        IpAddress::Ipv6(_) => IpEndpoint::new(IpAddress::Ipv6(MDNS_GROUP_V6), MDNS_PORT),
+        _ => IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT),
+    }
+}
+
+/// Groups to announce to: one per address family the station has an address in
+fn mdns_groups(
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+) -> impl Iterator<Item = embassy_net::IpEndpoint> {
+    use embassy_net::{IpAddress, IpEndpoint};
+
+    let v4 = addresses
+        .0
+        .map(|_| IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT));
+    #[cfg(feature = "ipv6")]
+    let v6 = addresses
+        .1
+        .map(|_| IpEndpoint::new(IpAddress::Ipv6(MDNS_GROUP_V6), MDNS_PORT));
+    #[cfg(not(feature = "ipv6"))]
+    let v6 = None;
+    v4.into_iter().chain(v6)
+}
+
+/// Link-local address of the station
+#[cfg(feature = "ipv6")]
+fn sta_ipv6_address(stack: Stack<'static>) -> Option<Ipv6Addr> {
+    stack.config_v6().map(|config| config.address.address())
+}
+
+/// `fe80::/64` with the modified EUI-64 interface identifier of `mac`
+#[cfg(feature = "ipv6")]
+fn link_local_address(mac: &[u8; 6]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets[..2].copy_from_slice(&[0xfe, 0x80]);
+    octets[8..11].copy_from_slice(&[mac[0] ^ 0x02, mac[1], mac[2]]);
+    octets[11..13].copy_from_slice(&[0xff, 0xfe]);
+    octets[13..].copy_from_slice(&mac[3..]);
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(not(feature = "ipv6"))]
+fn sta_ipv6_address(_stack: Stack<'static>) -> Option<Ipv6Addr> {
+    None
+}
+
 #[allow(clippy::too_many_arguments)]
 pub async fn init_wifi(
     spawner: Spawner,
@@ -102,10 +737,13 @@ pub async fn init_wifi(
     mut rng: Rng,
     wifi: WIFI,
     radio_clock_control: RADIO_CLK,
-    ssid: String<32>,
-    password: String<64>,
+    networks: SavedNetworks,
+    static_ip: Option<StaticIpConfig>,
+    mut ap: ApConfig,
     mode: WifiMode,
-) -> Result<&'static Stack<'static>, Error> {
+    sta_failure_limit: u32,
+    db: &'static DbMutex,
+) -> Result<WifiStacks, Error> {
     let esp_wifi_ctrl = &*mk_static!(
         EspWifiController<'static>,
         init(timer_g0.timer0, rng, radio_clock_control)?
@@ -113,185 +751,637 @@ pub async fn init_wifi(
 
     let (controller, interfaces) = esp_wifi::wifi::new(esp_wifi_ctrl, wifi)?;
 
-    let (device, config) = match mode {
-        WifiMode::Sta => (
-            interfaces.sta,
-            embassy_net::Config::dhcpv4(Default::default()),
-        ),
-        WifiMode::Ap => {
-            let gw_ip_addr = Ipv4Addr::new(192, 168, 1, 1);
-            (
-                interfaces.ap,
-                embassy_net::Config::ipv4_static(StaticConfigV4 {
-                    address: Ipv4Cidr::new(gw_ip_addr, 28),
-                    gateway: Some(gw_ip_addr),
-                    dns_servers: Default::default(),
-                }),
-            )
+    let sta_config = match static_ip {
+        Some(static_ip) => {
+            info!("Static IP {}/{}", static_ip.address, static_ip.prefix_len);
+            embassy_net::Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(static_ip.address, static_ip.prefix_len),
+                gateway: static_ip.gateway,
+                dns_servers: static_ip.dns.into_iter().collect(),
+            })
         }
+        None => embassy_net::Config::dhcpv4(Default::default()),
     };
+    // With a static IPv4 address or not, the station answers on its link-local
+    // IPv6 address; embassy-net has no SLAAC for a global one
+    #[cfg(feature = "ipv6")]
+    let sta_config = {
+        let mut mac = [0u8; 6];
+        esp_wifi::wifi::sta_mac(&mut mac);
+        embassy_net::Config {
+            ipv6: embassy_net::ConfigV6::Static(embassy_net::StaticConfigV6 {
+                address: embassy_net::Ipv6Cidr::new(link_local_address(&mac), 64),
+                gateway: None,
+                dns_servers: Default::default(),
+            }),
+            ..sta_config
+        }
+    };
+    let ap_config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(ap.gateway, ap.prefix_len),
+        gateway: Some(ap.gateway),
+        dns_servers: Default::default(),
+    });
 
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
-    let resources = STACK_RESOURCES.init(StackResources::<10>::new());
-    let (temp_stack, runner) = embassy_net::new(device, config, resources, seed);
-    let stack = WIFI_STACK.init(temp_stack);
+    let resources = STA_STACK_RESOURCES.init(StackResources::<10>::new());
+    let (sta_stack, sta_runner) = embassy_net::new(interfaces.sta, sta_config, resources, seed);
+    let sta_stack = STA_STACK.init(sta_stack);
 
+    let resources = AP_STACK_RESOURCES.init(StackResources::<10>::new());
+    let (ap_stack, ap_runner) = embassy_net::new(interfaces.ap, ap_config, resources, !seed);
+    let ap_stack = AP_STACK.init(ap_stack);
+
+    set_known_networks(networks).await;
     match mode {
-        WifiMode::Sta => {
-            info!("Connect Sta Mode");
-            WIFI_MODE_CLIENT.store(true, Ordering::Release);
-            if let Err(e) = spawner.spawn(wifi_connection(
-                controller,
-                mode,
-                Some(ssid),
-                Some(password),
-            )) {
-                error!("Failed to spawn wifi_connection: {e:?}");
-            }
-        }
-        WifiMode::Ap => {
-            info!("Connect AP Mode");
-            WIFI_MODE_CLIENT.store(false, Ordering::Release);
-            if let Err(e) = spawner.spawn(wifi_connection(controller, mode, None, None)) {
-                error!("Failed to spawn wifi_connection: {e:?}");
-            }
-            if let Err(e) = spawner.spawn(run_dhcp(*stack, "192.168.1.1")) {
-                error!("Failed to spawn DHCP task: {e:?}");
-            }
-        }
+        WifiMode::Sta => info!("Connect Sta Mode"),
+        WifiMode::Ap => info!("Connect AP Mode"),
     }
-
-    if let Err(e) = spawner.spawn(net_task(runner)) {
-        error!("Failed to spawn net task: {e:?}");
+    if ap.ssid.is_empty() {
+        ap.ssid = default_ap_ssid();
+    }
+    if ap.password.is_empty() {
+        info!("AP {} is open, set a password in the AP settings", ap.ssid);
+    }
+    let ap_config = ap_configuration(&ap);
+    if let Err(e) = spawner.spawn(wifi_connection(
+        controller,
+        *sta_stack,
+        mode,
+        ap_config,
+        sta_failure_limit,
+    )) {
+        error!("Failed to spawn wifi_connection: {e:?}");
     }
 
-    loop {
-        if stack.is_link_up() {
-            break;
-        }
-        Timer::after(Duration::from_millis(500)).await;
+    // The AP may come up later as a fallback, so it always gets its DHCP server
+    let gateway = ap.gateway;
+    if let Err(e) = spawner.spawn(run_dhcp(*ap_stack, ap, db)) {
+        error!("Failed to spawn DHCP task: {e:?}");
+    }
+    if let Err(e) = spawner.spawn(run_dns(*ap_stack, gateway)) {
+        error!("Failed to spawn DNS task: {e:?}");
+    }
+    if let Err(e) = spawner.spawn(net_task(sta_runner)) {
+        error!("Failed to spawn STA net task: {e:?}");
+    }
+    if let Err(e) = spawner.spawn(net_task(ap_runner)) {
+        error!("Failed to spawn AP net task: {e:?}");
     }
 
-    info!("Waiting to get IP address...");
+    info!("Waiting to get IP address or to start the AP...");
     loop {
-        if let Some(config) = temp_stack.config_v4() {
+        if let Some(config) = sta_stack.config_v4() {
             info!("Got IP: {}", config.address);
             break;
         }
+        if ap_stack.is_link_up() {
+            info!("AP up at {gateway}");
+            break;
+        }
         Timer::after(Duration::from_millis(500)).await;
     }
     info!("Leave connection task");
-    Ok(stack)
+    Ok(WifiStacks {
+        sta: sta_stack,
+        ap: ap_stack,
+    })
 }
 
-#[task]
-async fn wifi_connection(
-    mut controller: WifiController<'static>,
+fn client_configuration(network: &SavedNetwork) -> ClientConfiguration {
+    ClientConfiguration {
+        ssid: network.ssid.clone(),
+        password: network.psw.clone(),
+        ..Default::default()
+    }
+}
+
+fn ap_configuration(ap: &ApConfig) -> AccessPointConfiguration {
+    AccessPointConfiguration {
+        ssid: ap.ssid.clone(),
+        channel: ap.channel,
+        auth_method: if ap.password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        password: ap.password.clone(),
+        ..Default::default()
+    }
+}
+
+/// `esp-wifi-` and the last three bytes of the MAC address
+fn default_ap_ssid() -> String<32> {
+    let mac = esp_hal::efuse::Efuse::read_base_mac_address();
+    let mut ssid = String::new();
+    let _ = write!(
+        ssid,
+        "{AP_SSID_PREFIX}-{:02x}{:02x}{:02x}",
+        mac[3], mac[4], mac[5]
+    );
+    ssid
+}
+
+/// Station configuration for `client`, with the AP alongside if there is one
+fn station_configuration(
+    client: ClientConfiguration,
+    ap: Option<&AccessPointConfiguration>,
+) -> Configuration {
+    match ap {
+        Some(ap) => Configuration::Mixed(client, ap.clone()),
+        None => Configuration::Client(client),
+    }
+}
+
+/// A known network to try, with the channel of its strongest access point
+#[derive(Clone)]
+struct Candidate {
+    network: SavedNetwork,
+    channel: Option<u8>,
+}
+
+type Candidates = heapless::Vec<Candidate, MAX_NETWORKS>;
+
+/// Known networks in the order to try them: visible ones by priority and then
+/// signal strength, followed by the ones the scan missed, which may be hidden
+fn rank_networks(known: &SavedNetworks, visible: &[AccessPointInfo]) -> Candidates {
+    let mut ranked: heapless::Vec<(Option<&AccessPointInfo>, &SavedNetwork), MAX_NETWORKS> = known
+        .iter()
+        .map(|network| {
+            let strongest = visible
+                .iter()
+                .filter(|ap| ap.ssid == network.ssid)
+                .max_by_key(|ap| ap.signal_strength);
+            (strongest, network)
+        })
+        .collect();
+    let rssi = |ap: &Option<&AccessPointInfo>| ap.map(|ap| ap.signal_strength);
+    ranked.sort_unstable_by(|(a_ap, a), (b_ap, b)| {
+        b_ap.is_some()
+            .cmp(&a_ap.is_some())
+            .then(b.priority.cmp(&a.priority))
+            .then(rssi(b_ap).cmp(&rssi(a_ap)))
+    });
+    ranked
+        .iter()
+        .map(|(ap, network)| Candidate {
+            network: (*network).clone(),
+            channel: ap.map(|ap| ap.channel),
+        })
+        .collect()
+}
+
+/// Scans and ranks the known networks, returning them in connect order
+async fn find_candidates(controller: &mut WifiController<'static>) -> Candidates {
+    let known = KNOWN_NETWORKS.lock().await.clone();
+    if known.is_empty() {
+        return Candidates::new();
+    }
+    match controller.scan_n_async::<SCAN_MAX_RESULTS>().await {
+        Ok((visible, _)) => rank_networks(&known, &visible),
+        Err(e) => {
+            error!("Scan before connect failed: {e:?}");
+            rank_networks(&known, &[])
+        }
+    }
+}
+
+/// Station and access point state, owned by the `wifi_connection` task
+struct Connection {
+    controller: WifiController<'static>,
     mode: WifiMode,
-    ssid: Option<String<32>>,
-    password: Option<String<64>>,
-) {
-    info!("Start connection task");
-    info!("Device capabilities: {:?}", controller.capabilities());
+    ap: AccessPointConfiguration,
+    sta_failure_limit: u32,
+    /// Station interface, for its address
+    stack: Stack<'static>,
+    /// Known networks in connect order, tried from `next_candidate` on
+    candidates: Candidates,
+    next_candidate: usize,
+    /// Network the station last connected to, what a failed switch goes back to
+    connected: Option<SavedNetwork>,
+    /// Channel of that network, if a scan found it
+    channel: Option<u8>,
+    /// Successful station connects since boot
+    connects: u32,
+    /// Failed station connects in a row
+    failures: u32,
+    /// AP running next to the station
+    ap_fallback: bool,
+    /// Station disconnected on request, it stays so until the next command
+    idle: bool,
+}
 
-    if let WifiMode::Sta = &mode {
-        info!("SSID: {:?} Password: {:?}", ssid, password);
+impl Connection {
+    fn ap_up(&self) -> bool {
+        self.mode == WifiMode::Ap || self.ap_fallback
     }
 
-    loop {
-        let current_mode = &mode;
+    /// Station configuration for `client`, with the AP alongside while it is up
+    fn configuration(&self, client: ClientConfiguration) -> Configuration {
+        station_configuration(client, self.ap_up().then_some(&self.ap))
+    }
 
-        match (esp_wifi::wifi::wifi_state(), current_mode) {
-            (WifiState::StaConnected, WifiMode::Sta) => {
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                Timer::after(Duration::from_millis(5000)).await;
-            }
-            (WifiState::ApStarted, WifiMode::Ap) => {
-                controller.wait_for_event(WifiEvent::ApStop).await;
-                Timer::after(Duration::from_millis(5000)).await;
-            }
+    async fn ensure_started(&mut self) -> Result<(), WifiError> {
+        if self.controller.is_started()? {
+            return Ok(());
+        }
+        // The station starts idle, the network is picked after a scan.
+        // In AP mode the idle station interface is what lets the AP scan.
+        let config = self.configuration(Default::default());
+        self.controller.set_configuration(&config)?;
+        info!("Starting wifi");
+        self.controller.start_async().await?;
+        info!("Wifi started!");
+        Ok(())
+    }
+
+    /// Brings the AP up next to the station or takes it down again
+    fn set_fallback_ap(&mut self, client: ClientConfiguration, up: bool) -> Result<(), WifiError> {
+        let config = station_configuration(client, up.then_some(&self.ap));
+        self.controller.set_configuration(&config)?;
+        self.ap_fallback = up;
+        Ok(())
+    }
+
+    /// Points the station at `network`, leaving the AP as it is, and connects.
+    /// A known `channel` spares the driver from scanning them all.
+    async fn connect(
+        &mut self,
+        network: &SavedNetwork,
+        channel: Option<u8>,
+    ) -> Result<(), WifiError> {
+        let client = ClientConfiguration {
+            channel,
+            ..client_configuration(network)
+        };
+        let config = self.configuration(client);
+        self.controller.set_configuration(&config)?;
+        info!("About to connect SSID {:?}", network.ssid);
+        self.controller.connect_async().await?;
+        info!("Wifi connected!");
+        self.connected = Some(network.clone());
+        self.channel = channel;
+        self.connects = self.connects.saturating_add(1);
+        Ok(())
+    }
+
+    /// Publishes the current state on `WIFI_STATUS` if it changed
+    fn publish_status(&mut self) {
+        let link_up = sta_state() == WifiState::StaConnected;
+        let ap_up = ap_state() == WifiState::ApStarted;
+        let ip = self.stack.config_v4().filter(|_| link_up).map(|config| {
+            let mut ip = String::new();
+            let _ = write!(ip, "{}", config.address.address());
+            ip
+        });
+        let status = WifiStatus {
+            mode: self.mode,
+            link_up,
+            ap_up,
+            ssid: self
+                .connected
+                .as_ref()
+                .filter(|_| link_up)
+                .map(|network| network.ssid.clone()),
+            ip,
+            #[cfg(feature = "ipv6")]
+            ipv6: sta_ipv6_address(self.stack)
+                .filter(|_| link_up)
+                .map(|address| {
+                    let mut text = String::new();
+                    let _ = write!(text, "{address}");
+                    text
+                })
+                .into_iter()
+                .collect(),
+            rssi: if link_up {
+                self.controller.rssi().ok()
+            } else {
+                None
+            },
+            channel: match (link_up, ap_up) {
+                (true, _) => self.channel,
+                (false, true) => Some(self.ap.channel),
+                (false, false) => None,
+            },
+            reconnects: self.connects.saturating_sub(1),
+        };
+
+        let previous = WIFI_STATUS.try_get();
+        if previous.as_ref() == Some(&status) {
+            return;
+        }
+        let (was_up, had_ip) = previous.map_or((false, false), |p| (p.link_up, p.ip.is_some()));
+        match (was_up, status.link_up) {
+            (false, true) => info!("Wi-Fi link up"),
+            (true, false) => info!("Wi-Fi link down"),
+            _ => {}
+        }
+        match (had_ip, &status.ip) {
+            (false, Some(ip)) => info!("Wi-Fi IP acquired: {ip}"),
+            (true, None) => info!("Wi-Fi IP lost"),
             _ => {}
         }
+        WIFI_STATUS.sender().send(status);
+    }
 
-        match controller.is_started() {
-            Ok(true) => {} // already started
-            Ok(false) => {
-                let config = match current_mode {
-                    WifiMode::Sta => {
-                        let ssid_val = match ssid.clone() {
-                            Some(v) => v,
-                            None => {
-                                error!("STA mode requested but SSID not provided");
-                                Timer::after(Duration::from_millis(5000)).await;
-                                continue;
-                            }
-                        };
-                        let password_val = match password.clone() {
-                            Some(v) => v,
-                            None => {
-                                error!("STA mode requested but password not provided");
-                                Timer::after(Duration::from_millis(5000)).await;
-                                continue;
-                            }
-                        };
-
-                        Configuration::Client(ClientConfiguration {
-                            ssid: ssid_val,
-                            password: password_val,
-                            ..Default::default()
-                        })
+    /// Waits for `event` while serving scans, as long as `current()` stays `state`.
+    /// Other commands end the wait and are returned.
+    async fn serve_until(
+        &mut self,
+        event: WifiEvent,
+        current: fn() -> WifiState,
+        state: WifiState,
+    ) -> Option<WifiCommand> {
+        loop {
+            let next = select3(
+                self.controller.wait_for_event(event),
+                WIFI_COMMANDS.receive(),
+                Timer::after(STATUS_INTERVAL),
+            )
+            .await;
+            match next {
+                Either3::First(()) => return None,
+                Either3::Second(WifiCommand::Scan) => {
+                    run_scan(&mut self.controller).await;
+                    if current() != state {
+                        return None;
                     }
-                    WifiMode::Ap => {
-                        let ap_ssid: String<32> = match "esp-wifi".try_into() {
-                            Ok(s) => s,
-                            Err(_) => {
-                                error!("Failed to create AP SSID string");
-                                Timer::after(Duration::from_millis(5000)).await;
-                                continue;
-                            }
-                        };
-                        Configuration::AccessPoint(AccessPointConfiguration {
-                            ssid: ap_ssid,
-                            ..Default::default()
-                        })
+                }
+                Either3::Second(command) => return Some(command),
+                Either3::Third(()) => self.publish_status(),
+            }
+        }
+    }
+
+    /// Serves scans for `duration`, returning early with any other command
+    async fn serve_for(&mut self, duration: Duration) -> Option<WifiCommand> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let next = select3(
+                Timer::at(deadline),
+                WIFI_COMMANDS.receive(),
+                Timer::after(STATUS_INTERVAL),
+            )
+            .await;
+            match next {
+                Either3::First(()) => return None,
+                Either3::Second(WifiCommand::Scan) => run_scan(&mut self.controller).await,
+                Either3::Second(command) => return Some(command),
+                Either3::Third(()) => self.publish_status(),
+            }
+        }
+    }
+
+    /// Serves scans until some other command comes in
+    async fn wait_for_command(&mut self) -> WifiCommand {
+        loop {
+            match select(WIFI_COMMANDS.receive(), Timer::after(STATUS_INTERVAL)).await {
+                Either::First(WifiCommand::Scan) => run_scan(&mut self.controller).await,
+                Either::First(command) => return command,
+                Either::Second(()) => self.publish_status(),
+            }
+        }
+    }
+
+    async fn disconnect_station(&mut self) {
+        if sta_state() == WifiState::StaConnected {
+            if let Err(e) = self.controller.disconnect_async().await {
+                error!("Failed to disconnect station: {e:?}");
+            }
+        }
+    }
+
+    /// One round of keeping the link up. Returns a command that came in meanwhile.
+    async fn step(&mut self) -> Option<WifiCommand> {
+        match self.mode {
+            WifiMode::Sta if self.idle => {
+                return Some(self.wait_for_command().await);
+            }
+            WifiMode::Sta if sta_state() == WifiState::StaConnected => {
+                if self.ap_fallback {
+                    let client = self.connected.as_ref().map(client_configuration);
+                    match self.set_fallback_ap(client.unwrap_or_default(), false) {
+                        Ok(()) => info!("Station connected, AP stopped"),
+                        Err(e) => error!("Failed to stop fallback AP: {e:?}"),
                     }
-                };
+                }
+                self.failures = 0;
+                let command = self
+                    .serve_until(
+                        WifiEvent::StaDisconnected,
+                        sta_state,
+                        WifiState::StaConnected,
+                    )
+                    .await;
+                if command.is_some() {
+                    return command;
+                }
+                return self.serve_for(RETRY_DELAY).await;
+            }
+            WifiMode::Ap if ap_state() == WifiState::ApStarted => {
+                let command = self
+                    .serve_until(WifiEvent::ApStop, ap_state, WifiState::ApStarted)
+                    .await;
+                if command.is_some() {
+                    return command;
+                }
+                return self.serve_for(RETRY_DELAY).await;
+            }
+            _ => {}
+        }
+
+        if let Err(e) = self.ensure_started().await {
+            error!("Failed to start wifi: {e:?}");
+            return self.serve_for(RETRY_DELAY).await;
+        }
+        match self.mode {
+            WifiMode::Sta => self.connect_next().await,
+            WifiMode::Ap => self.serve_for(Duration::from_millis(500)).await,
+        }
+    }
 
-                if let Err(e) = controller.set_configuration(&config) {
-                    error!("set_configuration error: {e:?}");
-                    Timer::after(Duration::from_millis(5000)).await;
-                    continue;
+    /// Tries the next candidate, scanning for new ones once all were tried
+    async fn connect_next(&mut self) -> Option<WifiCommand> {
+        if self.next_candidate >= self.candidates.len() {
+            self.candidates = find_candidates(&mut self.controller).await;
+            self.next_candidate = 0;
+        }
+        let Some(Candidate { network, channel }) =
+            self.candidates.get(self.next_candidate).cloned()
+        else {
+            error!("No known networks to connect to");
+            return self.serve_for(RETRY_DELAY).await;
+        };
+        self.next_candidate += 1;
+
+        match self.connect(&network, channel).await {
+            Ok(()) => {
+                // Rescan after the next disconnect, the best network may have changed
+                self.next_candidate = self.candidates.len();
+                None
+            }
+            Err(e) => {
+                self.failures = self.failures.saturating_add(1);
+                let limit = self.sta_failure_limit;
+                error!(
+                    "Failed to connect to wifi ({}/{limit}): {e:?}",
+                    self.failures
+                );
+                if self.failures >= limit && !self.ap_fallback {
+                    match self.set_fallback_ap(client_configuration(&network), true) {
+                        Ok(()) => info!("Station keeps failing, starting AP {}", self.ap.ssid),
+                        Err(e) => error!("Failed to start fallback AP: {e:?}"),
+                    }
                 }
-                info!("Starting wifi");
-                if let Err(e) = controller.start_async().await {
-                    error!("start_async error: {e:?}");
-                    Timer::after(Duration::from_millis(5000)).await;
-                    continue;
+                self.serve_for(RETRY_DELAY).await
+            }
+        }
+    }
+
+    async fn apply(&mut self, command: WifiCommand) {
+        match command {
+            WifiCommand::Scan => run_scan(&mut self.controller).await,
+            WifiCommand::Connect(network) => self.switch_network(network).await,
+            WifiCommand::SetMode(mode) => self.set_mode(mode).await,
+            WifiCommand::Disconnect => {
+                info!("Disconnecting station on request");
+                self.idle = true;
+                self.disconnect_station().await;
+                publish_sse(format_args!("Wi-Fi: station disconnected"));
+            }
+        }
+    }
+
+    /// Tries `network` right away. If that fails the station goes back to the
+    /// network it was on; in AP mode only the AP stays up.
+    async fn switch_network(&mut self, network: SavedNetwork) {
+        info!("Switching to network {}", network.ssid);
+        publish_sse(format_args!("Wi-Fi: connecting to {}", network.ssid));
+        self.idle = false;
+        let previous = self.connected.take();
+        let previous_channel = self.channel;
+        self.disconnect_station().await;
+
+        let result = match self.ensure_started().await {
+            Ok(()) => self.connect(&network, None).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                self.candidates.clear();
+                self.next_candidate = 0;
+                CONNECT_RESULT.signal(Ok(()));
+                publish_sse(format_args!("Wi-Fi: connected to {}", network.ssid));
+                if self.mode == WifiMode::Ap {
+                    // The AP lingers so a browser on it gets the news, then goes
+                    // down like a fallback AP once the station is connected
+                    self.mode = WifiMode::Sta;
+                    self.ap_fallback = true;
+                    Timer::after(AP_HANDOVER_DELAY).await;
                 }
-                info!("Wifi started!");
             }
             Err(e) => {
-                error!("is_started() failed: {e:?}");
-                Timer::after(Duration::from_millis(5000)).await;
-                continue;
+                error!("Failed to connect to {}: {e:?}", network.ssid);
+                match previous {
+                    Some(previous) if self.mode == WifiMode::Sta => {
+                        publish_sse(format_args!(
+                            "Wi-Fi: could not connect to {}, back to {}",
+                            network.ssid, previous.ssid
+                        ));
+                        if let Err(e) = self.connect(&previous, previous_channel).await {
+                            error!("Failed to reconnect to {}: {e:?}", previous.ssid);
+                        }
+                    }
+                    _ => {
+                        publish_sse(format_args!("Wi-Fi: could not connect to {}", network.ssid));
+                        let config = self.configuration(Default::default());
+                        if let Err(e) = self.controller.set_configuration(&config) {
+                            error!("Failed to reset station: {e:?}");
+                        }
+                    }
+                }
+                CONNECT_RESULT.signal(Err(e));
             }
         }
+    }
 
-        if let WifiMode::Sta = current_mode {
-            info!("About to connect SSID {:?}", ssid);
-            match controller.connect_async().await {
-                Ok(_) => info!("Wifi connected!"),
-                Err(e) => {
-                    error!("Failed to connect to wifi: {e:?}");
-                    Timer::after(Duration::from_millis(5000)).await;
+    async fn set_mode(&mut self, mode: WifiMode) {
+        self.idle = false;
+        if mode == self.mode && !self.ap_fallback {
+            return;
+        }
+        match mode {
+            WifiMode::Ap => {
+                info!("Switching to AP mode");
+                self.disconnect_station().await;
+                self.mode = WifiMode::Ap;
+                self.ap_fallback = false;
+                let config = self.configuration(Default::default());
+                if let Err(e) = self.controller.set_configuration(&config) {
+                    error!("Failed to start AP: {e:?}");
                 }
+                publish_sse(format_args!("Wi-Fi: AP {} up", self.ap.ssid));
+            }
+            WifiMode::Sta => {
+                // The AP stays up as a fallback until the station is connected,
+                // it may be how the browser asking for the switch is attached
+                info!("Switching to station mode");
+                self.mode = WifiMode::Sta;
+                self.ap_fallback = true;
+                self.failures = 0;
+                self.candidates.clear();
+                self.next_candidate = 0;
+                publish_sse(format_args!(
+                    "Wi-Fi: station mode, looking for known networks"
+                ));
             }
         }
     }
 }
 
+/// Runs the station or the AP, as told by `mode` and later by `WifiCommand`s.
+/// The station scans and tries the known networks best first, moving on to
+/// the next one when a connect fails. After `sta_failure_limit` failed
+/// connects in a row the AP comes up next to the station, which keeps
+/// retrying and takes the AP down again once it is connected.
 #[task]
+async fn wifi_connection(
+    controller: WifiController<'static>,
+    stack: Stack<'static>,
+    mode: WifiMode,
+    ap: AccessPointConfiguration,
+    sta_failure_limit: u32,
+) {
+    info!("Start connection task");
+    info!("Device capabilities: {:?}", controller.capabilities());
+
+    let mut connection = Connection {
+        controller,
+        mode,
+        ap,
+        sta_failure_limit,
+        stack,
+        candidates: Candidates::new(),
+        next_candidate: 0,
+        connected: None,
+        channel: None,
+        connects: 0,
+        failures: 0,
+        ap_fallback: false,
+        idle: false,
+    };
+    loop {
+        connection.publish_status();
+        if let Some(command) = connection.step().await {
+            connection.apply(command).await;
+        }
+    }
+}
+
+#[task(pool_size = 2)]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
@@ -303,6 +1393,9 @@ pub enum Error {
 
     /// Error during Wi-Fi operation
     Wifi(#[expect(unused, reason = "Never read directly")] WifiError),
+
+    /// The `wifi_connection` task did not answer in time
+    Timeout,
 }
 
 impl From<InitializationError> for Error {
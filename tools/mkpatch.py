@@ -0,0 +1,42 @@
+#!/usr/bin/env python3
+"""Build a delta OTA patch (see src/delta.rs) that turns OLD into NEW.
+
+Usage: mkpatch.py OLD NEW PATCH
+
+OLD must be exactly the image flashed in the device's running slot.
+Requires the bsdiff4 package (pip install bsdiff4).
+"""
+import hashlib
+import struct
+import sys
+
+from bsdiff4 import core
+
+
+def main():
+    if len(sys.argv) != 4:
+        sys.exit(__doc__)
+    old = open(sys.argv[1], "rb").read()
+    new = open(sys.argv[2], "rb").read()
+
+    control, diff, extra = core.diff(old, new)
+
+    out = bytearray(b"EDP1")
+    out += struct.pack("<I", len(old))
+    out += hashlib.sha256(old).digest()
+    out += struct.pack("<I", len(new))
+    diff_pos = extra_pos = 0
+    for diff_len, extra_len, seek in control:
+        out += struct.pack("<IIi", diff_len, extra_len, seek)
+        out += diff[diff_pos:diff_pos + diff_len]
+        out += extra[extra_pos:extra_pos + extra_len]
+        diff_pos += diff_len
+        extra_pos += extra_len
+
+    with open(sys.argv[3], "wb") as f:
+        f.write(out)
+    print(f"{len(new)} byte image as {len(out)} byte patch")
+
+
+if __name__ == "__main__":
+    main()